@@ -0,0 +1,456 @@
+//! Whole backup process: snapshot → diff → pack → upload.
+
+use crate::concurrent::run_limited;
+use crate::cpio::{Archive, Format};
+use crate::database::{
+    self, ArchiveRecord, Database, Diff, DiffRow, DiffType, Hashing, RowId, Snapshot, SqlName,
+};
+use crate::packer::{self, PackLimits, Packed, Strategy};
+use crate::storage::{self, ObjectInfo, PutOptions, Storage, StorageClass};
+use crate::stream_hash::stream_hash;
+use crate::types::Checksum;
+use crate::DateTime;
+use smallvec::SmallVec;
+use snafu::{ResultExt, Snafu};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    DatabaseFailed {
+        source: database::Error,
+    },
+    StorageFailed {
+        source: storage::Error,
+    },
+}
+
+/// Settings of the single backup run.
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    /// Files smaller than this are grouped together. See [`packer::pack`](packer::pack).
+    pub min_size: u64,
+    /// Archives never grow larger than this, and larger files are skipped.
+    ///
+    /// Note that S3 allows at most 10000 parts in the upload,
+    /// so archive can't be larger than 10000 [parts](crate::storage::S3Config::part_size).
+    pub max_size: u64,
+    /// How small files are grouped together.
+    pub strategy: Strategy,
+    /// Approximate amount of memory used for packing, see [`PackLimits::memory`](PackLimits::memory).
+    pub memory_limit: u64,
+    /// Content of files is hashed by this number of threads while creating the snapshot.
+    /// Checksums of unchanged files are taken from the base snapshot, so they are never read again.
+    /// When `None`, changed files are not [deduplicated](crate::database::Database::find_by_checksum),
+    /// since their checksums are unknown before archiving.
+    pub hash_workers: Option<usize>,
+    /// Maximum number of requests made to the storage.
+    /// Packs that don't fit are uploaded by the next backups, see [`packer::fit_budget`](packer::fit_budget).
+    pub max_requests: Option<u64>,
+    /// Number of archives uploaded at once.
+    ///
+    /// Every upload holds a single [part](crate::storage::S3Config::part_size) in memory.
+    pub concurrency: usize,
+    pub storage_class: StorageClass,
+    pub format: Format,
+    /// Place [restore script](crate::cpio::script) into every archive.
+    pub script: bool,
+    /// Prepended to every key, usually either empty or ends with `/`.
+    pub prefix: String,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            min_size: 1024 * 1024,
+            max_size: 64 * 1024 * 1024 * 1024,
+            strategy: Strategy::default(),
+            memory_limit: PackLimits::new(0, 0).memory,
+            hash_workers: Some(4),
+            max_requests: None,
+            concurrency: 4,
+            storage_class: StorageClass::default(),
+            format: Format::default(),
+            script: false,
+            prefix: String::new(),
+        }
+    }
+}
+
+/// What was done by [`backup`](backup).
+#[derive(Debug)]
+pub struct BackupReport {
+    /// Name of the created snapshot.
+    pub snapshot: SqlName,
+    /// Snapshot that was used as a base for computing changes.
+    pub base: SqlName,
+    /// Uploaded archives, sorted by key.
+    pub archives: Vec<ObjectInfo>,
+    /// Number of files that were not uploaded, since their content is already stored.
+    pub deduplicated: usize,
+    /// Number of files that were not uploaded, since they are larger than maximum archive size.
+    /// They are listed by [`Database::oversized_files`](crate::database::Database::oversized_files).
+    pub oversized: usize,
+    /// Number of files that will be uploaded later, since request budget is exhausted.
+    pub deferred: usize,
+    /// Number of files that were deferred by previous backups and included into this one.
+    pub resumed: usize,
+    /// Number of files that were moved, so only their new paths are recorded.
+    pub moved: usize,
+}
+
+/// Packs that are left after [deduplication](deduplicate).
+pub(crate) struct Deduplicated {
+    /// Remaining packs with sizes of their archives, ordered from the largest.
+    pub(crate) packs: Vec<(u64, SmallVec<[RowId; 4]>)>,
+    /// Files that are duplicates of other files from the same packs.
+    /// They can be recorded only after the original file is uploaded,
+    /// so only their rows are kept, like for packs.
+    pub(crate) later: Vec<RowId>,
+}
+
+/// Removes files which content is already uploaded from the packs, recording references to them instead.
+///
+/// Files are never read here, so only files with known checksum are deduplicated:
+/// ones [hashed](BackupOptions::hash_workers) while creating the snapshot.
+/// Checksums of other files are calculated while archiving, and used by the next backups.
+///
+/// Every file of the packs is taken from the database here anyway,
+/// so sizes of remaining archives are measured too.
+/// With `dry_run` references are only counted, so the index is not touched.
+pub(crate) fn deduplicate(
+    db: &Database,
+    diff: &Diff<'_>,
+    packed: &mut Packed,
+    options: &BackupOptions,
+    dry_run: bool,
+    deduplicated: &mut usize,
+) -> Result<Deduplicated, Error> {
+    let mut seen = HashSet::new();
+    let mut later = Vec::new();
+    let mut packs = Vec::with_capacity(packed.packs.len());
+    for pack in packed.packs.drain(..) {
+        let mut kept = SmallVec::new();
+        // Dropped right after measuring, so only one archive is kept in memory.
+        let mut archive = Archive::with_format(options.format);
+        archive.set_script(options.script);
+        for rowid in pack {
+            let info = match diff.query().get(rowid).context(DatabaseFailed)? {
+                DiffRow::Created { after, .. } | DiffRow::Changed { after, .. } => after,
+                DiffRow::Deleted { .. } | DiffRow::Moved { .. } => continue,
+            };
+            let checksum = if let (Some(_), Some(checksum)) = (info.identifier(), info.hash) {
+                checksum
+            } else {
+                kept.push(rowid);
+                archive.add(info.cast());
+                continue;
+            };
+            if let Some(existing) = db.find_by_checksum(&checksum).context(DatabaseFailed)? {
+                if !dry_run {
+                    db.add_reference(&existing, &info).context(DatabaseFailed)?;
+                }
+                *deduplicated += 1;
+            } else if seen.insert(checksum) {
+                kept.push(rowid);
+                archive.add(info.cast());
+            } else {
+                later.push(rowid);
+            }
+        }
+        if !kept.is_empty() {
+            packs.push((archive.size(), kept));
+        }
+    }
+    packs.sort_by_key(|(size, _)| Reverse(*size));
+    Ok(Deduplicated { packs, later })
+}
+
+/// Records new paths of moved files, referencing archives that already contain them.
+///
+/// Moved files that are not found in any archive are uploaded again.
+/// Returns number of recorded files. With `dry_run` they are only counted.
+pub(crate) fn record_moved(db: &Database, diff: &Diff<'_>, dry_run: bool) -> Result<usize, Error> {
+    let mut moved = 0;
+    let mut missing = Vec::new();
+    diff.query()
+        .only_kind(DiffType::Moved)
+        .for_each(|row| {
+            if let DiffRow::Moved {
+                rowid,
+                before,
+                after,
+                ..
+            } = row
+            {
+                let existing = match before.identifier() {
+                    Some(identifier) => {
+                        db.find_by_identifier(&identifier).context(DatabaseFailed)?
+                    }
+                    None => None,
+                };
+                match existing {
+                    Some(existing) => {
+                        if !dry_run {
+                            db.add_reference(&existing, &after)
+                                .context(DatabaseFailed)?;
+                        }
+                        moved += 1;
+                    }
+                    None => missing.push(rowid),
+                }
+            }
+            Ok(())
+        })
+        .context(DatabaseFailed)??;
+    for rowid in missing {
+        diff.mark_created(rowid).context(DatabaseFailed)?;
+    }
+    Ok(moved)
+}
+
+/// Removes packs that don't fit into request budget, recording their files as deferred.
+///
+/// Returns number of deferred files.
+fn fit_budget(
+    db: &Database,
+    diff: &Diff<'_>,
+    snapshot: &SqlName,
+    sized: &mut Vec<(u64, SmallVec<[RowId; 4]>)>,
+    part_size: Option<u64>,
+    limits: &PackLimits,
+    max_requests: u64,
+) -> Result<usize, Error> {
+    let postponed = packer::fit_budget(sized, limits, part_size, max_requests);
+    let mut deferred = 0;
+    for pack in postponed {
+        for rowid in pack {
+            match diff.query().get(rowid).context(DatabaseFailed)? {
+                DiffRow::Created { after, .. } | DiffRow::Changed { after, .. } => {
+                    db.add_deferred(snapshot, &after).context(DatabaseFailed)?;
+                    deferred += 1;
+                }
+                DiffRow::Deleted { .. } | DiffRow::Moved { .. } => {}
+            }
+        }
+    }
+    Ok(deferred)
+}
+
+/// Records duplicates of the files from the same packs, once the packs are uploaded.
+///
+/// Returns numbers of deduplicated and deferred files.
+fn record_later(
+    db: &Database,
+    diff: &Diff<'_>,
+    snapshot: &SqlName,
+    later: Vec<RowId>,
+) -> Result<(usize, usize), Error> {
+    let mut deduplicated = 0;
+    let mut deferred = 0;
+    for rowid in later {
+        let info = match diff.query().get(rowid).context(DatabaseFailed)? {
+            DiffRow::Created { after, .. } | DiffRow::Changed { after, .. } => after,
+            DiffRow::Deleted { .. } | DiffRow::Moved { .. } => continue,
+        };
+        let checksum = match info.hash {
+            Some(checksum) => checksum,
+            None => continue,
+        };
+        // Original file is either uploaded already, or deferred together with this one.
+        if let Some(existing) = db.find_by_checksum(&checksum).context(DatabaseFailed)? {
+            db.add_reference(&existing, &info).context(DatabaseFailed)?;
+            deduplicated += 1;
+        } else {
+            db.add_deferred(snapshot, &info).context(DatabaseFailed)?;
+            deferred += 1;
+        }
+    }
+    Ok((deduplicated, deferred))
+}
+
+/// Archive that is uploaded, but not recorded in the index yet.
+struct Uploaded {
+    archive: Archive,
+    object: ObjectInfo,
+    checksum: Checksum,
+}
+
+/// Uploads single archive. Database is not touched here, so many archives can be uploaded at once.
+async fn upload(
+    storage: &dyn Storage,
+    mut archive: Archive,
+    key: String,
+    put_options: &PutOptions,
+) -> Result<Uploaded, Error> {
+    let (object, checksum) = {
+        let reader = archive.read();
+        tokio::pin!(reader);
+        let mut reader = stream_hash(reader);
+        let object = storage
+            .put(&key, &mut reader, put_options)
+            .await
+            .context(StorageFailed)?;
+        (object, Checksum::from(reader.finalize()))
+    };
+    log!(cli: "Uploaded {} ({} bytes)", key, size = object.size);
+    Ok(Uploaded {
+        archive,
+        object,
+        checksum,
+    })
+}
+
+/// Records uploaded archive in the index.
+///
+/// Checksums of archived files are recorded in the `after` snapshot too.
+fn record(
+    db: &Database,
+    after: &Snapshot<&Database>,
+    uploaded: Uploaded,
+) -> Result<ObjectInfo, Error> {
+    let Uploaded {
+        archive,
+        object: uploaded,
+        checksum,
+    } = uploaded;
+    let record = ArchiveRecord {
+        key: uploaded.key.clone(),
+        snapshot: after.name().as_str().to_owned(),
+        size: uploaded.size,
+        checksum: Some(checksum),
+        storage_class: uploaded.storage_class,
+        uploaded_at: DateTime::now_utc(),
+    };
+    let files = archive
+        .files()
+        .iter()
+        .map(|pending| (&pending.info, pending.calculated));
+    db.add_archive(&record, files).context(DatabaseFailed)?;
+
+    // Files are read while archiving, so their checksums are known now.
+    let calculated = archive
+        .files()
+        .iter()
+        .filter_map(|pending| Some((&pending.info, pending.calculated?)));
+    after.record_hashes(calculated).context(DatabaseFailed)?;
+    Ok(uploaded)
+}
+
+/// Creates new snapshot of the `root` and uploads everything changed since the last uploaded snapshot.
+///
+/// Every pack becomes a separate archive named `<prefix><snapshot>/<number>.cpio`,
+/// which is recorded in the [index](crate::database::remote) as soon as it is uploaded.
+/// Files which content is already uploaded (even under different name) are never uploaded again,
+/// and [moved](crate::database::DiffType::Moved) files are not even read.
+/// When [request budget](BackupOptions::max_requests) is exhausted, remaining files are
+/// [deferred](crate::database::Database::add_deferred) to the next backup.
+/// Up to [`concurrency`](BackupOptions::concurrency) archives are uploaded at once, largest first.
+/// Snapshot is marked as uploaded only when all archives are uploaded successfully.
+pub async fn backup(
+    db: &mut Database,
+    root: &Path,
+    storage: &dyn Storage,
+    options: &BackupOptions,
+) -> Result<BackupReport, Error> {
+    let base = match db.last_uploaded_snapshot().context(DatabaseFailed)? {
+        Some(base) => base,
+        None => db.empty_snapshot().context(DatabaseFailed)?,
+    };
+    let name = SqlName::now();
+    {
+        let mut snapshot = db.open_snapshot(name.clone()).context(DatabaseFailed)?;
+        let filler = match options.hash_workers {
+            Some(workers) => snapshot.hashing_filler(Hashing {
+                workers,
+                reuse_from: Some(base.clone()),
+            }),
+            None => snapshot.filler(),
+        };
+        filler
+            .context(DatabaseFailed)?
+            .fill(root)
+            .context(DatabaseFailed)?
+            .save()
+            .context(DatabaseFailed)?;
+    }
+    log!(cli: "Backing up {} relative to {}", snapshot = name.as_str(), base = base.as_str());
+
+    let put_options = PutOptions {
+        storage_class: options.storage_class,
+        metadata: vec![("snapshot".to_owned(), name.as_str().to_owned())],
+    };
+    let mut archives = Vec::new();
+    let mut deduplicated = 0;
+    let oversized;
+    let resumed;
+    let moved;
+    let mut deferred = 0;
+    {
+        let before = db.readonly_snapshot(base.clone()).context(DatabaseFailed)?;
+        let after = db.readonly_snapshot(name.clone()).context(DatabaseFailed)?;
+        let diff = db
+            .compare_snapshots(&before, &after)
+            .context(DatabaseFailed)?;
+        resumed = diff.include_deferred().context(DatabaseFailed)?;
+        moved = record_moved(db, &diff, false)?;
+        let limits = PackLimits {
+            min_size: options.min_size,
+            max_size: options.max_size,
+            memory: options.memory_limit,
+        };
+        let mut packed = packer::pack(&diff, &options.strategy, &limits).context(DatabaseFailed)?;
+        packer::for_each_oversized(&diff, options.max_size, |file| {
+            db.add_oversized(&name, &file.path, file.size, options.max_size)
+        })
+        .context(DatabaseFailed)?
+        .context(DatabaseFailed)?;
+        let Deduplicated { mut packs, later } =
+            deduplicate(db, &diff, &mut packed, options, false, &mut deduplicated)?;
+        if let Some(max_requests) = options.max_requests {
+            deferred = fit_budget(
+                db,
+                &diff,
+                &name,
+                &mut packs,
+                storage.part_size(),
+                &limits,
+                max_requests,
+            )?;
+        }
+
+        // Only sizes are kept, archives are created again right before uploading.
+        let jobs = packs.iter().enumerate().map(|(idx, (_, pack))| {
+            let mut archive =
+                packer::to_archive(&diff, pack, options.format).context(DatabaseFailed)?;
+            archive.set_script(options.script);
+            let key = format!("{}{}/{:05}.cpio", options.prefix, name, idx);
+            Ok(upload(storage, archive, key, &put_options))
+        });
+        run_limited(jobs, options.concurrency, |uploaded| {
+            archives.push(record(db, &after, uploaded)?);
+            Ok(())
+        })
+        .await?;
+        archives.sort_by(|a, b| a.key.cmp(&b.key));
+
+        oversized = packed.oversized;
+
+        let (referenced, postponed) = record_later(db, &diff, &name, later)?;
+        deduplicated += referenced;
+        deferred += postponed;
+    }
+
+    db.mark_uploaded(&name).context(DatabaseFailed)?;
+    Ok(BackupReport {
+        snapshot: name,
+        base,
+        archives,
+        deduplicated,
+        oversized,
+        deferred,
+        resumed,
+        moved,
+    })
+}
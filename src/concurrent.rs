@@ -0,0 +1,55 @@
+//! Running several transfers at once.
+//!
+//! [`Storage`](crate::storage::Storage) futures are not `Send`, so everything runs on the current task:
+//! transfers are only polled concurrently. It also means that results are handled one by one,
+//! so the database is never written from two places at once.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+
+/// Runs at most `limit` jobs at once, passing every result to `done` as soon as it is ready.
+///
+/// Jobs are started in the order of `jobs`, so it's up to the caller to put the most important
+/// (or the largest) first. Next job is created only when some running job is finished,
+/// so no more than `limit` jobs are holding their buffers at any moment.
+///
+/// Stops at the first error, dropping jobs that are still running.
+/// ```
+/// # use colbak_lib::concurrent::run_limited;
+/// # futures::executor::block_on(async {
+/// let jobs = (1..=5).map(|x| Ok(async move { Ok::<_, ()>(x * 10) }));
+/// let mut sum = 0;
+/// run_limited(jobs, 2, |x| {
+///     sum += x;
+///     Ok(())
+/// })
+/// .await
+/// .unwrap();
+/// assert_eq!(sum, 150);
+/// # });
+/// ```
+pub async fn run_limited<I, F, T, E>(
+    jobs: I,
+    limit: usize,
+    mut done: impl FnMut(T) -> Result<(), E>,
+) -> Result<(), E>
+where
+    I: IntoIterator<Item = Result<F, E>>,
+    F: Future<Output = Result<T, E>>,
+{
+    let limit = limit.max(1);
+    let mut jobs = jobs.into_iter();
+    let mut running = FuturesUnordered::new();
+    loop {
+        while running.len() < limit {
+            match jobs.next() {
+                Some(job) => running.push(job?),
+                None => break,
+            }
+        }
+        match running.next().await {
+            Some(result) => done(result?)?,
+            None => return Ok(()),
+        }
+    }
+}
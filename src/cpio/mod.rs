@@ -1,21 +1,34 @@
 #[macro_use]
 mod state_machine;
+mod newc;
+mod odc;
 pub mod pending;
 pub mod reader;
+pub mod script;
 mod smart_read;
 mod writer;
 
-use crate::fileinfo::{Info, UnspecifiedInfo};
+use crate::fileinfo::{DeviceKind, HardLinkInfo, Info, UnspecifiedInfo};
+use crate::types::Checksum;
 use crate::DateTime;
 use pending::Pending;
 use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::mem::size_of;
+use std::str::FromStr;
 
-use crate::path::{EncodedPath, External, Local, PathKind};
+use crate::path::{EncodedPath, EscapedString, External, Local, PathKind};
+pub use newc::{NewcHeader, NEWC_HEADER_LEN};
+pub use odc::{OdcHeader, ODC_HEADER_LEN};
 pub use reader::Reader;
 
 /// This is header of old binary format. See [`man 5 cpio`](http://man.he.net/man5/cpio) for details.
+///
+/// Each field is stored in the byte order of machine that created the archive,
+/// [`CpioHeader::decode`](CpioHeader::decode) detects it using the magic.
+/// We are always writing headers in little-endian order.
 #[derive(Debug)]
 #[repr(C)]
 pub struct CpioHeader {
@@ -28,11 +41,12 @@ pub struct CpioHeader {
     mode: u16,
     uid: u16,
     gid: u16,
-    /// Number of links. This archiver does not aware of hard links, so this field is mostly useless.
-    /// It is set to `1` for files and TRAILER, to `2` for directories and `0` for everything else.
+    /// Number of links. It is set to `2` for directories and `1` for TRAILER.
+    /// Hard links share the inode, data is stored only with the first of them.
     nlink: u16,
-    /// `rdev` field is used for storing higher bits of file size.
+    /// For regular files `rdev` field is used for storing higher bits of file size.
     /// This allows us to decode files up to 2^48 = 256TB, but is not supported by normal archivers.
+    /// Devices store their major and minor numbers here, one byte each.
     rdev: u16,
     mtime: [u16; 2],
     /// Length of path should not exceed 65536 bytes, and that matches maximum path length on Windows.
@@ -77,35 +91,120 @@ pub fn decode_u32(x: [u16; 2]) -> u32 {
     (higher << 16) | lower
 }
 
-/// Encodes unix timestamp into two bytes.
+/// File type bits of the mode, number of links and device numbers of the entry.
+struct EntryKind {
+    kind: u32,
+    nlink: u32,
+    device: (u32, u32),
+}
+
+impl EntryKind {
+    fn new<K: PathKind>(info: &Info<K>) -> Self {
+        // Links count is not going to overflow in practice.
+        #[allow(clippy::cast_possible_truncation)]
+        let (kind, nlink) = match &info.data {
+            UnspecifiedInfo::File(file) => (0o0100000, file.links.max(1) as u32),
+            UnspecifiedInfo::Dir(_) => (0o0040000, 2),
+            UnspecifiedInfo::Symlink(_) => (0o0120000, 1),
+            UnspecifiedInfo::HardLink(link) => (0o0100000, link.links.max(2) as u32),
+            UnspecifiedInfo::Fifo(_) => (0o0010000, 1),
+            UnspecifiedInfo::Device(device) => match device.kind {
+                DeviceKind::Char => (0o0020000, 1),
+                DeviceKind::Block => (0o0060000, 1),
+            },
+            // Let's keep the original type, there is no data anyway.
+            UnspecifiedInfo::Unknown(_) => (info.mode & 0o0170000, 0),
+        };
+        let device = match &info.data {
+            UnspecifiedInfo::Device(device) => (device.major, device.minor),
+            _ => (0, 0),
+        };
+        EntryKind {
+            kind,
+            nlink,
+            device,
+        }
+    }
+
+    /// Reverse of [`EntryKind::new`](EntryKind::new).
+    ///
+    /// Symlink target is stored as the entry data, so it's not known here.
+    /// Hard links are not detected either, [`Reader`](Reader) does that.
+    fn decode(&self, size: u64) -> UnspecifiedInfo {
+        use crate::fileinfo::{DeviceInfo, DirInfo, FifoInfo, FileInfo, SymlinkInfo, UnknownInfo};
+
+        let device = |kind| {
+            UnspecifiedInfo::Device(DeviceInfo {
+                kind,
+                major: self.device.0,
+                minor: self.device.1,
+            })
+        };
+        match self.kind {
+            0o0100000 => UnspecifiedInfo::File(FileInfo {
+                size,
+                links: self.nlink.max(1).into(),
+            }),
+            0o0040000 => UnspecifiedInfo::Dir(DirInfo {}),
+            0o0120000 => UnspecifiedInfo::Symlink(SymlinkInfo::default()),
+            0o0010000 => UnspecifiedInfo::Fifo(FifoInfo {}),
+            0o0020000 => device(DeviceKind::Char),
+            0o0060000 => device(DeviceKind::Block),
+            _ => UnspecifiedInfo::Unknown(UnknownInfo {}),
+        }
+    }
+}
+
+/// Clamps unix timestamp to fit into `u32`.
 /// Any date between 1970.01.01 and 2106.02.07 will be stored without any losses.
-/// Date outside of this range will be represented as `[0, 0]` or `[0xFFFF, 0xFFFF]`
 ///
 /// ```
-/// # use colbak_lib::cpio::encode_timestamp;
-/// assert_eq!(encode_timestamp(0xC0FF_EE11), [0xC0FF, 0xEE11]);
-/// assert_eq!(encode_timestamp(0x12_0000_0000), [0xFFFF, 0xFFFF]);
-/// assert_eq!(encode_timestamp(-1), [0, 0]);
+/// # use colbak_lib::cpio::clamp_timestamp;
+/// assert_eq!(clamp_timestamp(0xC0FF_EE11), 0xC0FF_EE11);
+/// assert_eq!(clamp_timestamp(0x12_0000_0000), u32::MAX);
+/// assert_eq!(clamp_timestamp(-1), 0);
 /// ```
 #[must_use]
-pub fn encode_timestamp(x: i64) -> [u16; 2] {
+pub fn clamp_timestamp(x: i64) -> u32 {
     match x {
-        x if x <= 0 => [0, 0],
-        x if x > u32::MAX.into() => [u16::MAX, u16::MAX],
+        x if x <= 0 => 0,
+        x if x > u32::MAX.into() => u32::MAX,
         x => {
             // We already handled these cases in other arms
             #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-            convert_u32(x as u32)
+            let x = x as u32;
+            x
         }
     }
 }
 
+/// Encodes unix timestamp into two bytes.
+/// Date outside of range supported by [`clamp_timestamp`](clamp_timestamp)
+/// will be represented as `[0, 0]` or `[0xFFFF, 0xFFFF]`
+///
+/// ```
+/// # use colbak_lib::cpio::encode_timestamp;
+/// assert_eq!(encode_timestamp(0xC0FF_EE11), [0xC0FF, 0xEE11]);
+/// assert_eq!(encode_timestamp(0x12_0000_0000), [0xFFFF, 0xFFFF]);
+/// assert_eq!(encode_timestamp(-1), [0, 0]);
+/// ```
+#[must_use]
+pub fn encode_timestamp(x: i64) -> [u16; 2] {
+    convert_u32(clamp_timestamp(x))
+}
+
 impl CpioHeader {
     /// Generates `TRAILER!!!` entry that marks end of archive.
     /// Can be followed by any given content, most normal archivers will handle it without major issues.
     #[must_use]
     pub fn trailer(content: &[u8]) -> Vec<u8> {
-        let header = CpioHeader {
+        Format::Binary.trailer(content)
+    }
+
+    /// Header of the `TRAILER!!!` entry.
+    #[must_use]
+    fn trailer_header() -> Self {
+        CpioHeader {
             magic: MAGIC,
             dev_ino: [0, 0],
             mode: 0,
@@ -116,18 +215,7 @@ impl CpioHeader {
             mtime: [0, 0],
             namesize: TRAILER_LEN,
             filesize: [0, 0],
-        };
-        let header = header.into_array();
-
-        let mut res =
-            Vec::with_capacity(size_of::<CpioHeader>() + TRAILER.len() + 1 + content.len());
-        res.extend_from_slice(&header);
-        res.extend_from_slice(TRAILER);
-        if TRAILER_LEN % 2 != 0 {
-            res.push(0);
         }
-        res.extend_from_slice(content);
-        res
     }
 
     /// Returns true when current entry is an `TRAILER!!!` entry.
@@ -152,34 +240,24 @@ impl CpioHeader {
     /// Name must not exceed 65535 bytes,
     #[must_use]
     fn from_info<K: PathKind>(info: &Info<K>, name: &[u8]) -> Self {
-        let mode;
-        let nlink;
-        let filesize;
-        match &info.data {
-            UnspecifiedInfo::File(file) => {
-                mode = 0o0100000;
-                nlink = 1;
-                filesize = file.size;
-            }
-            UnspecifiedInfo::Dir(_) => {
-                mode = 0o0040000;
-                nlink = 2;
-                filesize = 0;
-            }
-            UnspecifiedInfo::Unknown(_) => {
-                mode = 0o0020000;
-                nlink = 0;
-                filesize = 0;
-            }
-        };
-        let mode = mode | (info.mode & (!0o0170000));
+        let EntryKind {
+            kind,
+            nlink,
+            device,
+        } = EntryKind::new(info);
+        let mode = kind | (info.mode & (!0o0170000));
+        let filesize = info.data_size();
 
         // Name should include NUL byte, but it is not included in `name`.
         let namesize = name.len() + 1;
         debug_assert!(u16::try_from(namesize).is_ok());
 
         let max_normal_size = u64::from(u32::MAX);
-        let rdev = filesize >> 32;
+        let rdev = if kind == 0o0100000 {
+            filesize >> 32
+        } else {
+            u64::from(((device.0 & 0xFF) << 8) | (device.1 & 0xFF))
+        };
         let filesize = filesize & max_normal_size;
         // Now maximum file size is 2^(32 + 16) = 2^48 = 256 TB
 
@@ -192,7 +270,7 @@ impl CpioHeader {
             mode: mode as u16,
             uid: info.user_id as u16,
             gid: info.group_id as u16,
-            nlink,
+            nlink: nlink as u16,
             rdev: rdev as u16,
             mtime: encode_timestamp(info.modified_at.unix_timestamp()),
             namesize: namesize as u16,
@@ -203,45 +281,93 @@ impl CpioHeader {
     /// Creates header for given info, correctly attaches filename and returns everything.
     #[must_use]
     pub fn encode<K: PathKind>(info: &Info<K>) -> Vec<u8> {
-        let name = info.path.crop_name_to(u16::MAX - 1);
-
-        let header = Self::from_info(info, &name);
-        let namesize = header.namesize as usize;
-        let header = header.into_array();
-
-        let mut result = Vec::with_capacity(size_of::<CpioHeader>() + namesize + 1);
-        result.extend_from_slice(&header);
-        result.extend_from_slice(&name);
-        // Name always ends with NUL byte, and if the namesize is odd, an additional NUL byte should be added.
-        result.push(0);
-        if namesize % 2 != 0 {
-            result.push(0);
-        }
-        result
+        Format::Binary.encode(info)
     }
 
     /// Decodes header from provided array, checking for correct magic.
+    /// Both little-endian and big-endian archives are supported.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::CpioHeader;
+    /// let mut little = [0; 26];
+    /// little[..2].copy_from_slice(&[0xC7, 0x71]);
+    /// little[24..].copy_from_slice(&[5, 0]); // lower half of filesize
+    /// assert_eq!(CpioHeader::decode(little).unwrap().size(), 5);
+    ///
+    /// let mut big = [0; 26];
+    /// big[..2].copy_from_slice(&[0x71, 0xC7]);
+    /// big[24..].copy_from_slice(&[0, 5]);
+    /// assert_eq!(CpioHeader::decode(big).unwrap().size(), 5);
+    ///
+    /// assert!(CpioHeader::decode([0; 26]).is_none());
+    /// ```
     #[must_use]
     pub fn decode(data: [u8; size_of::<CpioHeader>()]) -> Option<Self> {
-        let header: CpioHeader = unsafe { std::mem::transmute(data) };
-        match header.magic {
-            MAGIC => Some(header),
-            _ => None,
+        let magic = [data[0], data[1]];
+        let read: fn([u8; 2]) -> u16 = if u16::from_le_bytes(magic) == MAGIC {
+            u16::from_le_bytes
+        } else if u16::from_be_bytes(magic) == MAGIC {
+            u16::from_be_bytes
+        } else {
+            return None;
+        };
+
+        let mut words = [0; size_of::<CpioHeader>() / 2];
+        for (word, bytes) in words.iter_mut().zip(data.chunks_exact(2)) {
+            *word = read([bytes[0], bytes[1]]);
         }
+        // Four-byte integers are always stored with the most significant half first.
+        let [magic, dev, ino, mode, uid, gid, nlink, rdev, mtime_high, mtime_low, namesize, size_high, size_low] =
+            words;
+        Some(CpioHeader {
+            magic,
+            dev_ino: [dev, ino],
+            mode,
+            uid,
+            gid,
+            nlink,
+            rdev,
+            mtime: [mtime_high, mtime_low],
+            namesize,
+            filesize: [size_high, size_low],
+        })
     }
 
-    /// Encodes header into byte array.
+    /// Encodes header into little-endian byte array.
     #[must_use]
     pub fn into_array(self) -> [u8; size_of::<Self>()] {
-        unsafe { std::mem::transmute(self) }
+        let words = [
+            self.magic,
+            self.dev_ino[0],
+            self.dev_ino[1],
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.rdev,
+            self.mtime[0],
+            self.mtime[1],
+            self.namesize,
+            self.filesize[0],
+            self.filesize[1],
+        ];
+        let mut result = [0; size_of::<Self>()];
+        for (bytes, word) in result.chunks_exact_mut(2).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        result
     }
 
     /// Decodes full size from different fields.
     #[must_use]
     pub fn size(&self) -> u64 {
-        let higher = u64::from(self.rdev);
         let lower = u64::from(decode_u32(self.filesize));
-        (higher << 32) | lower
+        if self.mode & 0o0170000 == 0o0100000 {
+            let higher = u64::from(self.rdev);
+            (higher << 32) | lower
+        } else {
+            lower
+        }
     }
 
     /// Extracts info from header, using provided name.
@@ -249,17 +375,15 @@ impl CpioHeader {
     /// `info.hash` will be set to None.
     #[must_use]
     pub fn info(&self, name: &[u8]) -> Info<External> {
-        use crate::fileinfo::{DirInfo, FileInfo, UnknownInfo};
-
         debug_assert_eq!(self.namesize as usize - 1, name.len());
 
-        let kind = self.mode & 0o0170000;
-        let mode = self.mode & 0o0000777;
-        let data = match kind {
-            0o0100000 => UnspecifiedInfo::File(FileInfo { size: self.size() }),
-            0o0040000 => UnspecifiedInfo::Dir(DirInfo {}),
-            _ => UnspecifiedInfo::Unknown(UnknownInfo {}),
-        };
+        let mode = self.mode & 0o0007777;
+        let data = EntryKind {
+            kind: (self.mode & 0o0170000).into(),
+            nlink: self.nlink.into(),
+            device: ((self.rdev >> 8).into(), (self.rdev & 0xFF).into()),
+        }
+        .decode(self.size());
         Info {
             path: EncodedPath::from_vec(name.to_vec()),
             inode: decode_u32(self.dev_ino).into(),
@@ -268,26 +392,320 @@ impl CpioHeader {
             group_id: self.gid.into(),
             created_at: DateTime::from_unix_timestamp(0),
             modified_at: DateTime::from_unix_timestamp(decode_u32(self.mtime).into()),
+            accessed_at: None,
+            changed_at: None,
+            device: 0,
+            blocks: 0,
             hash: None,
+            xattrs: Vec::new(),
             data,
         }
     }
 }
 
+/// Header format used for writing an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default)]
+pub enum Format {
+    /// Old binary format, see [`CpioHeader`](CpioHeader).
+    ///
+    /// Compact, but truncates inodes, uid and gid to 16 bits.
+    #[default]
+    Binary,
+    /// SVR4 portable format without checksums (`070701`), see [`NewcHeader`](NewcHeader).
+    ///
+    /// Stores full 32-bit uid and gid and files up to 4GB in a way that any archiver understands.
+    ///
+    /// Larger files keep bits 32..63 of their size in `rdevmajor`, which usual archivers ignore,
+    /// so they read only the lower 32 bits of the size and fail to read the rest of archive.
+    /// Such archives can be read only by colbak or the [restore script](script).
+    /// Higher bits of inodes are placed into `devmajor`, that does not affect anyone.
+    Newc,
+}
+
+impl Format {
+    /// Creates header for given info in this format, correctly attaching filename.
+    #[must_use]
+    pub fn encode<K: PathKind>(self, info: &Info<K>) -> Vec<u8> {
+        let name = info.path.crop_name_to(u16::MAX - 1);
+        let header = match self {
+            Format::Binary => CpioHeader::from_info(info, &name).into_array().to_vec(),
+            Format::Newc => NewcHeader::from_info(info, &name).into_array().to_vec(),
+        };
+        self.attach_name(header, &name)
+    }
+
+    /// Generates `TRAILER!!!` entry that marks end of archive, followed by any given content.
+    #[must_use]
+    pub fn trailer(self, content: &[u8]) -> Vec<u8> {
+        let header = match self {
+            Format::Binary => CpioHeader::trailer_header().into_array().to_vec(),
+            Format::Newc => NewcHeader::trailer().into_array().to_vec(),
+        };
+        // TRAILER already contains NUL byte.
+        let name = &TRAILER[..TRAILER.len() - 1];
+        let mut res = self.attach_name(header, name);
+        res.extend_from_slice(content);
+        res
+    }
+
+    /// Appends NUL-ended name to the header, followed by padding.
+    /// For example, in the binary format an additional NUL byte is added if the namesize is odd.
+    fn attach_name(self, mut header: Vec<u8>, name: &[u8]) -> Vec<u8> {
+        let namesize = name.len() + 1;
+        let padding = self.name_padding(namesize);
+        header.reserve(namesize + padding);
+        header.extend_from_slice(name);
+        header.push(0);
+        header.resize(header.len() + padding, 0);
+        header
+    }
+
+    /// Number of NUL bytes that follow the name of given size (including NUL).
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::Binary.name_padding(11), 1);
+    /// assert_eq!(Format::Binary.name_padding(12), 0);
+    /// // Header and name together are aligned to four bytes.
+    /// assert_eq!(Format::Newc.name_padding(11), 3);
+    /// assert_eq!(Format::Newc.name_padding(14), 0);
+    /// ```
+    #[must_use]
+    pub fn name_padding(self, namesize: usize) -> usize {
+        match self {
+            Format::Binary => namesize % 2,
+            Format::Newc => (4 - (NEWC_HEADER_LEN + namesize) % 4) % 4,
+        }
+    }
+
+    /// Number of NUL bytes that follow the file data of given size.
+    ///
+    /// ```
+    /// # use colbak_lib::cpio::Format;
+    /// assert_eq!(Format::Binary.data_padding(15), 1);
+    /// assert_eq!(Format::Newc.data_padding(15), 1);
+    /// assert_eq!(Format::Newc.data_padding(14), 2);
+    /// assert_eq!(Format::Newc.data_padding(16), 0);
+    /// ```
+    #[must_use]
+    pub fn data_padding(self, size: u64) -> usize {
+        let alignment = match self {
+            Format::Binary => 2,
+            Format::Newc => 4,
+        };
+        // Remainder is always less than 4.
+        #[allow(clippy::cast_possible_truncation)]
+        let padding = ((alignment - size % alignment) % alignment) as usize;
+        padding
+    }
+}
+
+
+#[derive(Debug, Snafu)]
+#[snafu(display("Unknown cpio format `{}`, expected `binary` or `newc`", name))]
+pub struct UnknownFormat {
+    name: String,
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" | "bin" => Ok(Format::Binary),
+            "newc" => Ok(Format::Newc),
+            _ => Err(UnknownFormat { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Header of any supported format, as it was read from the archive.
+#[derive(Debug)]
+pub enum Header {
+    /// Old binary format, either little-endian or big-endian.
+    Binary(CpioHeader),
+    /// POSIX.1 portable format (`070707`).
+    Odc(OdcHeader),
+    /// SVR4 portable format, either with checksums (`070702`) or without (`070701`).
+    Newc(NewcHeader),
+}
+
+impl Header {
+    /// Length of name, including NUL byte.
+    #[must_use]
+    pub fn namesize(&self) -> usize {
+        match self {
+            Header::Binary(header) => header.namesize as usize,
+            Header::Odc(header) => header.namesize as usize,
+            Header::Newc(header) => header.namesize as usize,
+        }
+    }
+
+    /// Number of NUL bytes that follow the name.
+    #[must_use]
+    pub fn name_padding(&self) -> usize {
+        match self {
+            Header::Binary(_) => Format::Binary.name_padding(self.namesize()),
+            Header::Odc(_) => 0,
+            Header::Newc(_) => Format::Newc.name_padding(self.namesize()),
+        }
+    }
+
+    /// Number of NUL bytes that follow the file data.
+    #[must_use]
+    pub fn data_padding(&self) -> usize {
+        match self {
+            Header::Binary(_) => Format::Binary.data_padding(self.size()),
+            Header::Odc(_) => 0,
+            Header::Newc(_) => Format::Newc.data_padding(self.size()),
+        }
+    }
+
+    /// Number of links to the entry, hard links share the inode.
+    #[must_use]
+    pub fn nlink(&self) -> u32 {
+        match self {
+            Header::Binary(header) => header.nlink.into(),
+            Header::Odc(header) => header.nlink,
+            Header::Newc(header) => header.nlink,
+        }
+    }
+
+    /// Decodes full size of the entry data.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        match self {
+            Header::Binary(header) => header.size(),
+            Header::Odc(header) => header.filesize,
+            Header::Newc(header) => header.size(),
+        }
+    }
+
+    /// Expected sum of all bytes of the file data, only available in "crc" archives.
+    #[must_use]
+    pub fn checksum(&self) -> Option<u32> {
+        match self {
+            Header::Binary(_) => None,
+            Header::Odc(_) => None,
+            Header::Newc(header) => header.checksum(),
+        }
+    }
+
+    /// Returns true when current entry is an `TRAILER!!!` entry.
+    ///
+    /// Note: name must be NUL-ended.
+    #[must_use]
+    pub fn is_trailer(&self, name: &[u8]) -> bool {
+        match self {
+            Header::Binary(header) => header.is_trailer(name),
+            Header::Odc(header) => header.is_trailer(name),
+            Header::Newc(header) => header.is_trailer(name),
+        }
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    #[must_use]
+    pub fn info(&self, name: &[u8]) -> Info<External> {
+        match self {
+            Header::Binary(header) => header.info(name),
+            Header::Odc(header) => header.info(name),
+            Header::Newc(header) => header.info(name),
+        }
+    }
+}
+
 /// Pending cpio archive, waiting for be written.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Archive {
     files: Vec<Pending<Local>>,
+    /// Paths of files with several hard links, by their inodes.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    links: HashMap<u64, EncodedPath<Local>>,
+    #[serde(default)]
+    format: Format,
+    /// Whether [restore script and manifest](script) are placed before files.
+    #[serde(default)]
+    script: bool,
 }
 
 impl Archive {
+    /// Creates an empty archive in the [old binary format](Format::Binary).
     #[must_use]
     pub fn new() -> Self {
-        Archive { files: Vec::new() }
+        Self::with_format(Format::default())
+    }
+
+    /// Creates an empty archive, that will be written using given format.
+    #[must_use]
+    pub fn with_format(format: Format) -> Self {
+        Archive {
+            files: Vec::new(),
+            links: HashMap::new(),
+            format,
+            script: false,
+        }
+    }
+
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Places [restore script and manifest](script) before all files,
+    /// so archive can be extracted without colbak.
+    pub fn set_script(&mut self, script: bool) {
+        self.script = script;
+    }
+
+    #[must_use]
+    pub fn script(&self) -> bool {
+        self.script
+    }
+
+    /// Generates entries that are placed before files, if any.
+    #[must_use]
+    pub fn prelude(&self) -> Vec<u8> {
+        if self.script {
+            script::prelude(self.format, &self.files)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns all files added to the archive.
+    /// After archive is read, their checksums are [calculated](Pending::calculated).
+    #[must_use]
+    pub fn files(&self) -> &[Pending<Local>] {
+        &self.files
     }
 
     /// Adds file to the archive by it's path.
-    pub fn add(&mut self, file: Info<Local>) {
+    ///
+    /// When the file has several hard links and one of them is in the archive already,
+    /// file is added as a [hard link](HardLinkInfo) to it, so data is stored only once.
+    pub fn add(&mut self, mut file: Info<Local>) {
+        if let UnspecifiedInfo::File(info) = &file.data {
+            if info.links > 1 {
+                let links = info.links;
+                match self.links.get(&file.inode) {
+                    Some(target) => {
+                        file.data = UnspecifiedInfo::HardLink(HardLinkInfo {
+                            target: target.as_bytes().to_vec(),
+                            links,
+                        });
+                    }
+                    None => {
+                        self.links.insert(file.inode, file.path.clone());
+                    }
+                }
+            }
+        }
+        if self.format == Format::Newc && file.data_size() > u64::from(u32::MAX) {
+            log!(warn: "{} is 4GB or larger, usual cpio can't read it from newc archive", path = file.path.escaped());
+        }
         self.files.push(Pending::new(file));
     }
 
@@ -301,7 +719,31 @@ impl Archive {
             infos.push(info);
         }
         let content = serde_json::to_vec(&infos).unwrap_or_default();
-        CpioHeader::trailer(&content)
+        self.format.trailer(&content)
+    }
+
+    /// Computes size of the archive without reading any file.
+    ///
+    /// Files are expected to keep their sizes, and checksums of all files
+    /// are expected to be stored in the trailer, as it happens after the archive is read.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        let mut size = self.prelude().len() as u64;
+        let mut infos = Vec::with_capacity(self.files.len());
+        for pending in &self.files {
+            let mut info = pending.info.clone();
+            let data = info.data_size();
+            size += self.format.encode(&info).len() as u64;
+            size += data + self.format.data_padding(data) as u64;
+            // All checksums take the same space. Only files are read, so others may have none.
+            info.hash = pending.calculated.or(info.hash);
+            if info.size().is_some() {
+                info.hash = Some(info.hash.unwrap_or(Checksum([0; 64])));
+            }
+            infos.push(info);
+        }
+        let content = serde_json::to_vec(&infos).unwrap_or_default();
+        size + self.format.trailer(&content).len() as u64
     }
 
     /// Returns `AsyncRead` over contents of this archive.
@@ -0,0 +1,238 @@
+use super::{clamp_timestamp, EntryKind, TRAILER};
+use crate::fileinfo::Info;
+use crate::path::{EncodedPath, External, PathKind};
+use crate::DateTime;
+use std::convert::TryFrom;
+
+/// Magic of the SVR4 "newc" format.
+pub(super) const NEWC_MAGIC: &[u8; 6] = b"070701";
+
+/// Magic of the SVR4 format with checksums ("crc").
+pub(super) const CRC_MAGIC: &[u8; 6] = b"070702";
+
+/// Length of encoded header, including magic.
+pub const NEWC_HEADER_LEN: usize = 110;
+
+/// Number of hex-encoded fields following the magic.
+const FIELDS: usize = 13;
+
+/// Header of SVR4 portable format without checksums (`070701`, also known as "newc").
+/// Every field is stored as 8 hexadecimal ASCII digits, so there is no troubles with byte order.
+///
+/// Same header is used for the variant with checksums (`070702`, "crc"),
+/// but we are able only to read it.
+/// See [`man 5 cpio`](http://man.he.net/man5/cpio) for details.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NewcHeader {
+    /// Lower 32 bits of inode.
+    pub(super) ino: u32,
+    /// Full mode, including file type bits.
+    pub(super) mode: u32,
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+    /// Same meaning as in the [old binary format](super::CpioHeader).
+    pub(super) nlink: u32,
+    pub(super) mtime: u32,
+    pub(super) filesize: u32,
+    /// Higher 32 bits of inode. Together with `ino` it is still unique for the archive,
+    /// so hard links detection of normal archivers is not affected.
+    pub(super) devmajor: u32,
+    pub(super) devminor: u32,
+    /// For regular files stores higher 32 bits of file size, like `rdev` in the old format does.
+    /// This field is ignored for regular files by normal archivers.
+    /// Devices store their numbers here and in `rdevminor`.
+    pub(super) rdevmajor: u32,
+    pub(super) rdevminor: u32,
+    /// Length of name, including NUL byte.
+    pub(super) namesize: u32,
+    /// Sum of all bytes of the file data. Zero when there is no checksum.
+    pub(super) check: u32,
+    /// Whether `check` is meaningful, i.e. header was read from "crc" archive.
+    pub(super) has_checksum: bool,
+}
+
+impl NewcHeader {
+    /// Header of the `TRAILER!!!` entry.
+    #[must_use]
+    pub(super) fn trailer() -> Self {
+        #[allow(clippy::cast_possible_truncation)] // Obviously TRAILER.len() fits into u32.
+        NewcHeader {
+            nlink: 1,
+            namesize: TRAILER.len() as u32,
+            ..NewcHeader::default()
+        }
+    }
+
+    /// Creates header from given info. See [`CpioHeader::from_info`](super::CpioHeader) for details.
+    #[must_use]
+    pub(super) fn from_info<K: PathKind>(info: &Info<K>, name: &[u8]) -> Self {
+        let EntryKind {
+            kind,
+            nlink,
+            device,
+        } = EntryKind::new(info);
+        let mode = kind | (info.mode & (!0o0170000));
+        let filesize = info.data_size();
+        #[allow(clippy::cast_possible_truncation)] // Size is less than 2^64.
+        let (rdevmajor, rdevminor) = if kind == 0o0100000 {
+            ((filesize >> 32) as u32, 0)
+        } else {
+            device
+        };
+
+        let namesize = name.len() + 1;
+        debug_assert!(u32::try_from(namesize).is_ok());
+
+        // Both inode and size are split into two halves.
+        #[allow(clippy::cast_possible_truncation)]
+        NewcHeader {
+            ino: info.inode as u32,
+            mode,
+            uid: info.user_id,
+            gid: info.group_id,
+            nlink,
+            mtime: clamp_timestamp(info.modified_at.unix_timestamp()),
+            filesize: filesize as u32,
+            devmajor: (info.inode >> 32) as u32,
+            devminor: 0,
+            rdevmajor,
+            rdevminor,
+            namesize: namesize as u32,
+            check: 0,
+            has_checksum: false,
+        }
+    }
+
+    /// Encodes header into ASCII representation.
+    #[must_use]
+    pub fn into_array(self) -> [u8; NEWC_HEADER_LEN] {
+        let fields: [u32; FIELDS] = [
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.mtime,
+            self.filesize,
+            self.devmajor,
+            self.devminor,
+            self.rdevmajor,
+            self.rdevminor,
+            self.namesize,
+            self.check,
+        ];
+        let magic = if self.has_checksum {
+            CRC_MAGIC
+        } else {
+            NEWC_MAGIC
+        };
+        let mut result = [0; NEWC_HEADER_LEN];
+        result[..magic.len()].copy_from_slice(magic);
+        for (field, chunk) in fields
+            .iter()
+            .zip(result[NEWC_MAGIC.len()..].chunks_exact_mut(8))
+        {
+            chunk.copy_from_slice(format!("{field:08X}").as_bytes());
+        }
+        result
+    }
+
+    /// Decodes header from provided array, checking for correct magic.
+    /// Both "newc" and "crc" variants are accepted.
+    #[must_use]
+    pub fn decode(data: &[u8; NEWC_HEADER_LEN]) -> Option<Self> {
+        let (magic, data) = data.split_at(NEWC_MAGIC.len());
+        let has_checksum = if magic == NEWC_MAGIC {
+            false
+        } else if magic == CRC_MAGIC {
+            true
+        } else {
+            return None;
+        };
+        let mut fields = [0; FIELDS];
+        for (field, chunk) in fields.iter_mut().zip(data.chunks_exact(8)) {
+            let chunk = std::str::from_utf8(chunk).ok()?;
+            *field = u32::from_str_radix(chunk, 16).ok()?;
+        }
+        let [ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor, namesize, check] =
+            fields;
+        Some(NewcHeader {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            filesize,
+            devmajor,
+            devminor,
+            rdevmajor,
+            rdevminor,
+            namesize,
+            check,
+            has_checksum,
+        })
+    }
+
+    /// Returns expected checksum of the file data, if it is stored in the header.
+    #[must_use]
+    pub fn checksum(&self) -> Option<u32> {
+        if self.has_checksum {
+            Some(self.check)
+        } else {
+            None
+        }
+    }
+
+    /// Decodes full size from different fields.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        let lower = u64::from(self.filesize);
+        if self.mode & 0o0170000 == 0o0100000 {
+            let higher = u64::from(self.rdevmajor);
+            (higher << 32) | lower
+        } else {
+            lower
+        }
+    }
+
+    /// Returns true when current entry is an `TRAILER!!!` entry.
+    ///
+    /// Note: name must be NUL-ended.
+    #[must_use]
+    pub fn is_trailer(&self, name: &[u8]) -> bool {
+        self.filesize == 0 && name == TRAILER
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    #[must_use]
+    pub fn info(&self, name: &[u8]) -> Info<External> {
+        debug_assert_eq!(self.namesize as usize - 1, name.len());
+
+        let mode = self.mode & 0o0007777;
+        let data = EntryKind {
+            kind: self.mode & 0o0170000,
+            nlink: self.nlink,
+            device: (self.rdevmajor, self.rdevminor),
+        }
+        .decode(self.size());
+        Info {
+            path: EncodedPath::from_vec(name.to_vec()),
+            inode: (u64::from(self.devmajor) << 32) | u64::from(self.ino),
+            mode,
+            user_id: self.uid,
+            group_id: self.gid,
+            created_at: DateTime::from_unix_timestamp(0),
+            modified_at: DateTime::from_unix_timestamp(self.mtime.into()),
+            accessed_at: None,
+            changed_at: None,
+            device: 0,
+            blocks: 0,
+            hash: None,
+            xattrs: Vec::new(),
+            data,
+        }
+    }
+}
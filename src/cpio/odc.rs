@@ -0,0 +1,123 @@
+use super::{EntryKind, TRAILER};
+use crate::fileinfo::Info;
+use crate::path::{EncodedPath, External};
+use crate::DateTime;
+
+/// Magic of the POSIX.1 portable format.
+pub(super) const ODC_MAGIC: &[u8; 6] = b"070707";
+
+/// Length of encoded header, including magic.
+pub const ODC_HEADER_LEN: usize = 76;
+
+/// Header of the old POSIX.1 portable format (`070707`, also known as "odc").
+/// Every field is stored as octal ASCII number, so there is no troubles with byte order.
+/// Unlike other formats there is no any padding after name or data.
+///
+/// This format is only supported for reading.
+/// See [`man 5 cpio`](http://man.he.net/man5/cpio) for details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OdcHeader {
+    pub(super) dev: u32,
+    pub(super) ino: u32,
+    pub(super) mode: u32,
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+    pub(super) nlink: u32,
+    pub(super) rdev: u32,
+    pub(super) mtime: u64,
+    /// Length of name, including NUL byte.
+    pub(super) namesize: u32,
+    pub(super) filesize: u64,
+}
+
+/// Parses octal number from ASCII digits.
+fn parse_octal(digits: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(digits).ok()?;
+    u64::from_str_radix(digits, 8).ok()
+}
+
+impl OdcHeader {
+    /// Decodes header from provided array, checking for correct magic.
+    #[must_use]
+    pub fn decode(data: &[u8; ODC_HEADER_LEN]) -> Option<Self> {
+        use std::convert::TryFrom;
+
+        let (magic, mut data) = data.split_at(ODC_MAGIC.len());
+        if magic != ODC_MAGIC {
+            return None;
+        }
+        let mut next = |length: usize| {
+            let (field, rest) = data.split_at(length);
+            data = rest;
+            parse_octal(field)
+        };
+        // Six octal digits always fit into u32.
+        let mut short = || next(6).and_then(|x| u32::try_from(x).ok());
+        let dev = short()?;
+        let ino = short()?;
+        let mode = short()?;
+        let uid = short()?;
+        let gid = short()?;
+        let nlink = short()?;
+        let rdev = short()?;
+        let mtime = next(11)?;
+        let namesize = next(6).and_then(|x| u32::try_from(x).ok())?;
+        let filesize = next(11)?;
+        Some(OdcHeader {
+            dev,
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            rdev,
+            mtime,
+            namesize,
+            filesize,
+        })
+    }
+
+    /// Returns true when current entry is an `TRAILER!!!` entry.
+    ///
+    /// Note: name must be NUL-ended.
+    #[must_use]
+    pub fn is_trailer(&self, name: &[u8]) -> bool {
+        self.filesize == 0 && name == TRAILER
+    }
+
+    /// Extracts info from header, using provided name.
+    ///
+    /// `info.hash` will be set to None.
+    #[must_use]
+    pub fn info(&self, name: &[u8]) -> Info<External> {
+        debug_assert_eq!(self.namesize as usize - 1, name.len());
+
+        let mode = self.mode & 0o0007777;
+        // Like in the binary format, device numbers are packed into `rdev`.
+        let data = EntryKind {
+            kind: self.mode & 0o0170000,
+            nlink: self.nlink,
+            device: (self.rdev >> 8, self.rdev & 0xFF),
+        }
+        .decode(self.filesize);
+        // Eleven octal digits are 33 bits, that is much less than i64 can store.
+        #[allow(clippy::cast_possible_wrap)]
+        let mtime = self.mtime as i64;
+        Info {
+            path: EncodedPath::from_vec(name.to_vec()),
+            inode: self.ino.into(),
+            mode,
+            user_id: self.uid,
+            group_id: self.gid,
+            created_at: DateTime::from_unix_timestamp(0),
+            modified_at: DateTime::from_unix_timestamp(mtime),
+            accessed_at: None,
+            changed_at: None,
+            device: 0,
+            blocks: 0,
+            hash: None,
+            xattrs: Vec::new(),
+            data,
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use crate::cpio::smart_read::{SmartBuf, SmartRead, SmartReader};
 use crate::cpio::state_machine::{AdvanceResult, Advanceable};
-use crate::cpio::CpioHeader;
+use crate::cpio::Format;
 use crate::fileinfo::{Info, UnspecifiedInfo};
 use crate::path::{Local, PathKind};
 use crate::types::Checksum;
 use crate::DefaultDigest;
 use fs2::FileExt;
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use snafu::{ResultExt, Snafu};
@@ -13,7 +14,6 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::fs::File;
-use tokio::io::AsyncRead;
 
 /// File in archive that is not archived yet.
 /// 
@@ -33,11 +33,10 @@ pub enum CantOpen {
 }
 
 /// Result of [`Pending::read`](Pending::read) function.
-pub type PendingReader<'a> = impl AsyncRead + 'a;
+pub type PendingReader<'a> = SmartReader<Reading<'a>>;
 
 /// Future that is returned by [`Pending::read_fut`](Pending::read_fut)
-pub type OpeningReadFuture<'a> =
-    impl std::future::Future<Output = Result<PendingReader<'a>, CantOpen>>;
+pub type OpeningReadFuture<'a> = BoxFuture<'a, Result<PendingReader<'a>, CantOpen>>;
 
 impl<P: PathKind> Pending<P> {
     #[must_use]
@@ -54,7 +53,8 @@ impl Pending<Local> {
     /// After file is completely read, [`self.calculated`] will be updated
     ///
     /// [`self.calculated`]: Self::calculated
-    pub async fn read(&mut self) -> Result<impl AsyncRead + '_, CantOpen> {
+    #[allow(clippy::unused_async)] // `read_fut` exposes it as a future.
+    pub async fn read(&mut self) -> Result<PendingReader<'_>, CantOpen> {
         let path = self.info.path.to_path().context(InvalidPath)?;
         let file = std::fs::File::open(path).context(IoFailed {})?;
         file.lock_exclusive().context(IoFailed {})?;
@@ -71,13 +71,13 @@ impl Pending<Local> {
 
     /// Same as [`Self::read`](Self::read), but returns named type.
     pub fn read_fut(&mut self) -> OpeningReadFuture<'_> {
-        self.read()
+        Box::pin(self.read())
     }
 
-    /// Returns [cpio header](CpioHeader) for this file.
+    /// Returns cpio header of given format for this file.
     #[must_use]
-    pub fn header(&self) -> Vec<u8> {
-        CpioHeader::encode(&self.info)
+    pub fn header(&self, format: Format) -> Vec<u8> {
+        format.encode(&self.info)
     }
 }
 
@@ -87,6 +87,7 @@ impl Pending<Local> {
 /// File --> Done-/
 ///      \-> Mismatch -> !
 /// ```
+#[allow(clippy::large_enum_variant)] // Mismatch is the final state, boxing it is not worth it.
 pub enum Reading<'a> {
     /// Should be unreachable.
     Poisoned,
@@ -108,7 +109,7 @@ impl SmartRead for Reading<'_> {
         let state = std::mem::replace(this, Reading::Poisoned);
         let (new_state, result) = match_advance! {
             match state.advance(cx, buf) {
-                Reading::Poisoned => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "State is poisoned"))),
+                Reading::Poisoned => return Poll::Ready(Err(io::Error::other("State is poisoned"))),
                 Reading::File => |x| x,
                 Reading::Done => Reading::Done,
                 Reading::Mismatch => Reading::Mismatch,
@@ -1,19 +1,34 @@
-use super::CpioHeader;
-use crate::fileinfo::Info;
+use super::odc::ODC_MAGIC;
+use super::{CpioHeader, Header, NewcHeader, OdcHeader, MAGIC, NEWC_HEADER_LEN, ODC_HEADER_LEN};
+use crate::fileinfo::{HardLinkInfo, Info, UnspecifiedInfo};
 use crate::path::External;
 use snafu::{OptionExt, ResultExt, Snafu};
-use std::io::SeekFrom;
-use std::mem::size_of;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, SeekFrom};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 /// Extractor of cpio archive.
 pub struct Reader<R> {
     reader: R,
+    links: Links,
+}
+
+/// Entries with several hard links, by their inodes.
+#[derive(Default)]
+struct Links {
+    /// Name of the entry which is a target of other links.
+    targets: HashMap<u64, Vec<u8>>,
+    /// Names of empty entries which were read before the one with data.
+    empty: HashMap<u64, Vec<Vec<u8>>>,
 }
 
 impl<R> Reader<R> {
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            links: Links::default(),
+        }
     }
 }
 
@@ -22,7 +37,12 @@ impl<R> Reader<R> {
 pub struct ReadFile<R> {
     filename: Vec<u8>,
     reader: R,
-    header: CpioHeader,
+    header: Header,
+    links: Links,
+    /// Name of the entry with the same inode, that was read earlier.
+    link_target: Option<Vec<u8>>,
+    /// Names of the empty entries with the same inode, that were read earlier.
+    earlier_links: Vec<Vec<u8>>,
 }
 
 #[derive(Debug, Snafu)]
@@ -34,28 +54,48 @@ pub struct ReadError {
 
 impl<R: AsyncRead + Unpin> ReadFile<R> {
     /// Writes contents of file to the provided writer.
+    ///
+    /// When archive stores checksums, they are verified too.
     pub async fn drain_to<W>(self, dst: &mut W) -> Result<Reader<R>, ReadError>
     where
         W: AsyncWrite + Unpin,
     {
         let size = self.header.size();
+        let expected = self.header.checksum();
         let mut file = self.reader.take(size);
 
+        let mut checksum = 0_u32;
         let mut buf = vec![0; 1024 * 1024];
         loop {
             let len = file.read(&mut buf).await?;
             if len == 0 {
                 break;
             }
+            if expected.is_some() {
+                checksum = buf[..len]
+                    .iter()
+                    .fold(checksum, |sum, &byte| sum.wrapping_add(byte.into()));
+            }
             dst.write_all(&buf[..len]).await?;
         }
 
-        let mut reader = file.into_inner();
-        if size % 2 != 0 {
-            reader.read_u8().await?;
+        if let Some(expected) = expected {
+            if checksum != expected {
+                let message = format!(
+                    "Checksum mismatch: expected 0x{expected:08X}, found 0x{checksum:08X}"
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+            }
         }
 
-        Ok(Reader { reader })
+        let mut reader = file.into_inner();
+        let padding = self.header.data_padding();
+        reader.read_exact(&mut [0; 4][..padding]).await?;
+
+        Ok(Reader {
+            reader,
+            links: self.links,
+        })
     }
 
     /// Skips file on non-seekable reader
@@ -68,22 +108,43 @@ impl<R: AsyncRead + Unpin> ReadFile<R> {
     where
         R: AsyncSeek,
     {
-        let mut size = self.header.size();
-        if size % 2 != 0 {
-            size += 1;
-        }
-        #[allow(clippy::cast_possible_wrap)] // size can't be moore than 2^48
-        self.reader.seek(SeekFrom::Current(size as i64)).await?;
+        let size = self.header.size();
+        let skipped = size
+            .checked_add(self.header.data_padding() as u64)
+            .and_then(|x| i64::try_from(x).ok())
+            .ok_or_else(|| {
+                let message = format!("File is too large to be skipped: {size} bytes");
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+        self.reader.seek(SeekFrom::Current(skipped)).await?;
         Ok(Reader {
             reader: self.reader,
+            links: self.links,
         })
     }
 
+    /// Names of the empty entries read earlier, which are hard links to this file.
+    ///
+    /// GNU cpio stores data with the last link in newc archives, so they are known only now.
+    /// These entries should be replaced by links to this file after it is written.
+    pub fn earlier_links(&self) -> &[Vec<u8>] {
+        &self.earlier_links
+    }
+
     /// Extracts info about this file. Hash is not set.
+    ///
+    /// Symlink target is not known until the data is read, see [`drain_to`](Self::drain_to).
     pub fn info(&self) -> Info<External> {
         // We should skip NUL byte in the end.
         let name = &self.filename[..self.filename.len() - 1];
-        self.header.info(name)
+        let mut info = self.header.info(name);
+        if let (Some(target), UnspecifiedInfo::File(file)) = (&self.link_target, &info.data) {
+            info.data = UnspecifiedInfo::HardLink(HardLinkInfo {
+                target: target.clone(),
+                links: file.links,
+            });
+        }
+        info
     }
 }
 
@@ -114,15 +175,49 @@ pub enum ReadingError {
 }
 
 impl<R: AsyncRead + Unpin> Reader<R> {
-    pub async fn advance(mut self) -> Result<NextItem<R>, ReadingError> {
-        let mut header = [0; size_of::<CpioHeader>()];
+    /// Reads the rest of header, which starts with already read `prefix`.
+    async fn read_rest<const N: usize>(&mut self, prefix: &[u8]) -> Result<[u8; N], ReadingError> {
+        let mut header = [0; N];
+        header[..prefix.len()].copy_from_slice(prefix);
         self.reader
-            .read_exact(&mut header)
+            .read_exact(&mut header[prefix.len()..])
             .await
             .context(IoFailed {})?;
-        let header = CpioHeader::decode(header).context(InvalidHeader)?;
+        Ok(header)
+    }
 
-        let mut filename = vec![0; header.namesize as usize];
+    /// Reads header of the next entry, detecting it's format and byte order by magic.
+    async fn read_header(&mut self) -> Result<Header, ReadingError> {
+        let mut magic = [0; 2];
+        self.reader
+            .read_exact(&mut magic)
+            .await
+            .context(IoFailed {})?;
+
+        if u16::from_le_bytes(magic) == MAGIC || u16::from_be_bytes(magic) == MAGIC {
+            let header = self.read_rest(&magic).await?;
+            let header = CpioHeader::decode(header).context(InvalidHeader)?;
+            return Ok(Header::Binary(header));
+        }
+
+        // All ASCII formats have six-digit magic, with the same prefix.
+        snafu::ensure!(magic == ODC_MAGIC[..magic.len()], InvalidHeader);
+        let magic: [u8; 6] = self.read_rest(&magic).await?;
+        if &magic == ODC_MAGIC {
+            let header: [u8; ODC_HEADER_LEN] = self.read_rest(&magic).await?;
+            let header = OdcHeader::decode(&header).context(InvalidHeader)?;
+            Ok(Header::Odc(header))
+        } else {
+            let header: [u8; NEWC_HEADER_LEN] = self.read_rest(&magic).await?;
+            let header = NewcHeader::decode(&header).context(InvalidHeader)?;
+            Ok(Header::Newc(header))
+        }
+    }
+
+    pub async fn advance(mut self) -> Result<NextItem<R>, ReadingError> {
+        let header = self.read_header().await?;
+
+        let mut filename = vec![0; header.namesize()];
         self.reader
             .read_exact(&mut filename)
             .await
@@ -131,9 +226,11 @@ impl<R: AsyncRead + Unpin> Reader<R> {
             return InvalidName.fail();
         }
 
-        if header.namesize % 2 != 0 {
-            self.reader.read_u8().await.context(IoFailed {})?;
-        }
+        let padding = header.name_padding();
+        self.reader
+            .read_exact(&mut [0; 4][..padding])
+            .await
+            .context(IoFailed {})?;
 
         if header.is_trailer(&filename) {
             // FIXME: Limit size of json.
@@ -148,10 +245,47 @@ impl<R: AsyncRead + Unpin> Reader<R> {
             return Ok(NextItem::End(UnpackedArchive { files }));
         }
 
+        let info = header.info(&filename[..filename.len() - 1]);
+        let mut link_target = None;
+        let mut earlier_links = Vec::new();
+        if let UnspecifiedInfo::File(file) = &info.data {
+            if file.links > 1 {
+                // We store data with the first link, and others are empty.
+                // GNU cpio stores it with the last link of newc archives instead,
+                // so empty links are linked to the first of them until data is found.
+                // In old formats every link has its own data, so they are read as separate files.
+                let name = info.path.as_bytes().to_vec();
+                let links = &mut self.links;
+                match links.targets.get(&info.inode) {
+                    Some(target) if file.size == 0 => {
+                        link_target = Some(target.clone());
+                        if let Some(empty) = links.empty.get_mut(&info.inode) {
+                            empty.push(name);
+                        }
+                    }
+                    Some(_) => {
+                        if let Some(empty) = links.empty.remove(&info.inode) {
+                            earlier_links = empty;
+                            links.targets.insert(info.inode, name);
+                        }
+                    }
+                    None => {
+                        if file.size == 0 {
+                            links.empty.insert(info.inode, vec![name.clone()]);
+                        }
+                        links.targets.insert(info.inode, name);
+                    }
+                }
+            }
+        }
+
         Ok(NextItem::File(ReadFile {
             filename,
             reader: self.reader,
             header,
+            links: self.links,
+            link_target,
+            earlier_links,
         }))
     }
 }
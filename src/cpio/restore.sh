@@ -0,0 +1,192 @@
+#!/usr/bin/env bash
+# Restores files from the colbak archive, without colbak itself.
+#
+# Usage:
+#   bash RESTORE.sh ARCHIVE [OUTPUT]   extracts every file into OUTPUT (current directory by default)
+#   bash RESTORE.sh --json ARCHIVE     prints JSON description of files, stored after the trailer
+#
+# Only bash 4, od, tail, head, mkdir, chmod, touch and ln are needed,
+# mkfifo and mknod are used for special files.
+# Archive is a usual cpio archive, so `cpio -idm --no-absolute-filenames < ARCHIVE` works too,
+# except for files larger than 4GB in the old binary format. See MANIFEST.txt for details.
+# No `pipefail`: `tail` is killed by SIGPIPE, when `head` read everything it needed.
+set -eu
+
+json=0
+if [ "${1:-}" = "--json" ]; then
+    json=1
+    shift
+fi
+archive=${1:?"Usage: $0 [--json] ARCHIVE [OUTPUT]"}
+output=${2:-.}
+
+# Prints `$2` bytes of the archive, starting at offset `$1`.
+bytes() {
+    tail -c +"$(($1 + 1))" "$archive" | head -c "$2"
+}
+
+# Paths of files with several hard links, by their inodes.
+declare -A links=()
+# Symlinks created from the archive, nothing is written through them.
+declare -A symlinks=()
+
+# Creates file, directory, link or special file, or skips the entry.
+# Arguments: name, mode, mtime, size, offset of data, inode, number of links, device major and minor.
+restore() {
+    local name=$1 mode=$2 mtime=$3 size=$4 data=$5 ino=$6 nlink=$7 major=$8 minor=$9
+    # Leading slashes are dropped, like cpio does with --no-absolute-filenames.
+    local path=${name#"${name%%[!/]*}"}
+    case "/$path/" in
+        */../*)
+            echo "Skipping $name: it is outside of the output directory" >&2
+            return
+            ;;
+    esac
+    # Repeated slashes and `.` components are dropped, so symlinks are found by their names.
+    path=/$path/
+    while [[ $path == *//* || $path == */./* ]]; do
+        path=${path//\/.\//\/}
+        path=${path//\/\//\/}
+    done
+    path=${path#/}
+    path=${path%/}
+    case "$path" in
+        "" | RESTORE.sh | MANIFEST.txt) return ;;
+    esac
+    local parent=$path
+    while [ -n "$parent" ]; do
+        if [ -n "${symlinks[$parent]:-}" ]; then
+            echo "Skipping $name: $parent is a symlink" >&2
+            return
+        fi
+        case "$parent" in
+            */*) parent=${parent%/*} ;;
+            *) parent= ;;
+        esac
+    done
+    local target="$output/$path"
+    mkdir -p "$(dirname "$target")"
+    case $((mode & 0170000)) in
+        $((0100000)))
+            # Data is stored with the first link only.
+            if [ "$nlink" -gt 1 ] && [ "$size" -eq 0 ] && [ -n "${links[$ino]:-}" ]; then
+                ln -f "$output/${links[$ino]}" "$target"
+                return
+            fi
+            if [ "$nlink" -gt 1 ]; then
+                links[$ino]=$path
+            fi
+            bytes "$data" "$size" >"$target"
+            ;;
+        $((0040000)))
+            mkdir -p "$target"
+            ;;
+        $((0120000)))
+            # Symlink target is the entry data. Mode and time of the link itself are not restored.
+            ln -sfn "$(bytes "$data" "$size")" "$target"
+            symlinks[$path]=1
+            return
+            ;;
+        $((0010000)))
+            mkfifo "$target"
+            ;;
+        $((0020000)) | $((0060000)))
+            local kind=c
+            if [ $((mode & 0170000)) -eq $((0060000)) ]; then
+                kind=b
+            fi
+            if ! mknod "$target" "$kind" "$major" "$minor"; then
+                echo "Skipping $name: unable to create the device" >&2
+                return
+            fi
+            ;;
+        *)
+            echo "Skipping $name: unsupported file type" >&2
+            return
+            ;;
+    esac
+    chmod "$(printf '%o' $((mode & 07777)))" "$target"
+    # GNU touch understands `@<unix timestamp>`, others keep the current time.
+    touch -d "@$mtime" "$target" 2>/dev/null || true
+}
+
+# Old binary format: 13 little-endian 16-bit words, followed by the name.
+# Four-byte numbers are stored with the most significant half first.
+# Files larger than 4GB keep bits 32..47 of their size in the `rdev` field,
+# devices keep their numbers there.
+binary() {
+    local pos=0 header name namesize mode mtime size data rdev
+    while true; do
+        # od splits output into lines of 16 bytes.
+        read -r -a header <<<"$(od -An -v -tu1 -j "$pos" -N 26 "$archive" | tr '\n' ' ')"
+        local -a word=()
+        for i in $(seq 0 12); do
+            word[i]=$((header[2 * i] + 256 * header[2 * i + 1]))
+        done
+        if [ "${word[0]}" -ne $((070707)) ]; then
+            echo "Unexpected magic at $pos" >&2
+            exit 1
+        fi
+        mode=${word[3]}
+        rdev=${word[7]}
+        mtime=$((word[8] * 65536 + word[9]))
+        namesize=${word[10]}
+        size=$((word[11] * 65536 + word[12]))
+        if [ $((mode & 0170000)) -eq $((0100000)) ]; then
+            size=$((rdev * 4294967296 + size))
+        fi
+        name=$(bytes $((pos + 26)) $((namesize - 1)))
+        # Name and data are padded to even size.
+        data=$((pos + 26 + namesize + namesize % 2))
+        if [ "$name" = "TRAILER!!!" ]; then
+            trailer "$data"
+            return
+        fi
+        restore "$name" "$mode" "$mtime" "$size" "$data" \
+            $((word[1] * 65536 + word[2])) "${word[6]}" $((rdev >> 8)) $((rdev & 255))
+        pos=$((data + size + size % 2))
+    done
+}
+
+# SVR4 portable format: 110 bytes of ASCII, where numbers are written as 8 hex digits.
+# Header together with the name is padded to multiple of four bytes, and so is data.
+# Regular files keep bits 32..63 of their size in `rdevmajor`.
+newc() {
+    local pos=0 header name namesize mode mtime size data
+    while true; do
+        header=$(bytes "$pos" 110)
+        field() {
+            echo $((16#${header:6+8*$1:8}))
+        }
+        mode=$(field 1)
+        mtime=$(field 5)
+        size=$(field 6)
+        if [ $((mode & 0170000)) -eq $((0100000)) ]; then
+            size=$(($(field 9) * 4294967296 + size))
+        fi
+        namesize=$(field 11)
+        name=$(bytes $((pos + 110)) $((namesize - 1)))
+        data=$(((pos + 110 + namesize + 3) / 4 * 4))
+        if [ "$name" = "TRAILER!!!" ]; then
+            trailer "$data"
+            return
+        fi
+        restore "$name" "$mode" "$mtime" "$size" "$data" \
+            $(($(field 7) * 4294967296 + $(field 0))) "$(field 4)" "$(field 9)" "$(field 10)"
+        pos=$(((data + size + 3) / 4 * 4))
+    done
+}
+
+# Everything after the trailer is a JSON array.
+trailer() {
+    if [ "$json" = 1 ]; then
+        tail -c +"$(($1 + 1))" "$archive"
+        echo
+    fi
+}
+
+if [ "$(head -c 6 "$archive" | tr -d '\0')" = "070701" ]; then
+    newc
+else
+    binary
+fi
@@ -0,0 +1,131 @@
+//! Restore script and manifest, that are placed at the beginning of the archive.
+//!
+//! Archives should outlive colbak itself, so they explain how to read them in plain text.
+
+use super::pending::Pending;
+use super::Format;
+use crate::fileinfo::{FileInfo, Info, UnspecifiedInfo};
+use crate::path::{EncodedPath, EscapedString, Local};
+use crate::DateTime;
+use std::fmt::Write;
+
+/// Name of the bash script that extracts files from the archive.
+pub const SCRIPT_NAME: &str = "RESTORE.sh";
+
+/// Name of the human-readable description of the archive.
+pub const MANIFEST_NAME: &str = "MANIFEST.txt";
+
+const SCRIPT: &str = include_str!("restore.sh");
+
+/// Returns true when the entry is a script or a manifest, not a backed up file.
+///
+/// ```
+/// # use colbak_lib::cpio::script::is_embedded;
+/// assert!(is_embedded(b"RESTORE.sh"));
+/// assert!(!is_embedded(b"/home/me/RESTORE.sh"));
+/// ```
+#[must_use]
+pub fn is_embedded(name: &[u8]) -> bool {
+    name == SCRIPT_NAME.as_bytes() || name == MANIFEST_NAME.as_bytes()
+}
+
+/// Describes archive format and lists all files of the archive.
+#[must_use]
+pub fn manifest(format: Format, files: &[Pending<Local>]) -> String {
+    let format = match format {
+        Format::Binary => "old binary format, little-endian",
+        Format::Newc => "SVR4 portable format without checksums (070701)",
+    };
+    let mut result = format!(
+        "This is a cpio archive in the {format}, created by colbak.
+
+Files can be extracted by the {SCRIPT_NAME} placed in this archive:
+    cpio -i --to-stdout {SCRIPT_NAME} < archive.cpio > {SCRIPT_NAME}
+    bash {SCRIPT_NAME} archive.cpio output/
+Or by any cpio implementation:
+    cpio -idm --no-absolute-filenames < archive.cpio
+
+Things that usual archivers do not know about:
+- In the old binary format, `rdev` field stores bits 32..47 of the file size.
+  That allows files up to 256TB, while cpio would see only lower 32 bits
+  of the size and fail to read the rest of archive. {SCRIPT_NAME} handles it.
+- In the old binary format, `dev` and `ino` fields together store lower 32 bits of the inode.
+  Inodes, user and group ids are truncated to 16 bits there.
+- In the SVR4 format, `rdevmajor` field of regular files stores bits 32..63 of the file size,
+  and `devmajor` stores bits 32..63 of the inode. Files up to 4GB are read by cpio as usual,
+  but larger ones are misread, and the rest of archive is lost for it. {SCRIPT_NAME} handles them.
+- Hard links share the inode, and only the first of them holds the data.
+  Usual cpio expects data with the last link, so it extracts other links as empty files.
+- Symlink target is stored as the entry data. Devices keep their numbers in `rdev`,
+  one byte for major and one for minor in the old binary format.
+- Modification time is stored in headers as seconds since 1970-01-01, timestamps after 2106 are clamped.
+  Full timestamps are stored in the JSON below.
+- `TRAILER!!!` entry is followed by the JSON array, which describes every file
+  (`bash {SCRIPT_NAME} --json archive.cpio` prints it):
+    path         base64 of the original path bytes, in an array
+    inode, mode  like in `stat`, mode includes file type bits
+    user_id, group_id
+    created_at, modified_at, accessed_at, changed_at
+                 [year, day of the year, seconds since midnight, nanoseconds] in UTC,
+                 access and status change times may be missing
+    device       device containing the file, like `st_dev`
+    blocks       number of allocated 512-byte blocks
+    xattrs       extended attributes and ACLs, as [{{\"name\": <base64>, \"value\": <base64>}}]
+    hash         base64 of the SHA-256 of file contents, padded with zeroes to 64 bytes
+    File         {{\"size\": <bytes>, \"links\": <count, when more than 1>}} for files
+    Symlink      {{\"target\": <base64 of the target>}}
+    HardLink     {{\"target\": <base64 of the path that holds the data>, \"links\": <count>}}
+    Device       {{\"kind\": \"Char\" or \"Block\", \"major\": <number>, \"minor\": <number>}}
+    Dir, Fifo    for directories and named pipes, `Unknown` for everything else
+  To verify a file:
+    sha256sum file
+    echo <hash> | base64 -d | head -c 32 | od -An -tx1 | tr -d ' \\n'
+
+Files (size in bytes, modification time in UTC, path):
+"
+    );
+    for pending in files {
+        let info = &pending.info;
+        let size = info.size().map(|x| x.to_string());
+        let _infallible = writeln!(
+            result,
+            "{:>14} {} {}",
+            size.as_deref().unwrap_or("-"),
+            info.modified_at.format("%F %T"),
+            info.path.escaped()
+        );
+    }
+    result
+}
+
+/// Encodes script and manifest as two archive entries.
+#[must_use]
+pub fn prelude(format: Format, files: &[Pending<Local>]) -> Vec<u8> {
+    let manifest = manifest(format, files);
+    let mut result = Vec::new();
+    for (name, mode, content) in &[
+        (SCRIPT_NAME, 0o755, SCRIPT.as_bytes()),
+        (MANIFEST_NAME, 0o644, manifest.as_bytes()),
+    ] {
+        let info: Info<Local> = Info {
+            path: EncodedPath::from_vec(name.as_bytes().to_vec()).cast(),
+            inode: 0,
+            mode: *mode,
+            user_id: 0,
+            group_id: 0,
+            created_at: DateTime::now_utc(),
+            modified_at: DateTime::now_utc(),
+            accessed_at: None,
+            changed_at: None,
+            device: 0,
+            blocks: 0,
+            hash: None,
+            xattrs: Vec::new(),
+            data: UnspecifiedInfo::File(FileInfo::new(content.len() as u64)),
+        };
+        result.extend_from_slice(&format.encode(&info));
+        result.extend_from_slice(content);
+        result.resize(result.len() + format.data_padding(content.len() as u64), 0);
+    }
+    result
+}
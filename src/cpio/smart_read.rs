@@ -44,7 +44,7 @@ pub struct SmartBuf<'a, 'b, 'c> {
     is_eof: bool,
 }
 
-impl<'a, 'b, 'c> SmartBuf<'a, 'b, 'c> {
+impl SmartBuf<'_, '_, '_> {
     /// Writes slice to the buffers.
     /// When possible, data written to the buffer provided by reader, but when no more space left,
     /// data is temporary saved to the internal buffer.
@@ -181,7 +181,6 @@ where
                         return Poll::Ready(Ok(()));
                     }
                     // Otherwise we will try reading again.
-                    continue;
                 }
             }
         }
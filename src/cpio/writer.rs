@@ -3,10 +3,9 @@ use super::smart_read::SmartReadExt;
 use super::smart_read::SmartWrap;
 use super::state_machine::{AdvanceResult, Advanceable};
 use crate::cpio::smart_read::{SmartBuf, SmartRead};
-use crate::cpio::Archive;
+use crate::cpio::{Archive, Format};
 use either::Either;
 use pin_project_lite::pin_project;
-use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -20,7 +19,7 @@ pin_project! {
     }
 }
 
-impl<'a> AsyncRead for Reader<'a> {
+impl AsyncRead for Reader<'_> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -31,26 +30,35 @@ impl<'a> AsyncRead for Reader<'a> {
 }
 
 impl Reader<'_> {
-    pub fn new(archive: &mut Archive) -> Reader {
+    pub fn new(archive: &mut Archive) -> Reader<'_> {
+        let prelude = archive.prelude();
+        let none = states::None {
+            format: archive.format,
+            archive: std::ptr::from_mut(archive),
+            phantom: std::marker::PhantomData,
+            position: 0,
+        };
+        let state = if prelude.is_empty() {
+            State::None(none)
+        } else {
+            State::Prelude(states::Prelude { none, prelude })
+        };
         Reader {
-            inner: State::None(states::None {
-                archive: archive as *mut _,
-                phantom: std::marker::PhantomData::default(),
-                position: 0,
-            })
-            .wrap(),
+            inner: state.wrap(),
         }
     }
 }
 
 /// State machine for [`Reader`](Reader)
 /// ```text
-///     ↙--------↖                   ↙--↖
-/// None -> Header -> OpeningFile -> File -> None again
-///     \-> Trailer -> EOF-⸜
-///                     ↖--/
+///                ↙--------↖                   ↙--↖
+/// Prelude -> None -> Header -> OpeningFile -> File -> None again
+///                \-> Trailer -> EOF-⸜
+///                                ↖--/
 /// ```
 enum State<'a> {
+    /// Writing restore script and manifest, if they are enabled
+    Prelude(states::Prelude<'a>),
     /// «Neutral» state
     None(states::None<'a>),
     /// Writing a header for chosen file from archive
@@ -77,7 +85,8 @@ impl SmartRead for State<'_> {
         let state = std::mem::replace(this, State::Poisoned);
         let (new_state, result) = match_advance! {
             match state.advance(cx, buf) {
-                State::Poisoned => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "State is poisoned"))),
+                State::Poisoned => return Poll::Ready(Err(io::Error::other("State is poisoned"))),
+                State::Prelude => State::None,
                 State::None => |x| match x {
                     Either::Left(header) => State::Header(header),
                     Either::Right(trailer) => State::Trailer(trailer),
@@ -107,11 +116,17 @@ mod states {
     use super::*;
 
     pub struct None<'a> {
+        pub format: Format,
         pub archive: *mut Archive,
         pub phantom: std::marker::PhantomData<&'a mut Archive>,
         pub position: usize,
     }
 
+    pub struct Prelude<'a> {
+        pub none: None<'a>,
+        pub prelude: Vec<u8>,
+    }
+
     pub struct Header<'a> {
         pub none: None<'a>,
         pub file: &'a mut super::Pending<Local>,
@@ -119,7 +134,7 @@ mod states {
 
     pub struct OpeningFile<'a> {
         pub none: None<'a>,
-        pub future: Pin<Box<super::OpeningReadFuture<'a>>>,
+        pub future: super::OpeningReadFuture<'a>,
     }
 
     pub struct File<'a> {
@@ -135,6 +150,18 @@ mod states {
     pub struct Eof;
 }
 
+impl<'a> Advanceable for states::Prelude<'a> {
+    type Next = states::None<'a>;
+    fn advance(
+        self,
+        _cx: &mut Context<'_>,
+        buf: &mut SmartBuf<'_, '_, '_>,
+    ) -> AdvanceResult<Self, Self::Next> {
+        buf.put_slice(&self.prelude);
+        AdvanceResult::Ready(self.none)
+    }
+}
+
 impl<'a> Advanceable for states::None<'a> {
     type Next = Either<states::Header<'a>, states::Trailer<'a>>;
     fn advance(
@@ -167,7 +194,7 @@ impl<'a> Advanceable for states::Header<'a> {
         _cx: &mut Context<'_>,
         buf: &mut SmartBuf<'_, '_, '_>,
     ) -> AdvanceResult<Self, Self::Next> {
-        let header = self.file.header();
+        let header = self.file.header(self.none.format);
         buf.put_slice(&header);
 
         let next = if self.file.info.size().is_some() {
@@ -175,10 +202,16 @@ impl<'a> Advanceable for states::Header<'a> {
 
             Either::Left(states::OpeningFile {
                 none: self.none,
-                future: Box::pin(future),
+                future,
             })
         } else {
-            // Move to the next file, nothing to write here: there is no size.
+            // Move to the next file, nothing to read here: there is no size.
+            // Symlink targets are known already, so they are written right away.
+            if let Some(data) = self.file.info.inline_data() {
+                buf.put_slice(data);
+                let padding = self.none.format.data_padding(data.len() as u64);
+                buf.put_slice(&[0; 4][..padding]);
+            }
             self.none.position += 1;
 
             Either::Right(self.none)
@@ -197,7 +230,7 @@ impl<'a> Advanceable for states::OpeningFile<'a> {
         match self.future.as_mut().poll(cx) {
             Poll::Pending => AdvanceResult::Pending(self),
             Poll::Ready(Err(err)) => {
-                AdvanceResult::Failed(io::Error::new(io::ErrorKind::Other, err))
+                AdvanceResult::Failed(io::Error::other(err))
             }
             Poll::Ready(Ok(reader)) => {
                 // OpeningFile -> File
@@ -223,9 +256,8 @@ impl<'a> Advanceable for states::File<'a> {
             Poll::Ready(Err(e)) => AdvanceResult::Failed(e),
             Poll::Ready(Ok(None)) => {
                 // EOF
-                if self.length % 2 != 0 {
-                    buf.put_slice(&[0]);
-                }
+                let padding = self.none.format.data_padding(self.length);
+                buf.put_slice(&[0; 4][..padding]);
                 // Switch to next file
                 self.none.position += 1;
                 AdvanceResult::Ready(Either::Right(self.none))
@@ -238,7 +270,7 @@ impl<'a> Advanceable for states::File<'a> {
     }
 }
 
-impl<'a> Advanceable for states::Trailer<'a> {
+impl Advanceable for states::Trailer<'_> {
     type Next = states::Eof;
     fn advance(
         self,
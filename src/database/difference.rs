@@ -15,6 +15,9 @@ use super::{error::*, RowId};
 /// It looks like bitflag, but it is not.
 /// Each row in database may have only one of these bits set.
 /// Making an bitflag allows to filter rows much more easily and efficient.
+///
+/// Directories, symlinks, named pipes and devices are compared by path,
+/// and they are only ever `Created` or `Changed`, with zero size.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, num_enum::TryFromPrimitive)]
 #[repr(u8)]
 pub enum DiffType {
@@ -30,6 +33,14 @@ pub enum DiffType {
     ///
     /// [identifier]: crate::fileinfo::FileIdentifier
     Changed = 0b100,
+    /// File is placed at another path, but it's contents are the same.
+    ///
+    /// Either [identifier] did not change, or file has the same checksum as some deleted one.
+    /// Checksums are compared only when they are known in both snapshots,
+    /// see [`Hashing`](super::Hashing).
+    ///
+    /// [identifier]: crate::fileinfo::FileIdentifier
+    Moved = 0b1000,
 }
 
 impl DiffType {
@@ -60,6 +71,14 @@ pub enum DiffRow {
         size: u64,
         path: EncodedPath<External>,
     },
+    Moved {
+        rowid: RowId,
+        before: Info<External>,
+        after: Info<External>,
+        size: u64,
+        /// New path of the file.
+        path: EncodedPath<External>,
+    },
 }
 
 impl DiffRow {
@@ -69,6 +88,7 @@ impl DiffRow {
             DiffRow::Deleted { .. } => DiffType::Deleted,
             DiffRow::Created { .. } => DiffType::Created,
             DiffRow::Changed { .. } => DiffType::Changed,
+            DiffRow::Moved { .. } => DiffType::Moved,
         }
     }
 }
@@ -88,7 +108,7 @@ impl<'a> Diff<'a> {
         before_snap: &'a SqlName,
         after_snap: &'a SqlName,
     ) -> Result<Self, Error> {
-        let name = SqlName::new(format!("diff_{}_vs_{}", before_snap, after_snap)).context(
+        let name = SqlName::new(format!("diff_{before_snap}_vs_{after_snap}")).context(
             CantBuildDiffName {
                 before: before_snap,
                 after: after_snap,
@@ -133,14 +153,17 @@ impl<'a> Diff<'a> {
         let deleted = DiffType::Deleted as u8;
         let created = DiffType::Created as u8;
         let changed = DiffType::Changed as u8;
+        let moved = DiffType::Moved as u8;
         self.db
             .conn
             .execute_batch(&fmt_sql!(
-                r#"
+                r"
                     CREATE INDEX IF NOT EXISTS {after}.idx_ident ON snap ( identifier );
                     CREATE INDEX IF NOT EXISTS {before}.idx_ident ON snap ( identifier );
                     CREATE INDEX IF NOT EXISTS {after}.idx_info ON snap ( info );
                     CREATE INDEX IF NOT EXISTS {before}.idx_info ON snap ( info );
+                    CREATE INDEX IF NOT EXISTS {after}.idx_path ON snap ( path );
+                    CREATE INDEX IF NOT EXISTS {before}.idx_path ON snap ( path );
 
                     DELETE FROM {name}.diff;
 
@@ -171,24 +194,201 @@ impl<'a> Diff<'a> {
                     FROM {after}.snap
                     INNER JOIN {before}.snap
                         USING (identifier)
+                    -- Access time and device change while the file stays the same,
+                    -- and checksum may be recorded only in one of snapshots.
                     WHERE length(identifier) > 0
-                        AND {after}.snap.info != {before}.snap.info;
-                "#
+                        AND {after}.snap.path = {before}.snap.path
+                        AND json_remove({after}.snap.info, '$.accessed_at', '$.device', '$.hash')
+                            != json_remove({before}.snap.info, '$.accessed_at', '$.device', '$.hash');
+
+                    -- Hard links share the identifier, so they are not moved
+                    -- unless some of the paths is gone.
+                    INSERT INTO {name}.diff
+                        (before, after, type, size, path)
+                    SELECT
+                        {before}.snap.id,
+                        {after}.snap.id,
+                        {moved},
+                        {after}.snap.size,
+                        {after}.snap.path
+                    FROM {after}.snap
+                    INNER JOIN {before}.snap
+                        USING (identifier)
+                    WHERE length(identifier) > 0
+                        AND {after}.snap.path != {before}.snap.path
+                        AND {after}.snap.path NOT IN (
+                            SELECT path FROM {before}.snap AS b
+                            WHERE b.identifier = {after}.snap.identifier
+                        )
+                        AND {before}.snap.path NOT IN (
+                            SELECT path FROM {after}.snap AS a
+                            WHERE a.identifier = {before}.snap.identifier
+                        );
+
+                    -- Contents of the created file are the same as of the deleted one.
+                    INSERT INTO {name}.diff
+                        (before, after, type, size, path)
+                    SELECT
+                        MIN(deleted.before), created.after, {moved}, created.size, created.path
+                    FROM {name}.diff AS created
+                    INNER JOIN {after}.snap AS a ON a.id = created.after
+                    INNER JOIN {before}.snap AS b ON b.hash = a.hash
+                    INNER JOIN {name}.diff AS deleted ON deleted.before = b.id
+                    WHERE created.type = {created} AND deleted.type = {deleted}
+                    GROUP BY created.after;
+
+                    DELETE FROM {name}.diff
+                    WHERE type = {created}
+                        AND after IN (SELECT after FROM {name}.diff WHERE type = {moved});
+                    DELETE FROM {name}.diff
+                    WHERE type = {deleted}
+                        AND before IN (SELECT before FROM {name}.diff WHERE type = {moved});
+                "
             ))
             .context(SqliteFailed)?;
+        self.fill_entries()
+    }
 
+    /// Adds directories, symlinks, pipes and devices. They have no identifier,
+    /// so they are compared by path. They are archived too,
+    /// so archives can be extracted without the index.
+    fn fill_entries(&self) -> Result<(), Error> {
+        let before = self.before_snap;
+        let after = self.after_snap;
+        let name = &self.name;
+        let created = DiffType::Created as u8;
+        let changed = DiffType::Changed as u8;
+        self.db
+            .conn
+            .execute(
+                &fmt_sql!(
+                    r"
+                    INSERT INTO {name}.diff
+                        (before, after, type, size, path)
+                    SELECT
+                        b.id,
+                        a.id,
+                        CASE WHEN b.id IS NULL THEN {created} ELSE {changed} END,
+                        0,
+                        a.path
+                    FROM {after}.snap AS a
+                    LEFT JOIN {before}.snap AS b
+                        ON b.path = a.path AND length(b.identifier) = 0
+                    WHERE length(a.identifier) = 0
+                        AND (b.id IS NULL
+                            OR json_remove(a.info, '$.accessed_at', '$.device', '$.hash')
+                                != json_remove(b.info, '$.accessed_at', '$.device', '$.hash'))
+                    "
+                ),
+                params![],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Adds files that were [deferred](Database::add_deferred) by the previous backups,
+    /// so they are uploaded now. Returns number of such files.
+    ///
+    /// Deferred files that are not present in the `after` snapshot are forgotten:
+    /// they are either deleted or modified, and the modified version is in the diff already.
+    /// Deferred directories, symlinks, pipes and devices are found by their path.
+    pub fn include_deferred(&self) -> Result<usize, Error> {
+        let after = self.after_snap;
+        let name = &self.name;
+        let created = DiffType::Created as u8;
+        let changed = DiffType::Changed as u8;
+        let moved = DiffType::Moved as u8;
+        let conn = &self.db.conn;
+        conn.execute(
+            &fmt_sql!(
+                "DELETE FROM deferred_files
+                WHERE identifier NOT IN (SELECT identifier FROM {after}.snap)"
+            ),
+            params![],
+        )
+        .context(SqliteFailed)?;
+        conn.execute(
+            &fmt_sql!(
+                "DELETE FROM deferred_entries
+                WHERE path NOT IN (SELECT path FROM {after}.snap WHERE length(identifier) = 0)"
+            ),
+            params![],
+        )
+        .context(SqliteFailed)?;
+        // Moved files are never packed, so they are uploaded as created ones, like changed ones.
+        let updated = conn
+            .execute(
+                &fmt_sql!(
+                    "UPDATE {name}.diff SET type = {created}, before = NULL
+                    WHERE type IN ({changed}, {moved}) AND after IN (
+                        SELECT id FROM {after}.snap
+                        WHERE identifier IN (SELECT identifier FROM deferred_files)
+                    )"
+                ),
+                params![],
+            )
+            .context(SqliteFailed)?;
+        let inserted = conn
+            .execute(
+                &fmt_sql!(
+                    "INSERT INTO {name}.diff
+                        (before, after, type, size, path)
+                    SELECT
+                        NULL, id, {created}, size, path
+                    FROM {after}.snap
+                    WHERE identifier IN (SELECT identifier FROM deferred_files)
+                        AND id NOT IN (SELECT after FROM {name}.diff WHERE after IS NOT NULL)"
+                ),
+                params![],
+            )
+            .context(SqliteFailed)?;
+        let entries = conn
+            .execute(
+                &fmt_sql!(
+                    "INSERT INTO {name}.diff
+                        (before, after, type, size, path)
+                    SELECT
+                        NULL, id, {created}, 0, path
+                    FROM {after}.snap
+                    WHERE length(identifier) = 0
+                        AND path IN (SELECT path FROM deferred_entries)
+                        AND id NOT IN (SELECT after FROM {name}.diff WHERE after IS NOT NULL)"
+                ),
+                params![],
+            )
+            .context(SqliteFailed)?;
+        Ok(updated + inserted + entries)
+    }
+
+    /// Turns row into [`Created`](DiffType::Created) one, so the file is uploaded again.
+    ///
+    /// Used for moved files which contents can't be found in any archive.
+    pub fn mark_created(&self, rowid: RowId) -> Result<(), Error> {
+        let name = &self.name;
+        let created = DiffType::Created as u8;
+        self.db
+            .conn
+            .execute(
+                &fmt_sql!("UPDATE {name}.diff SET type = {created}, before = NULL WHERE ROWID = ?"),
+                params![rowid.0],
+            )
+            .context(SqliteFailed)?;
         Ok(())
     }
 
     pub fn query(&'a self) -> DiffQuery<'a> {
         DiffQuery {
             diff: self,
-            enabled_kinds: 0b111,
+            enabled_kinds: 0b1111,
             allowed_sizes: 0..=u64::MAX,
+            ordered: false,
         }
     }
 }
 
+/// Columns that are needed to build [`DiffRow`](DiffRow).
+const ROW_COLUMNS: &str = "type, before, after, size, path, ROWID";
+
 /// Small structure that helps making efficient queries to the [`Diff`](Diff).
 #[must_use]
 pub struct DiffQuery<'a> {
@@ -197,6 +397,8 @@ pub struct DiffQuery<'a> {
     enabled_kinds: u8,
     /// Size of files that will be returned
     allowed_sizes: RangeInclusive<u64>,
+    /// Whether rows are sorted by path
+    ordered: bool,
 }
 
 impl<'a> DiffQuery<'a> {
@@ -216,33 +418,33 @@ impl<'a> DiffQuery<'a> {
             .diff
             .db
             .conn
-            .query_row(
-                &fmt_sql!("SELECT info FROM {source}.snap WHERE id=?"),
-                params![id],
-                |row| row.get(0),
-            )
+            .prepare_cached(&fmt_sql!("SELECT info FROM {source}.snap WHERE id=?"))
+            .context(SqliteFailed)?
+            .query_row(params![id], |row| row.get(0))
             .context(SqliteFailed)?;
         let info = serde_json::from_str(&json).context(JsonFailed)?;
         Ok(Some(info))
     }
 
     /// Selects provided columns with correct filters.
-    fn select(&'a self, select: &str) -> Result<rusqlite::Statement, Error> {
+    fn select(&'a self, select: &str) -> Result<rusqlite::Statement<'a>, Error> {
         let name = &self.diff.name;
         let type_filter = self.enabled_kinds;
         let min_size = self.allowed_sizes.start();
         let max_size = self.allowed_sizes.end();
+        let order = if self.ordered { "ORDER BY path" } else { "" };
         let statement = self
             .diff
             .db
             .conn
             .prepare(&fmt_sql!(
-                r#"
+                r"
                 SELECT {select}
                 FROM {name}.diff
                 WHERE (type & {type_filter}) != 0
                 AND {min_size} <= size AND size <= {max_size}
-                "#
+                {order}
+                "
             ))
             .context(SqliteFailed)?;
         Ok(statement)
@@ -256,53 +458,81 @@ impl<'a> DiffQuery<'a> {
             .context(SqliteFailed)
     }
 
+    /// Converts row selected with [`ROW_COLUMNS`](ROW_COLUMNS) to the [`DiffRow`](DiffRow).
+    fn parse_row(&'a self, row: &rusqlite::Row) -> Result<DiffRow, Error> {
+        let kind: u8 = row.get(0).context(SqliteFailed)?;
+        let before: Option<u64> = row.get(1).context(SqliteFailed)?;
+        let after: Option<u64> = row.get(2).context(SqliteFailed)?;
+        let size: u64 = row.get(3).context(SqliteFailed)?;
+        let path: Vec<u8> = row.get(4).context(SqliteFailed)?;
+        let rowid = row.get(5).context(SqliteFailed)?;
+
+        let kind = DiffType::parse(kind).context(WrongDiffType { found: kind })?;
+        // FIXME: This is not fast at all.
+        let before = self.load_info(self.diff.before_snap, before)?;
+        let after = self.load_info(self.diff.after_snap, after)?;
+        let path = EncodedPath::from_vec(path);
+        let rowid = RowId(rowid);
+
+        let row = match kind {
+            DiffType::Deleted => DiffRow::Deleted {
+                rowid,
+                path,
+                size,
+                before: before.context(InvalidDiffRow)?,
+            },
+            DiffType::Created => DiffRow::Created {
+                rowid,
+                path,
+                size,
+                after: after.context(InvalidDiffRow)?,
+            },
+            DiffType::Changed => DiffRow::Changed {
+                rowid,
+                path,
+                size,
+                before: before.context(InvalidDiffRow)?,
+                after: after.context(InvalidDiffRow)?,
+            },
+            DiffType::Moved => DiffRow::Moved {
+                rowid,
+                path,
+                size,
+                before: before.context(InvalidDiffRow)?,
+                after: after.context(InvalidDiffRow)?,
+            },
+        };
+        Ok(row)
+    }
+
+    /// Loads single row by it's `ROWID`. Filters are ignored.
+    pub fn get(&'a self, rowid: RowId) -> Result<DiffRow, Error> {
+        let name = &self.diff.name;
+        let mut statement = self
+            .diff
+            .db
+            .conn
+            .prepare_cached(&fmt_sql!(
+                "SELECT {ROW_COLUMNS} FROM {name}.diff WHERE ROWID = ?"
+            ))
+            .context(SqliteFailed)?;
+        let mut rows = statement.query(params![rowid.0]).context(SqliteFailed)?;
+        let row = rows.next().context(SqliteFailed)?.context(InvalidDiffRow)?;
+        self.parse_row(row)
+    }
+
     /// Applies function to each matching row
     pub fn for_each<F, E>(&'a self, mut func: F) -> Result<Result<(), E>, Error>
     where
         F: FnMut(DiffRow) -> Result<(), E>,
     {
-        let mut statement = self.select("type, before, after, size, path, ROWID")?;
+        let mut statement = self.select(ROW_COLUMNS)?;
 
         let mut rows = statement.query(params![]).context(SqliteFailed)?;
         while let Some(row) = rows.next().context(SqliteFailed)? {
-            let kind: u8 = row.get(0).context(SqliteFailed)?;
-            let before: Option<u64> = row.get(1).context(SqliteFailed)?;
-            let after: Option<u64> = row.get(2).context(SqliteFailed)?;
-            let size: u64 = row.get(3).context(SqliteFailed)?;
-            let path: Vec<u8> = row.get(4).context(SqliteFailed)?;
-            let rowid = row.get(5).context(SqliteFailed)?;
-
-            let kind = DiffType::parse(kind).context(WrongDiffType { found: kind })?;
-            // FIXME: This is not fast at all.
-            let before = self.load_info(self.diff.before_snap, before)?;
-            let after = self.load_info(self.diff.after_snap, after)?;
-            let path = EncodedPath::from_vec(path);
-            let rowid = RowId(rowid);
-
-            let row = match kind {
-                DiffType::Deleted => DiffRow::Deleted {
-                    rowid,
-                    path,
-                    size,
-                    before: before.context(InvalidDiffRow)?,
-                },
-                DiffType::Created => DiffRow::Created {
-                    rowid,
-                    path,
-                    size,
-                    after: after.context(InvalidDiffRow)?,
-                },
-                DiffType::Changed => DiffRow::Changed {
-                    rowid,
-                    path,
-                    size,
-                    before: before.context(InvalidDiffRow)?,
-                    after: after.context(InvalidDiffRow)?,
-                },
-            };
-
+            let row = self.parse_row(row)?;
             match func(row) {
-                Ok(_) => {}
+                Ok(()) => {}
                 res @ Err(_) => return Ok(res),
             }
         }
@@ -333,6 +563,12 @@ impl<'a> DiffQuery<'a> {
     pub fn larger_or_eq(self, size: u64) -> Self {
         self.with_size(size..=u64::MAX)
     }
+
+    /// Returns rows sorted by path, so files from the same directory are next to each other.
+    pub fn ordered_by_path(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
 }
 
 impl Drop for Diff<'_> {
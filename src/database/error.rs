@@ -31,6 +31,13 @@ pub enum Error {
         found: u8,
     },
     InvalidDiffRow,
+    InvalidArchiveRow,
+    InvalidStorageClass {
+        source: crate::storage::Error,
+    },
+    InvalidTimestamp {
+        source: time::ParseError,
+    },
     #[snafu(display("It looks like you have mixed different databases: this=0x{:x}, before=0x{:x}, after=0x{:x}", this, before, after))]
     DatabasesMixed {
         backtrace: snafu::Backtrace,
@@ -1,7 +1,7 @@
 use std::borrow::Borrow;
 use std::path::{Path, PathBuf};
 
-use rusqlite::{named_params, params};
+use rusqlite::{named_params, params, OptionalExtension};
 use snafu::ResultExt;
 
 use crate::database::generate_id;
@@ -30,6 +30,76 @@ impl Database {
         Ok(fmt_sql!("ATTACH DATABASE '{path}' AS {name}"))
     }
 
+    pub(super) fn is_attached(&self, name: &SqlName) -> Result<bool, Error> {
+        let attached: u32 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_database_list WHERE name = ?",
+                params![name.as_str()],
+                |row| row.get(0),
+            )
+            .context(SqliteFailed)?;
+        Ok(attached != 0)
+    }
+
+    /// Attaches snapshot database, unless it is attached already.
+    ///
+    /// Snapshots created by older versions are upgraded too.
+    pub(super) fn attach_existing(&self, name: &SqlName) -> Result<(), Error> {
+        if !self.is_attached(name)? {
+            self.conn
+                .execute(&self.attach(name)?, params![])
+                .context(SqliteFailed)?;
+        }
+        self.upgrade_snapshot(name)
+    }
+
+    /// Detaches snapshot database. `SQLite` allows only a few databases to be attached at once.
+    pub(super) fn detach(&self, name: &SqlName) -> Result<(), Error> {
+        self.conn
+            .execute(&fmt_sql!("DETACH DATABASE {name}"), params![])
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Runs `func` inside a transaction which is always rolled back, so nothing it writes is kept.
+    ///
+    /// Databases can't be attached inside, so snapshots and diffs used by `func` must be opened before.
+    pub fn rolled_back<T>(&self, func: impl FnOnce() -> T) -> Result<T, Error> {
+        let txn = self.conn.unchecked_transaction().context(SqliteFailed)?;
+        let result = func();
+        txn.rollback().context(SqliteFailed)?;
+        Ok(result)
+    }
+
+    /// Adds columns that are missing in snapshots created by older versions.
+    fn upgrade_snapshot(&self, name: &SqlName) -> Result<(), Error> {
+        let mut statement = self
+            .conn
+            .prepare(&fmt_sql!("PRAGMA {name}.table_info(snap)"))
+            .context(SqliteFailed)?;
+        let columns = statement
+            .query_map(params![], |row| row.get::<_, String>(1))
+            .context(SqliteFailed)?
+            .collect::<Result<Vec<_>, _>>()
+            .context(SqliteFailed)?;
+        // Table does not exist at all.
+        if columns.is_empty() {
+            return Ok(());
+        }
+        if !columns.iter().any(|x| x == "hash") {
+            self.conn
+                .execute_batch(&fmt_sql!(
+                    "
+                    ALTER TABLE {name}.snap ADD COLUMN hash BLOB;
+                    CREATE INDEX IF NOT EXISTS {name}.idx_hash ON snap ( hash );
+                    "
+                ))
+                .context(SqliteFailed)?;
+        }
+        Ok(())
+    }
+
     /// Opens database at given path.
     ///
     /// Note that path is a directory, not `.db` file.
@@ -40,16 +110,21 @@ impl Database {
         let db = rusqlite::Connection::open(&root).context(SqliteFailed)?;
         root.pop();
 
-        db.execute(
-            "CREATE TABLE IF NOT EXISTS snapshots (
+        db.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS snapshots (
                 name TEXT NOT NULL,
                 created_at DATETIME NOT NULL,
                 filled_at DATETIME,
                 is_uploaded BOOLEAN
-            )",
-            params![],
+            );
+            CREATE TABLE IF NOT EXISTS expired_snapshots (
+                name TEXT NOT NULL PRIMARY KEY
+            );
+            ",
         )
         .context(SqliteFailed)?;
+        super::remote::create_tables(&db)?;
         let snapshot_count = db
             .query_row("SELECT COUNT(*) FROM snapshots", params![], |r| r.get(0))
             .context(SqliteFailed)?;
@@ -62,9 +137,7 @@ impl Database {
 
     /// Opens a snapshot for reading only.
     pub fn readonly_snapshot(&self, name: SqlName) -> Result<Snapshot<&Database>, Error> {
-        self.conn
-            .execute(&self.attach(&name)?, params![])
-            .context(SqliteFailed)?;
+        self.attach_existing(&name)?;
         // FIXME: We should check is snapshot exists.
         Ok(Snapshot { db: self, name })
     }
@@ -77,18 +150,17 @@ impl Database {
     /// [`readonly_snapshot`]: Self::readonly_snapshot
     pub fn open_snapshot(&mut self, name: SqlName) -> Result<Snapshot<&mut Database>, Error> {
         // Attach database:
-        self.conn
-            .execute(&self.attach(&name)?, params![])
-            .context(SqliteFailed)?;
+        self.attach_existing(&name)?;
         // Maybe we should create a table then.
-        let is_exists = self
+        let is_exists: u32 = self
             .conn
-            .execute(
+            .query_row(
                 &fmt_sql!(
-                    "SELECT name FROM {name}.sqlite_master
+                    "SELECT COUNT(*) FROM {name}.sqlite_master
                     WHERE type='table' AND name='snap'",
                 ),
                 params![],
+                |row| row.get(0),
             )
             .context(SqliteFailed)?;
         if is_exists == 0 {
@@ -102,8 +174,10 @@ impl Database {
                         path STRING,
                         size INTEGER,
                         identifier BLOB,   /* binary data */
-                        info TEXT          /* json */
+                        info TEXT,         /* json */
+                        hash BLOB          /* content checksum, if it was computed */
                     );
+                    CREATE INDEX {name}.idx_hash ON snap ( hash );
                     INSERT INTO {name}.snap(id) VALUES ({first_id});
                     DELETE FROM {name}.snap WHERE id={first_id};
                 "
@@ -125,6 +199,74 @@ impl Database {
         Ok(Snapshot { db: self, name })
     }
 
+    /// Returns name of the latest snapshot that was [marked as uploaded](Self::mark_uploaded).
+    pub fn last_uploaded_snapshot(&self) -> Result<Option<SqlName>, Error> {
+        let name: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT name FROM snapshots WHERE is_uploaded ORDER BY ROWID DESC LIMIT 1",
+                params![],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(SqliteFailed)?;
+        Ok(name.map(SqlName))
+    }
+
+    /// Marks snapshot as uploaded, so next backups will be computed relative to it.
+    pub fn mark_uploaded(&self, name: &SqlName) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "UPDATE snapshots SET is_uploaded=1 WHERE name=?",
+                params![name.as_str()],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Marks snapshot as expired, so archives that are needed only for it
+    /// can be [deleted](crate::retention).
+    pub fn expire_snapshot(&self, name: &SqlName) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO expired_snapshots(name) VALUES (?)",
+                params![name.as_str()],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Returns uploaded snapshots that are not [expired](Self::expire_snapshot), oldest first.
+    ///
+    /// The last uploaded snapshot is always retained, since next backups are based on it.
+    pub fn retained_snapshots(&self) -> Result<Vec<SqlName>, Error> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT name FROM snapshots WHERE is_uploaded AND (
+                    name NOT IN (SELECT name FROM expired_snapshots)
+                    OR ROWID = (SELECT MAX(ROWID) FROM snapshots WHERE is_uploaded)
+                )
+                ORDER BY ROWID",
+            )
+            .context(SqliteFailed)?;
+        let names = statement
+            .query_map(params![], |row| row.get(0))
+            .context(SqliteFailed)?
+            .collect::<Result<Vec<String>, _>>()
+            .context(SqliteFailed)?;
+        Ok(names.into_iter().map(SqlName).collect())
+    }
+
+    /// Returns name of the snapshot without any files, creating it if needed.
+    ///
+    /// It is useful as a base for the very first backup.
+    pub fn empty_snapshot(&mut self) -> Result<SqlName, Error> {
+        let name = SqlName("empty".to_owned());
+        self.open_snapshot(name.clone())?;
+        Ok(name)
+    }
+
     /// Computes a difference between two given snapshots. See [Diff] documentation for details.
     ///
     /// Returns error if snapshot do not belong to this database (`self == before.db == after.db`).
@@ -134,9 +276,9 @@ impl Database {
         after: &'a Snapshot<D2>,
     ) -> Result<Diff<'a>, Error> {
         {
-            let this = self as *const Self as usize;
-            let before = std::borrow::Borrow::borrow(&before.db) as *const Self as usize;
-            let after = std::borrow::Borrow::borrow(&after.db) as *const Self as usize;
+            let this = std::ptr::from_ref::<Self>(self) as usize;
+            let before = std::ptr::from_ref::<Self>(std::borrow::Borrow::borrow(&before.db)) as usize;
+            let after = std::ptr::from_ref::<Self>(std::borrow::Borrow::borrow(&after.db)) as usize;
             snafu::ensure!(
                 this == before && before == after,
                 DatabasesMixed {
@@ -1,5 +1,5 @@
 //! This module contains code related to storing snapshots and computing differences.
-//! You won't find index of uploaded files here.
+//! Index of uploaded archives is stored here too, see [`remote`](remote).
 
 macro_rules! fmt_sql {
     (static $single:literal) => {{
@@ -17,6 +17,7 @@ macro_rules! fmt_sql {
 mod difference;
 mod error;
 mod index;
+pub mod remote;
 mod snapshot;
 
 use error::*;
@@ -25,7 +26,8 @@ pub use {
     difference::{Diff, DiffRow, DiffType},
     error::Error,
     index::Database,
-    snapshot::Snapshot,
+    remote::{ArchiveId, ArchiveRecord, ArchivedFile},
+    snapshot::{Hashing, Snapshot},
 };
 
 use snafu::{ensure, Snafu};
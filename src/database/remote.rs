@@ -0,0 +1,505 @@
+//! Index of uploaded archives: which archive contains which file.
+//!
+//! Files are matched by their [identifier](crate::fileinfo::FileIdentifier),
+//! so unchanged file is found in the archive where it was uploaded once, even many snapshots ago.
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use rusqlite::{named_params, params, ToSql};
+use snafu::{OptionExt, ResultExt};
+
+use crate::fileinfo::{FileIdentifier, Info};
+use crate::path::{EncodedPath, External, PathKind};
+use crate::storage::StorageClass;
+use crate::types::Checksum;
+use crate::DateTime;
+
+use super::error::*;
+use super::index::Database;
+use super::snapshot::Snapshot;
+use super::SqlName;
+
+/// `id` of the archive in the `archives` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct ArchiveId(pub u64);
+
+/// Archive object that was uploaded to the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveRecord {
+    pub key: String,
+    /// Snapshot that was being backed up when archive was uploaded.
+    pub snapshot: String,
+    pub size: u64,
+    /// Hash of the whole archive.
+    pub checksum: Option<Checksum>,
+    pub storage_class: StorageClass,
+    pub uploaded_at: DateTime,
+}
+
+/// File stored in some archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub archive: ArchiveId,
+    pub path: EncodedPath<External>,
+    pub identifier: Vec<u8>,
+    /// Hash of the file content, when known.
+    pub checksum: Option<Checksum>,
+}
+
+/// Creates tables of the index, if they are not exist yet.
+pub(super) fn create_tables(conn: &rusqlite::Connection) -> Result<(), Error> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS archives (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            key TEXT NOT NULL UNIQUE,
+            snapshot TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            checksum BLOB,
+            storage_class TEXT NOT NULL,
+            uploaded_at DATETIME NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS archive_files (
+            archive INTEGER NOT NULL REFERENCES archives(id),
+            path BLOB NOT NULL,
+            identifier BLOB NOT NULL,  /* see `FileIdentifier` */
+            checksum BLOB              /* content hash, may be unknown */
+        );
+        CREATE INDEX IF NOT EXISTS archive_files_identifier ON archive_files ( identifier );
+        CREATE INDEX IF NOT EXISTS archive_files_archive ON archive_files ( archive );
+        CREATE INDEX IF NOT EXISTS archive_files_checksum ON archive_files ( checksum );
+        /* Directories, symlinks, pipes and devices, which are matched by their path and info. */
+        CREATE TABLE IF NOT EXISTS archive_entries (
+            archive INTEGER NOT NULL REFERENCES archives(id),
+            path BLOB NOT NULL,
+            info TEXT NOT NULL  /* json, like in snapshots */
+        );
+        CREATE INDEX IF NOT EXISTS archive_entries_path ON archive_entries ( path );
+        CREATE INDEX IF NOT EXISTS archive_entries_archive ON archive_entries ( archive );
+        CREATE TABLE IF NOT EXISTS oversized_files (
+            snapshot TEXT NOT NULL,
+            path BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            max_size INTEGER NOT NULL  /* limit that was exceeded */
+        );
+        CREATE TABLE IF NOT EXISTS deferred_files (
+            identifier BLOB NOT NULL PRIMARY KEY,
+            snapshot TEXT NOT NULL,  /* snapshot that was being backed up */
+            path BLOB NOT NULL,
+            size INTEGER NOT NULL
+        );
+        /* Directories, symlinks, pipes and devices have no identifier. */
+        CREATE TABLE IF NOT EXISTS deferred_entries (
+            path BLOB NOT NULL PRIMARY KEY,
+            snapshot TEXT NOT NULL
+        );
+        ",
+    )
+    .context(SqliteFailed)
+}
+
+fn checksum_from_blob(blob: Option<Vec<u8>>) -> Result<Option<Checksum>, Error> {
+    match blob {
+        Some(blob) => {
+            let array = blob.as_slice().try_into().ok().context(InvalidArchiveRow)?;
+            Ok(Some(Checksum(array)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Columns that are needed to build [`ArchiveRecord`](ArchiveRecord), prefixed by `id`.
+const ARCHIVE_COLUMNS: &str = "id, key, snapshot, size, checksum, storage_class, uploaded_at";
+
+/// Columns that are needed to build [`ArchivedFile`](ArchivedFile).
+const FILE_COLUMNS: &str = "archive, path, identifier, checksum";
+
+fn parse_file(row: &rusqlite::Row) -> Result<ArchivedFile, Error> {
+    Ok(ArchivedFile {
+        archive: ArchiveId(row.get(0).context(SqliteFailed)?),
+        path: EncodedPath::from_vec(row.get(1).context(SqliteFailed)?),
+        identifier: row.get(2).context(SqliteFailed)?,
+        checksum: checksum_from_blob(row.get(3).context(SqliteFailed)?)?,
+    })
+}
+
+fn parse_archive(row: &rusqlite::Row) -> Result<(ArchiveId, ArchiveRecord), Error> {
+    let id: u64 = row.get(0).context(SqliteFailed)?;
+    let storage_class: String = row.get(5).context(SqliteFailed)?;
+    let uploaded_at: String = row.get(6).context(SqliteFailed)?;
+    let record = ArchiveRecord {
+        key: row.get(1).context(SqliteFailed)?,
+        snapshot: row.get(2).context(SqliteFailed)?,
+        size: row.get(3).context(SqliteFailed)?,
+        checksum: checksum_from_blob(row.get(4).context(SqliteFailed)?)?,
+        storage_class: storage_class.parse().context(InvalidStorageClass)?,
+        uploaded_at: DateTime::parse(uploaded_at, time::Format::Rfc3339)
+            .context(InvalidTimestamp)?,
+    };
+    Ok((ArchiveId(id), record))
+}
+
+impl Database {
+    /// Records uploaded archive with all files in it.
+    ///
+    /// Only regular files can be [found](Self::find_by_identifier) by their identifier.
+    /// Other entries are recorded only to keep their archives from being
+    /// [obsolete](Self::obsolete_archives), since they are restored from the snapshot.
+    /// Archived files and other entries are not [deferred](Self::add_deferred) anymore.
+    pub fn add_archive<'i, P: PathKind + 'i>(
+        &self,
+        archive: &ArchiveRecord,
+        files: impl IntoIterator<Item = (&'i Info<P>, Option<Checksum>)>,
+    ) -> Result<ArchiveId, Error> {
+        let txn = self.conn.unchecked_transaction().context(SqliteFailed)?;
+        txn.execute(
+            "INSERT INTO archives(key, snapshot, size, checksum, storage_class, uploaded_at)
+            VALUES (:key, :snapshot, :size, :checksum, :storage_class, :uploaded_at)",
+            named_params![
+                ":key": archive.key,
+                ":snapshot": archive.snapshot,
+                ":size": archive.size,
+                ":checksum": archive.checksum.as_ref().map(|x| &x.0[..]),
+                ":storage_class": archive.storage_class.as_str(),
+                ":uploaded_at": archive.uploaded_at.format(time::Format::Rfc3339),
+            ],
+        )
+        .context(SqliteFailed)?;
+        #[allow(clippy::cast_sign_loss)] // ROWID of AUTOINCREMENT table is always positive.
+        let id = txn.last_insert_rowid() as u64;
+        {
+            let mut statement = txn
+                .prepare(
+                    "INSERT INTO archive_files(archive, path, identifier, checksum)
+                    VALUES (:archive, :path, :identifier, :checksum)",
+                )
+                .context(SqliteFailed)?;
+            let mut undefer = txn
+                .prepare("DELETE FROM deferred_files WHERE identifier = ?")
+                .context(SqliteFailed)?;
+            let mut entry = txn
+                .prepare("INSERT INTO archive_entries(archive, path, info) VALUES (?, ?, ?)")
+                .context(SqliteFailed)?;
+            let mut undefer_entry = txn
+                .prepare("DELETE FROM deferred_entries WHERE path = ?")
+                .context(SqliteFailed)?;
+            for (info, checksum) in files {
+                let identifier = if let Some(identifier) = info.identifier() {
+                    identifier
+                } else {
+                    let json = serde_json::to_string(info).context(JsonFailed)?;
+                    entry
+                        .execute(params![id, info.path.as_bytes(), json])
+                        .context(SqliteFailed)?;
+                    undefer_entry
+                        .execute(params![info.path.as_bytes()])
+                        .context(SqliteFailed)?;
+                    continue;
+                };
+                statement
+                    .execute(named_params![
+                        ":archive": id,
+                        ":path": info.path.as_bytes(),
+                        ":identifier": identifier.as_bytes(),
+                        ":checksum": checksum.as_ref().map(|x| &x.0[..]),
+                    ])
+                    .context(SqliteFailed)?;
+                undefer
+                    .execute(params![identifier.as_bytes()])
+                    .context(SqliteFailed)?;
+            }
+        }
+        txn.commit().context(SqliteFailed)?;
+        Ok(ArchiveId(id))
+    }
+
+    /// Returns all known archives, oldest first.
+    pub fn archives(&self) -> Result<Vec<(ArchiveId, ArchiveRecord)>, Error> {
+        let mut statement = self
+            .conn
+            .prepare(&fmt_sql!(
+                "SELECT {ARCHIVE_COLUMNS} FROM archives ORDER BY id"
+            ))
+            .context(SqliteFailed)?;
+        let mut rows = statement.query(params![]).context(SqliteFailed)?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().context(SqliteFailed)? {
+            result.push(parse_archive(row)?);
+        }
+        Ok(result)
+    }
+
+    /// Returns archive by it's id.
+    pub fn archive(&self, id: ArchiveId) -> Result<ArchiveRecord, Error> {
+        let mut statement = self
+            .conn
+            .prepare_cached(&fmt_sql!(
+                "SELECT {ARCHIVE_COLUMNS} FROM archives WHERE id = ?"
+            ))
+            .context(SqliteFailed)?;
+        let mut rows = statement.query(params![id.0]).context(SqliteFailed)?;
+        let row = rows
+            .next()
+            .context(SqliteFailed)?
+            .context(InvalidArchiveRow)?;
+        Ok(parse_archive(row)?.1)
+    }
+
+    /// Returns all files stored in the archive.
+    pub fn archive_files(&self, id: ArchiveId) -> Result<Vec<ArchivedFile>, Error> {
+        let mut statement = self
+            .conn
+            .prepare_cached(&fmt_sql!(
+                "SELECT {FILE_COLUMNS} FROM archive_files WHERE archive = ? ORDER BY ROWID"
+            ))
+            .context(SqliteFailed)?;
+        let mut rows = statement.query(params![id.0]).context(SqliteFailed)?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().context(SqliteFailed)? {
+            result.push(parse_file(row)?);
+        }
+        Ok(result)
+    }
+
+    /// Runs query and parses the first row, if any.
+    fn first_file(&self, sql: &str, params: &[&dyn ToSql]) -> Result<Option<ArchivedFile>, Error> {
+        let mut statement = self.conn.prepare_cached(sql).context(SqliteFailed)?;
+        let mut rows = statement.query(params).context(SqliteFailed)?;
+        rows.next()
+            .context(SqliteFailed)?
+            .map(parse_file)
+            .transpose()
+    }
+
+    /// Finds the latest archive that contains file with given identifier.
+    pub fn find_by_identifier(
+        &self,
+        identifier: &FileIdentifier,
+    ) -> Result<Option<ArchivedFile>, Error> {
+        self.first_file(
+            &fmt_sql!(
+                "SELECT {FILE_COLUMNS} FROM archive_files
+                WHERE identifier = ? ORDER BY archive DESC LIMIT 1"
+            ),
+            params![identifier.as_bytes()],
+        )
+    }
+
+    /// Records that the file was not uploaded, since it's larger than `max_size`.
+    pub fn add_oversized(
+        &self,
+        snapshot: &SqlName,
+        path: &EncodedPath<External>,
+        size: u64,
+        max_size: u64,
+    ) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "INSERT INTO oversized_files(snapshot, path, size, max_size)
+                VALUES (:snapshot, :path, :size, :max_size)",
+                named_params![
+                    ":snapshot": snapshot.as_str(),
+                    ":path": path.as_bytes(),
+                    ":size": size,
+                    ":max_size": max_size,
+                ],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Returns paths and sizes of files which were too large to be uploaded with the snapshot.
+    pub fn oversized_files(
+        &self,
+        snapshot: &SqlName,
+    ) -> Result<Vec<(EncodedPath<External>, u64)>, Error> {
+        let mut statement = self
+            .conn
+            .prepare_cached(
+                "SELECT path, size FROM oversized_files WHERE snapshot = ? ORDER BY ROWID",
+            )
+            .context(SqliteFailed)?;
+        let mut rows = statement
+            .query(params![snapshot.as_str()])
+            .context(SqliteFailed)?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().context(SqliteFailed)? {
+            let path = EncodedPath::from_vec(row.get(0).context(SqliteFailed)?);
+            result.push((path, row.get(1).context(SqliteFailed)?));
+        }
+        Ok(result)
+    }
+
+    /// Returns archives that hold no files or other entries from any
+    /// [retained](Database::retained_snapshots) snapshot, oldest first.
+    ///
+    /// Such archives are needed only for restoring [expired](Database::expire_snapshot) snapshots.
+    pub fn obsolete_archives(&self) -> Result<Vec<(ArchiveId, ArchiveRecord)>, Error> {
+        let mut needed = HashSet::new();
+        for snap in self.retained_snapshots()? {
+            let attached = self.is_attached(&snap)?;
+            self.attach_existing(&snap)?;
+            {
+                let mut statement = self
+                    .conn
+                    .prepare(&fmt_sql!(
+                        "SELECT archive FROM archive_files
+                        WHERE identifier IN (SELECT identifier FROM {snap}.snap)
+                        UNION
+                        SELECT archive FROM archive_entries AS e
+                        INNER JOIN {snap}.snap AS s
+                            ON s.path = e.path AND length(s.identifier) = 0
+                        -- The same fields are ignored as when comparing snapshots.
+                        WHERE json_remove(s.info, '$.accessed_at', '$.device', '$.hash')
+                            = json_remove(e.info, '$.accessed_at', '$.device', '$.hash')"
+                    ))
+                    .context(SqliteFailed)?;
+                let mut rows = statement.query(params![]).context(SqliteFailed)?;
+                while let Some(row) = rows.next().context(SqliteFailed)? {
+                    needed.insert(ArchiveId(row.get(0).context(SqliteFailed)?));
+                }
+            }
+            // Statement is finalized already, so the snapshot can be detached.
+            // There may be many snapshots, but only a few can be attached at once.
+            if !attached {
+                self.detach(&snap)?;
+            }
+        }
+        let mut result = self.archives()?;
+        result.retain(|(id, _)| !needed.contains(id));
+        Ok(result)
+    }
+
+    /// Forgets about the archive and all files in it.
+    pub fn remove_archive(&self, id: ArchiveId) -> Result<(), Error> {
+        let txn = self.conn.unchecked_transaction().context(SqliteFailed)?;
+        txn.execute("DELETE FROM archive_files WHERE archive = ?", params![id.0])
+            .context(SqliteFailed)?;
+        txn.execute("DELETE FROM archive_entries WHERE archive = ?", params![id.0])
+            .context(SqliteFailed)?;
+        txn.execute("DELETE FROM archives WHERE id = ?", params![id.0])
+            .context(SqliteFailed)?;
+        txn.commit().context(SqliteFailed)
+    }
+
+    /// Finds the latest archive that contains file with given content.
+    pub fn find_by_checksum(&self, checksum: &Checksum) -> Result<Option<ArchivedFile>, Error> {
+        self.first_file(
+            &fmt_sql!(
+                "SELECT {FILE_COLUMNS} FROM archive_files
+                WHERE checksum = ? ORDER BY archive DESC LIMIT 1"
+            ),
+            params![&checksum.0[..]],
+        )
+    }
+
+    /// Records that the file is not uploaded, since the same content is stored in the `existing` one.
+    ///
+    /// After that file can be found [by it's identifier](Self::find_by_identifier) as usual,
+    /// but it will point to the `existing` file, which may have different path.
+    /// File is not [deferred](Self::add_deferred) anymore.
+    /// Nothing is recorded for entries that are not regular files.
+    pub fn add_reference<P: PathKind>(
+        &self,
+        existing: &ArchivedFile,
+        info: &Info<P>,
+    ) -> Result<(), Error> {
+        let identifier = match info.identifier() {
+            Some(identifier) => identifier,
+            None => return Ok(()),
+        };
+        self.conn
+            .execute(
+                "INSERT INTO archive_files(archive, path, identifier, checksum)
+                VALUES (:archive, :path, :identifier, :checksum)",
+                named_params![
+                    ":archive": existing.archive.0,
+                    ":path": existing.path.as_bytes(),
+                    ":identifier": identifier.as_bytes(),
+                    ":checksum": existing.checksum.as_ref().map(|x| &x.0[..]),
+                ],
+            )
+            .context(SqliteFailed)?;
+        self.conn
+            .execute(
+                "DELETE FROM deferred_files WHERE identifier = ?",
+                params![identifier.as_bytes()],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Records that the file was not uploaded with the snapshot, since request budget was exhausted.
+    ///
+    /// Such files are [uploaded](crate::database::Diff::include_deferred) by the next backups.
+    /// Entries that are not regular files are recorded by their path,
+    /// and they are not listed in [`deferred_files`](Database::deferred_files).
+    pub fn add_deferred<P: PathKind>(
+        &self,
+        snapshot: &SqlName,
+        info: &Info<P>,
+    ) -> Result<(), Error> {
+        let identifier = if let Some(identifier) = info.identifier() {
+            identifier
+        } else {
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO deferred_entries(path, snapshot) VALUES (?, ?)",
+                    params![info.path.as_bytes(), snapshot.as_str()],
+                )
+                .context(SqliteFailed)?;
+            return Ok(());
+        };
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO deferred_files(identifier, snapshot, path, size)
+                VALUES (:identifier, :snapshot, :path, :size)",
+                named_params![
+                    ":identifier": identifier.as_bytes(),
+                    ":snapshot": snapshot.as_str(),
+                    ":path": info.path.as_bytes(),
+                    ":size": info.size().unwrap_or(0),
+                ],
+            )
+            .context(SqliteFailed)?;
+        Ok(())
+    }
+
+    /// Returns paths and sizes of files that are waiting to be uploaded.
+    pub fn deferred_files(&self) -> Result<Vec<(EncodedPath<External>, u64)>, Error> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT path, size FROM deferred_files ORDER BY ROWID")
+            .context(SqliteFailed)?;
+        let mut rows = statement.query(params![]).context(SqliteFailed)?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().context(SqliteFailed)? {
+            let path = EncodedPath::from_vec(row.get(0).context(SqliteFailed)?);
+            result.push((path, row.get(1).context(SqliteFailed)?));
+        }
+        Ok(result)
+    }
+
+    /// Finds an archive that holds file located at `path` in the given snapshot.
+    ///
+    /// Note that the file may be stored in the archive under different name,
+    /// if it was renamed after uploading.
+    pub fn find_archive<D: Borrow<Database>>(
+        &self,
+        snapshot: &Snapshot<D>,
+        path: &EncodedPath<External>,
+    ) -> Result<Option<ArchivedFile>, Error> {
+        let snap = snapshot.name();
+        self.first_file(
+            &fmt_sql!(
+                "SELECT {FILE_COLUMNS} FROM archive_files
+                WHERE identifier IN (SELECT identifier FROM {snap}.snap WHERE path = ?)
+                ORDER BY archive DESC LIMIT 1"
+            ),
+            params![path.as_bytes()],
+        )
+    }
+}
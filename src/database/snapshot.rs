@@ -1,14 +1,18 @@
 use std::borrow::Borrow;
 use std::borrow::BorrowMut;
+use std::convert::TryInto;
 use std::path::Path;
 
 use rusqlite::named_params;
 use rusqlite::params;
+use rusqlite::OptionalExtension;
 use snafu::ResultExt;
 
 use crate::fileinfo::FileIdentifier;
 use crate::fileinfo::Info;
-use crate::path::EncodedPath;
+use crate::hash_pool::HashPool;
+use crate::path::{EncodedPath, EscapedString, External, Local, PathKind};
+use crate::types::Checksum;
 
 use super::error::*;
 use super::index::Database;
@@ -22,6 +26,16 @@ pub struct Snapshot<D: Borrow<Database>> {
     pub(super) name: SqlName,
 }
 
+/// How content of files is hashed while [filling](SnapshotFiller::fill) the snapshot.
+#[derive(Debug, Clone)]
+pub struct Hashing {
+    /// Number of threads reading files at once.
+    pub workers: usize,
+    /// Snapshot which checksums are reused for files with the same [identifier](FileIdentifier),
+    /// so unchanged files are not read again.
+    pub reuse_from: Option<SqlName>,
+}
+
 /// Simple struct that allows filling snapshot with files.
 /// 
 /// Note that if [`save()`](Self::save) is not called, transaction will be rolled back.
@@ -29,42 +43,72 @@ pub struct Snapshot<D: Borrow<Database>> {
 pub struct SnapshotFiller<'a> {
     snap_name: &'a SqlName,
     transaction: rusqlite::Transaction<'a>,
+    hashing: Option<Hashing>,
 }
 
 impl<'a> SnapshotFiller<'a> {
-    fn new<D: BorrowMut<Database>>(snapshot: &'a mut Snapshot<D>) -> Result<Self, Error> {
-        let mut txn = snapshot
-            .db
-            .borrow_mut()
-            .conn
-            .transaction()
-            .context(SqliteFailed)?;
+    fn new<D: BorrowMut<Database>>(
+        snapshot: &'a mut Snapshot<D>,
+        hashing: Option<Hashing>,
+    ) -> Result<Self, Error> {
+        let db = snapshot.db.borrow_mut();
+        // Databases can't be attached inside a transaction.
+        if let Some(previous) = hashing.as_ref().and_then(|x| x.reuse_from.as_ref()) {
+            db.attach_existing(previous)?;
+        }
+        let mut txn = db.conn.transaction().context(SqliteFailed)?;
         txn.set_drop_behavior(rusqlite::DropBehavior::Rollback);
         Ok(SnapshotFiller {
             snap_name: &snapshot.name,
             transaction: txn,
+            hashing,
         })
     }
 
-    fn get_statement(&self) -> Result<rusqlite::CachedStatement, Error> {
+    fn get_statement(&self) -> Result<rusqlite::CachedStatement<'_>, Error> {
         let sql = fmt_sql!(
-            "INSERT INTO {0}.snap(path, identifier, info, size)
-            VALUES(:path, :identifier, :info, :size)",
+            "INSERT INTO {0}.snap(path, identifier, info, size, hash)
+            VALUES(:path, :identifier, :info, :size, :hash)",
             &self.snap_name
         );
         self.transaction.prepare_cached(&sql).context(SqliteFailed)
     }
 
+    /// Finds checksum of the file with the same identifier in the snapshot we are reusing hashes from.
+    fn previous_hash(&self, identifier: &FileIdentifier) -> Result<Option<Checksum>, Error> {
+        let previous = match self.hashing.as_ref().and_then(|x| x.reuse_from.as_ref()) {
+            Some(previous) => previous,
+            None => return Ok(None),
+        };
+        let mut statement = self
+            .transaction
+            .prepare_cached(&fmt_sql!(
+                "SELECT hash FROM {previous}.snap
+                WHERE identifier = ? AND hash IS NOT NULL LIMIT 1"
+            ))
+            .context(SqliteFailed)?;
+        let blob: Option<Vec<u8>> = statement
+            .query_row(params![identifier.as_bytes()], |row| row.get(0))
+            .optional()
+            .context(SqliteFailed)?;
+        Ok(blob.and_then(|blob| Some(Checksum(blob.as_slice().try_into().ok()?))))
+    }
+
     /// Adds new entry to snapshot directly from [`walkdir::DirEntry`](walkdir::DirEntry).
     pub fn add(&self, entry: walkdir::DirEntry) -> Result<(), Error> {
         let metadata = entry.metadata().context(CantWalkdir)?;
         let path = EncodedPath::from_path(entry.into_path());
-        let info = Info::with_metadata(path, &metadata);
+        self.add_info(&Info::with_metadata(path, &metadata))
+    }
+
+    /// Adds new entry to snapshot, without looking at the filesystem.
+    pub fn add_info(&self, info: &Info<Local>) -> Result<(), Error> {
         self.get_statement()?.execute(named_params![
             ":path": info.path.as_bytes(),
             ":identifier": info.identifier().as_ref().map(FileIdentifier::as_bytes).unwrap_or_default(),
             ":info": serde_json::to_string(&info).context(JsonFailed)?,
-            ":size": info.size()
+            ":size": info.size(),
+            ":hash": info.hash.as_ref().map(|x| &x.0[..]),
         ])
         .context(SqliteFailed)?;
         Ok(())
@@ -86,31 +130,153 @@ impl<'a> SnapshotFiller<'a> {
     }
 
     /// Walk given directory, putting each file into snapshot.
+    ///
+    /// If filler is [hashing](Snapshot::hashing_filler), content of regular files is hashed too.
     pub fn fill(self, root: &Path) -> Result<Self, Error> {
         log!(time: "Walking over {}", root = root.to_string_lossy());
         let walk = walkdir::WalkDir::new(root).into_iter();
-        for entry in walk {
-            let entry = entry.context(CantWalkdir)?;
-            self.add(entry)?;
+        match &self.hashing {
+            Some(hashing) => {
+                let pool = HashPool::new(hashing.workers);
+                for entry in walk {
+                    let entry = entry.context(CantWalkdir)?;
+                    self.add_hashed(entry, &pool)?;
+                    for (info, result) in pool.ready() {
+                        self.add_with_checksum(info, result)?;
+                    }
+                }
+                for (info, result) in pool.finish() {
+                    self.add_with_checksum(info, result)?;
+                }
+            }
+            None => {
+                for entry in walk {
+                    let entry = entry.context(CantWalkdir)?;
+                    self.add(entry)?;
+                }
+            }
         }
         log!(time: "Done walking ({})", root = root.to_string_lossy());
         Ok(self)
     }
+
+    /// Adds entry right away if it's checksum is known or not needed, otherwise queues it for hashing.
+    fn add_hashed(&self, entry: walkdir::DirEntry, pool: &HashPool<Info<Local>>) -> Result<(), Error> {
+        let metadata = entry.metadata().context(CantWalkdir)?;
+        let local = entry.into_path();
+        let mut info = Info::with_metadata(EncodedPath::from_path(local.clone()), &metadata);
+        let identifier = match info.identifier() {
+            Some(identifier) => identifier,
+            None => return self.add_info(&info),
+        };
+        info.hash = self.previous_hash(&identifier)?;
+        if info.hash.is_some() {
+            return self.add_info(&info);
+        }
+        pool.push(info, local);
+        Ok(())
+    }
+
+    /// Adds file hashed by the pool. File that can't be read is added without checksum.
+    fn add_with_checksum(
+        &self,
+        mut info: Info<Local>,
+        checksum: std::io::Result<Checksum>,
+    ) -> Result<(), Error> {
+        match checksum {
+            Ok(checksum) => info.hash = Some(checksum),
+            Err(e) => {
+                log!(warn: "Can't hash {}: {}", path = info.path.escaped(), error = e.to_string());
+            }
+        }
+        self.add_info(&info)
+    }
 }
 
-impl<'a, D: BorrowMut<Database>> Snapshot<D> {
-    pub fn filler(&mut self) -> Result<SnapshotFiller, Error> {
-        SnapshotFiller::new(self)
+impl<D: BorrowMut<Database>> Snapshot<D> {
+    pub fn filler(&mut self) -> Result<SnapshotFiller<'_>, Error> {
+        SnapshotFiller::new(self, None)
+    }
+
+    /// Same as [`filler`](Self::filler), but content of files is hashed too.
+    ///
+    /// Checksums are stored both in [`Info::hash`](Info::hash) and in the separate indexed column.
+    pub fn hashing_filler(&mut self, hashing: Hashing) -> Result<SnapshotFiller<'_>, Error> {
+        SnapshotFiller::new(self, Some(hashing))
     }
 }
 
-impl<'a, D: Borrow<Database>> Snapshot<D> {
+impl<D: Borrow<Database>> Snapshot<D> {
     pub fn name(&self) -> &SqlName {
         &self.name
     }
+
+    /// Stores checksums that were computed after the snapshot was filled, for example while archiving.
+    ///
+    /// Checksum is written both to the [`Info::hash`](Info::hash) and to the separate column,
+    /// so next snapshots can [reuse](Hashing::reuse_from) it. Returns number of updated rows.
+    pub fn record_hashes<'i, P: PathKind + 'i>(
+        &self,
+        files: impl IntoIterator<Item = (&'i Info<P>, Checksum)>,
+    ) -> Result<usize, Error> {
+        let db: &Database = self.db.borrow();
+        let txn = db.conn.unchecked_transaction().context(SqliteFailed)?;
+        let mut updated = 0;
+        {
+            let mut statement = txn
+                .prepare(&fmt_sql!(
+                    "UPDATE {0}.snap SET hash = :hash, info = :info
+                    WHERE path = :path AND identifier = :identifier",
+                    self.name
+                ))
+                .context(SqliteFailed)?;
+            for (info, checksum) in files {
+                let identifier = match info.identifier() {
+                    Some(identifier) => identifier,
+                    None => continue,
+                };
+                let mut info = info.clone();
+                info.hash = Some(checksum);
+                updated += statement
+                    .execute(named_params![
+                        ":hash": &checksum.0[..],
+                        ":info": serde_json::to_string(&info).context(JsonFailed)?,
+                        ":path": info.path.as_bytes(),
+                        ":identifier": identifier.as_bytes(),
+                    ])
+                    .context(SqliteFailed)?;
+            }
+        }
+        txn.commit().context(SqliteFailed)?;
+        Ok(updated)
+    }
+
+    /// Applies function to each entry of the snapshot, in order they were added.
+    pub fn for_each<F, E>(&self, mut func: F) -> Result<Result<(), E>, Error>
+    where
+        F: FnMut(Info<External>) -> Result<(), E>,
+    {
+        let db: &Database = self.db.borrow();
+        let mut statement = db
+            .conn
+            .prepare(&fmt_sql!("SELECT info FROM {0}.snap ORDER BY id", self.name))
+            .context(SqliteFailed)?;
+
+        let mut rows = statement.query(params![]).context(SqliteFailed)?;
+        while let Some(row) = rows.next().context(SqliteFailed)? {
+            let json: String = row.get(0).context(SqliteFailed)?;
+            let info = serde_json::from_str(&json).context(JsonFailed)?;
+            match func(info) {
+                Ok(()) => {}
+                res @ Err(_) => return Ok(res),
+            }
+        }
+
+        Ok(Ok(()))
+    }
 }
 
-impl<'a, D: Borrow<Database>> Drop for Snapshot<D> {
+impl<D: Borrow<Database>> Drop for Snapshot<D> {
     fn drop(&mut self) {
         let db: &Database = self.db.borrow();
         let _unused_result = db
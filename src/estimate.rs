@@ -0,0 +1,301 @@
+//! Estimating how much the backup costs: requests, storage and retrieval.
+//!
+//! Nothing is uploaded here, archives are only [measured](crate::cpio::Archive::size).
+
+use crate::backup::{self, deduplicate, record_moved, BackupOptions, Deduplicated};
+use crate::database::{self, Database, Diff};
+use crate::packer::{self, PackLimits};
+use crate::storage::StorageClass;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    DatabaseFailed { source: database::Error },
+    BackupFailed { source: backup::Error },
+}
+
+/// AWS bills storage in binary gigabytes.
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Storage duration that is billed as a single month.
+const DAYS_IN_MONTH: f64 = 30.0;
+
+/// Prices of the single storage class, in USD.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClassPrices {
+    /// Per GB per month.
+    pub storage: f64,
+    /// Per 1000 PUT, POST or COPY requests.
+    pub upload_requests: f64,
+    /// Per 1000 GET requests, including restore requests for archive classes.
+    pub retrieval_requests: f64,
+    /// Per GB retrieved.
+    pub retrieval: f64,
+    /// Per 1000 objects per month, like the monitoring fee of Intelligent-Tiering.
+    pub objects: f64,
+    /// Objects deleted earlier are billed as if they were stored for this number of days.
+    pub min_duration_days: u32,
+    /// Smaller objects are billed as if they were this size.
+    pub min_object_size: u64,
+    /// Metadata billed at the price of this class for every object.
+    /// Glacier stores 32KB of index data here.
+    pub class_overhead: u64,
+    /// Metadata billed at the price of [`Standard`](StorageClass::Standard) class for every object.
+    /// Glacier stores 8KB of object name and metadata here.
+    pub standard_overhead: u64,
+}
+
+/// Price table of all storage classes.
+///
+/// Default one is taken from the S3 pricing page for `us-east-1`.
+/// Any class can be replaced when loading table from JSON:
+/// ```
+/// # use colbak_lib::estimate::Prices;
+/// let prices: Prices = serde_json::from_str(r#"{
+///     "glacier": {
+///         "storage": 0.0045, "upload_requests": 0.06, "retrieval_requests": 0.06, "retrieval": 0.012,
+///         "objects": 0, "min_duration_days": 90, "min_object_size": 0,
+///         "class_overhead": 32768, "standard_overhead": 8192
+///     }
+/// }"#).unwrap();
+/// assert_eq!(prices.glacier.storage, 0.0045);
+/// assert_eq!(prices.standard, Prices::default().standard);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prices {
+    pub standard: ClassPrices,
+    pub reduced_redundancy: ClassPrices,
+    pub standard_ia: ClassPrices,
+    pub onezone_ia: ClassPrices,
+    pub intelligent_tiering: ClassPrices,
+    pub glacier: ClassPrices,
+    pub deep_archive: ClassPrices,
+}
+
+impl Prices {
+    #[must_use]
+    pub fn get(&self, class: StorageClass) -> &ClassPrices {
+        match class {
+            StorageClass::Standard => &self.standard,
+            StorageClass::ReducedRedundancy => &self.reduced_redundancy,
+            StorageClass::StandardIa => &self.standard_ia,
+            StorageClass::OnezoneIa => &self.onezone_ia,
+            StorageClass::IntelligentTiering => &self.intelligent_tiering,
+            StorageClass::Glacier => &self.glacier,
+            StorageClass::DeepArchive => &self.deep_archive,
+        }
+    }
+
+    /// Monthly cost of storing single object of given size, including all overheads.
+    #[must_use]
+    pub fn monthly_cost(&self, class: StorageClass, size: u64) -> f64 {
+        let prices = self.get(class);
+        let billed = size.max(prices.min_object_size) + prices.class_overhead;
+        // Precision loss does not matter for the estimate.
+        #[allow(clippy::cast_precision_loss)]
+        let cost = billed as f64 / GB * prices.storage
+            + prices.standard_overhead as f64 / GB * self.standard.storage
+            + prices.objects / 1000.0;
+        cost
+    }
+
+    /// Cost that is charged when object is deleted after being stored for `stored_days`,
+    /// because of the minimum storage duration.
+    ///
+    /// ```
+    /// # use colbak_lib::estimate::Prices;
+    /// # use colbak_lib::storage::StorageClass;
+    /// let prices = Prices::default();
+    /// let monthly = prices.monthly_cost(StorageClass::Glacier, 1 << 30);
+    /// let penalty = prices.early_deletion_cost(StorageClass::Glacier, 1 << 30, 60);
+    /// assert!((penalty - monthly).abs() < 1e-9);
+    /// assert_eq!(prices.early_deletion_cost(StorageClass::Glacier, 1 << 30, 90), 0.0);
+    /// assert_eq!(prices.early_deletion_cost(StorageClass::Standard, 1 << 30, 0), 0.0);
+    /// ```
+    #[must_use]
+    pub fn early_deletion_cost(&self, class: StorageClass, size: u64, stored_days: u32) -> f64 {
+        let remaining = self
+            .get(class)
+            .min_duration_days
+            .saturating_sub(stored_days);
+        self.monthly_cost(class, size) * f64::from(remaining) / DAYS_IN_MONTH
+    }
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        let standard = ClassPrices {
+            storage: 0.023,
+            upload_requests: 0.005,
+            retrieval_requests: 0.0004,
+            retrieval: 0.0,
+            objects: 0.0,
+            min_duration_days: 0,
+            min_object_size: 0,
+            class_overhead: 0,
+            standard_overhead: 0,
+        };
+        let infrequent = ClassPrices {
+            storage: 0.0125,
+            upload_requests: 0.01,
+            retrieval_requests: 0.001,
+            retrieval: 0.01,
+            min_duration_days: 30,
+            min_object_size: 128 * 1024,
+            ..standard.clone()
+        };
+        let glacier = ClassPrices {
+            storage: 0.004,
+            upload_requests: 0.05,
+            retrieval_requests: 0.05,
+            retrieval: 0.01,
+            objects: 0.0,
+            min_duration_days: 90,
+            min_object_size: 0,
+            class_overhead: 32 * 1024,
+            standard_overhead: 8 * 1024,
+        };
+        Prices {
+            reduced_redundancy: ClassPrices {
+                storage: 0.024,
+                ..standard.clone()
+            },
+            standard_ia: infrequent.clone(),
+            onezone_ia: ClassPrices {
+                storage: 0.01,
+                ..infrequent
+            },
+            intelligent_tiering: ClassPrices {
+                objects: 0.0025,
+                min_duration_days: 30,
+                ..standard.clone()
+            },
+            deep_archive: ClassPrices {
+                storage: 0.00099,
+                retrieval_requests: 0.1,
+                retrieval: 0.02,
+                min_duration_days: 180,
+                ..glacier.clone()
+            },
+            glacier,
+            standard,
+        }
+    }
+}
+
+/// Expected cost of uploading packed archives, in USD.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Estimate {
+    pub archives: u64,
+    pub files: u64,
+    /// Files that are not uploaded, since their content is already stored.
+    pub deduplicated: u64,
+    /// Files that are moved, so only their new paths are recorded.
+    pub moved: u64,
+    /// Total size of all archives.
+    pub bytes: u64,
+    /// Requests required to upload all archives.
+    pub requests: u64,
+    /// Bytes billed at the price of the chosen class, including overhead and minimal object size.
+    pub billed_bytes: u64,
+    /// Bytes billed at the price of the [`Standard`](StorageClass::Standard) class.
+    pub standard_billed_bytes: u64,
+    pub upload_cost: f64,
+    pub monthly_storage_cost: f64,
+    /// Storage cost that is paid even if archives are deleted right after upload.
+    pub min_duration_cost: f64,
+    /// Cost of downloading every archive once.
+    pub retrieval_cost: f64,
+}
+
+/// Number of requests that is needed to upload an object of given size,
+/// see [`Storage::part_size`](crate::storage::Storage::part_size).
+///
+/// ```
+/// # use colbak_lib::estimate::upload_requests;
+/// assert_eq!(upload_requests(100, Some(1000)), 1);
+/// // Create, three parts and complete.
+/// assert_eq!(upload_requests(2500, Some(1000)), 5);
+/// assert_eq!(upload_requests(2500, None), 1);
+/// ```
+#[must_use]
+pub fn upload_requests(size: u64, part_size: Option<u64>) -> u64 {
+    match part_size {
+        Some(part_size) if size >= part_size => 2 + size.div_ceil(part_size),
+        _ => 1,
+    }
+}
+
+/// Estimates cost of uploading files from `diff` by [`backup`](crate::backup::backup) with given options.
+///
+/// Files are packed and [deduplicated](crate::database::Database::find_by_checksum) exactly like backup does it,
+/// including deferred and moved files, but nothing is recorded in the index:
+/// everything is done in a transaction which is [rolled back](Database::rolled_back).
+/// Only checksums stored in the `after` snapshot
+/// are used for deduplication, so it should be [hashed](crate::database::Hashing) as well.
+/// [Request budget](BackupOptions::max_requests) is ignored,
+/// since deferred files have to be uploaded anyway.
+///
+/// Requests are counted for the [`part_size`](crate::storage::Storage::part_size)
+/// of the storage archives would be uploaded to.
+pub fn estimate(
+    db: &Database,
+    diff: &Diff,
+    options: &BackupOptions,
+    part_size: Option<u64>,
+    prices: &Prices,
+) -> Result<Estimate, Error> {
+    db.rolled_back(|| estimate_diff(db, diff, options, part_size, prices))
+        .context(DatabaseFailed)?
+}
+
+/// Same as [`estimate`], but changes of the diff and index are kept.
+fn estimate_diff(
+    db: &Database,
+    diff: &Diff,
+    options: &BackupOptions,
+    part_size: Option<u64>,
+    prices: &Prices,
+) -> Result<Estimate, Error> {
+    let class = prices.get(options.storage_class);
+    let mut result = Estimate::default();
+    diff.include_deferred().context(DatabaseFailed)?;
+    result.moved = record_moved(db, diff, true).context(BackupFailed)? as u64;
+    let limits = PackLimits {
+        min_size: options.min_size,
+        max_size: options.max_size,
+        memory: options.memory_limit,
+    };
+    let mut packed = packer::pack(diff, &options.strategy, &limits).context(DatabaseFailed)?;
+    let mut deduplicated = 0;
+    let Deduplicated { packs, later } =
+        deduplicate(db, diff, &mut packed, options, true, &mut deduplicated)
+            .context(BackupFailed)?;
+    // Duplicates from the same packs reference archives right after they are uploaded.
+    result.deduplicated = (deduplicated + later.len()) as u64;
+    for (size, pack) in packs {
+        result.archives += 1;
+        result.files += pack.len() as u64;
+        result.bytes += size;
+        result.requests += upload_requests(size, part_size);
+        result.billed_bytes += size.max(class.min_object_size) + class.class_overhead;
+        result.standard_billed_bytes += class.standard_overhead;
+        result.monthly_storage_cost += prices.monthly_cost(options.storage_class, size);
+        result.min_duration_cost += prices.early_deletion_cost(options.storage_class, size, 0);
+    }
+
+    // Precision loss does not matter for the estimate.
+    #[allow(clippy::cast_precision_loss)]
+    {
+        let requests = result.requests as f64;
+        let archives = result.archives as f64;
+        let bytes = result.bytes as f64 / GB;
+
+        result.upload_cost = requests * class.upload_requests / 1000.0;
+        result.retrieval_cost =
+            archives * class.retrieval_requests / 1000.0 + bytes * class.retrieval;
+    }
+    Ok(result)
+}
@@ -0,0 +1,535 @@
+//! Extracting cpio archives into a directory, together with metadata of every entry.
+//!
+//! Headers store only permissions, owners and modification time in seconds,
+//! while the JSON after the trailer has everything. So metadata is applied
+//! when all entries are written, and directories are finished after their children:
+//! otherwise writing a child would change modification time of the directory.
+//!
+//! Archives are not trusted: entries are never placed outside of the output directory,
+//! and never written through symlinks created by the same archive.
+
+use crate::cpio::reader::{NextItem, ReadError, ReadFile, ReadingError};
+use crate::cpio::{script, Reader};
+use crate::fileext::{self, is_root, lookup_group, lookup_user};
+use crate::fileinfo::{Info, SymlinkInfo, UnspecifiedInfo};
+use crate::path::{EncodedPath, EscapedString, External, Local};
+use crate::stream_hash::stream_hash;
+use crate::types::Checksum;
+use crate::xattr;
+use crate::DateTime;
+use snafu::{ResultExt, Snafu};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    CantRead {
+        source: ReadingError,
+    },
+    CantExtract {
+        path: PathBuf,
+        source: ReadError,
+    },
+    InvalidPath {
+        source: os_str_bytes::EncodingError,
+    },
+    IoFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display(
+        "Invalid mapping `{}`, expected `<archived id>=<local name or id>`",
+        mapping
+    ))]
+    InvalidMapping {
+        mapping: String,
+    },
+}
+
+/// Which owners are given to extracted files.
+#[derive(Debug, Clone)]
+pub struct Owners {
+    /// Whether to restore user and group ids stored in the archive.
+    /// Only root is able to do that, so by default it's enabled only for root.
+    pub keep: bool,
+    /// Archived user ids, that are replaced by ids of local users. Applied even when `keep` is off.
+    pub users: HashMap<u32, u32>,
+    /// Same as `users`, but for groups.
+    pub groups: HashMap<u32, u32>,
+}
+
+impl Default for Owners {
+    fn default() -> Self {
+        Owners {
+            keep: is_root(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+}
+
+/// Parses `<archived id>=<local name or id>`, looking up local names with `lookup`.
+fn parse_mapping(mapping: &str, lookup: fn(&str) -> Option<u32>) -> Result<(u32, u32), Error> {
+    let parsed = mapping.split_once('=').and_then(|(archived, local)| {
+        let archived = archived.parse().ok()?;
+        let local = local.parse().ok().or_else(|| lookup(local))?;
+        Some((archived, local))
+    });
+    parsed.ok_or_else(|| Error::InvalidMapping {
+        mapping: mapping.to_owned(),
+    })
+}
+
+impl Owners {
+    /// Adds mapping of the user, like `1000=alice` or `1000=1001`.
+    pub fn map_user(&mut self, mapping: &str) -> Result<(), Error> {
+        let (archived, local) = parse_mapping(mapping, lookup_user)?;
+        self.users.insert(archived, local);
+        Ok(())
+    }
+
+    /// Adds mapping of the group, like `100=users`.
+    pub fn map_group(&mut self, mapping: &str) -> Result<(), Error> {
+        let (archived, local) = parse_mapping(mapping, lookup_group)?;
+        self.groups.insert(archived, local);
+        Ok(())
+    }
+
+    /// Returns user and group which should own the file, `None` keeps the current one.
+    ///
+    /// ```
+    /// # use colbak_lib::extract::Owners;
+    /// let mut owners = Owners::default();
+    /// owners.keep = false;
+    /// owners.map_user("1000=0").unwrap();
+    /// assert_eq!(owners.resolve(1000, 100), (Some(0), None));
+    /// owners.keep = true;
+    /// assert_eq!(owners.resolve(1001, 100), (Some(1001), Some(100)));
+    /// assert!(owners.map_group("users").is_err());
+    /// ```
+    #[must_use]
+    pub fn resolve(&self, user: u32, group: u32) -> (Option<u32>, Option<u32>) {
+        let keep = |id| if self.keep { Some(id) } else { None };
+        let user = self.users.get(&user).copied().or_else(|| keep(user));
+        let group = self.groups.get(&group).copied().or_else(|| keep(group));
+        (user, group)
+    }
+}
+
+/// What to do when an entry already exists in the output directory.
+///
+/// Existing directories are always reused, and are never replaced by other entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Default)]
+pub enum Existing {
+    /// Leave the existing entry as is.
+    #[default]
+    Skip,
+    /// Remove the existing entry and extract the archived one.
+    Overwrite,
+    /// Extract the archived entry under the first free name like `file.~1~`.
+    KeepBoth,
+    /// Overwrite only when the archived entry is modified later than the existing one.
+    IfNewer,
+}
+
+
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown policy `{}`, expected `skip`, `overwrite`, `keep-both` or `if-newer`",
+    name
+))]
+pub struct UnknownPolicy {
+    name: String,
+}
+
+impl FromStr for Existing {
+    type Err = UnknownPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Existing::Skip),
+            "overwrite" => Ok(Existing::Overwrite),
+            "keep-both" => Ok(Existing::KeepBoth),
+            "if-newer" => Ok(Existing::IfNewer),
+            _ => Err(UnknownPolicy { name: s.to_owned() }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub owners: Owners,
+    pub existing: Existing,
+}
+
+/// What was done by [`extract`](extract).
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// Number of extracted entries, including directories.
+    pub entries: usize,
+    /// Files which checksums do not match ones stored in the trailer.
+    pub mismatches: Vec<EncodedPath<External>>,
+    /// Entries that were not extracted, because they are unsafe or already exist.
+    pub skipped: Vec<EncodedPath<External>>,
+    /// Number of entries and attributes that were not restored, details are logged.
+    pub warnings: usize,
+}
+
+/// Entry of the archive, in the same order.
+struct Extracted {
+    /// Where the entry was written, `None` when it was skipped.
+    path: Option<PathBuf>,
+    info: Info<External>,
+}
+
+/// Where the file located at `path` in the archive or snapshot should be placed.
+///
+/// Path is always placed inside `output`, like `tar` does: root components are dropped.
+/// Paths with `..` components may point anywhere, so `None` is returned for them.
+pub(crate) fn destination(
+    output: &Path,
+    path: &EncodedPath<External>,
+) -> Result<Option<PathBuf>, os_str_bytes::EncodingError> {
+    let path = path.clone().cast::<Local>().to_path()?;
+    let mut result = output.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(x) => result.push(x),
+            Component::ParentDir => return Ok(None),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(Some(result))
+}
+
+/// Returns the first name like `file.~1~`, that is not taken yet.
+async fn free_name(path: &Path) -> std::io::Result<PathBuf> {
+    let mut i = 0_u64;
+    loop {
+        i += 1;
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".~{i}~"));
+        let candidate = PathBuf::from(name);
+        match tokio::fs::symlink_metadata(&candidate).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(candidate),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Frees the place for the entry according to the policy.
+///
+/// Returns where the entry should be written, or `None` when it should be skipped.
+/// Existing files are removed, not truncated: that way symlinks are never followed.
+pub(crate) async fn make_place(
+    path: PathBuf,
+    info: &Info<External>,
+    existing: Existing,
+) -> std::io::Result<Option<PathBuf>> {
+    let found = match tokio::fs::symlink_metadata(&path).await {
+        Ok(found) => found,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(path)),
+        Err(e) => return Err(e),
+    };
+    let is_dir = matches!(info.data, UnspecifiedInfo::Dir(_));
+    if is_dir && found.is_dir() {
+        return Ok(Some(path));
+    }
+    let replace = match existing {
+        Existing::Skip => false,
+        Existing::Overwrite => true,
+        Existing::KeepBoth => return free_name(&path).await.map(Some),
+        Existing::IfNewer => match found.modified() {
+            Ok(modified) => info.modified_at > DateTime::from(modified),
+            Err(_) => true,
+        },
+    };
+    if !replace || found.is_dir() {
+        return Ok(None);
+    }
+    tokio::fs::remove_file(&path).await?;
+    Ok(Some(path))
+}
+
+/// Sets owner, permissions, extended attributes and times of the extracted entry.
+///
+/// Failures are only logged, since most of them are caused by lack of privileges.
+/// Returns number of failures.
+fn apply_metadata(path: &Path, info: &Info<External>, owners: &Owners) -> usize {
+    let mut failures = 0;
+    let mut warn = |what: &str, result: std::io::Result<()>| {
+        if let Err(e) = result {
+            log!(warn: "Can't set {} of {}: {}", what, path = path.display().to_string(), error = e.to_string());
+            failures += 1;
+        }
+    };
+    let is_symlink = matches!(info.data, UnspecifiedInfo::Symlink(_));
+    // Owner goes first, since changing it drops setuid bits.
+    let (user, group) = owners.resolve(info.user_id, info.group_id);
+    if user.is_some() || group.is_some() {
+        warn("owner", fileext::set_owner(path, user, group));
+    }
+    // Attributes can't be written to read-only files, so they go before permissions.
+    for attribute in &info.xattrs {
+        let name = String::from_utf8_lossy(&attribute.name).into_owned();
+        warn(
+            &format!("attribute {name}"),
+            xattr::write(path, attribute),
+        );
+    }
+    // Symlinks have no permissions of their own.
+    if !is_symlink {
+        warn("permissions", fileext::set_mode(path, info.mode));
+    }
+    warn(
+        "times",
+        fileext::set_times(path, info.accessed_at, info.modified_at),
+    );
+    failures
+}
+
+/// Applies metadata to all given entries: to directories last, starting from the deepest ones.
+///
+/// Hard links share metadata with their targets, so they are skipped.
+/// Returns number of failures.
+pub(crate) fn finish(mut entries: Vec<(PathBuf, Info<External>)>, owners: &Owners) -> usize {
+    entries.retain(|(_, info)| !matches!(info.data, UnspecifiedInfo::HardLink(_)));
+    entries.sort_by_key(|(path, info)| match info.data {
+        UnspecifiedInfo::Dir(_) => Some(std::cmp::Reverse(path.components().count())),
+        _ => None,
+    });
+    entries
+        .iter()
+        .map(|(path, info)| apply_metadata(path, info, owners))
+        .sum()
+}
+
+/// Takes metadata of the entries from the trailer, when it's available.
+///
+/// Entries are listed in the same order there, with checksums of files.
+fn merge(extracted: &mut [Extracted], trailer: Vec<Info<External>>, report: &mut ExtractReport) {
+    for (entry, expected) in extracted.iter_mut().zip(trailer) {
+        let found = &entry.info;
+        if entry.path.is_none() {
+            continue;
+        }
+        if expected.path != found.path {
+            log!(warn: "Path mismatch: expected {}, found {}", expected = expected.path.escaped(), found = found.path.escaped());
+            report.warnings += 1;
+            continue;
+        }
+        if let (Some(x), Some(y)) = (expected.hash, found.hash) {
+            if x != y {
+                log!(warn: "Checksum mismatch at {}: expected {}, found {}", path = found.path.escaped(), x, y);
+                report.mismatches.push(found.path.clone());
+            }
+        }
+        entry.info = expected;
+    }
+}
+
+/// Finds where the entry should be written, creating missing parent directories.
+///
+/// Returns the reason when the entry should be skipped.
+async fn place(
+    output: &Path,
+    info: &Info<External>,
+    symlinks: &HashSet<PathBuf>,
+    existing: Existing,
+) -> Result<Result<PathBuf, String>, Error> {
+    let dst = match destination(output, &info.path).context(InvalidPath)? {
+        Some(dst) => dst,
+        None => return Ok(Err("it is outside of the output directory".to_owned())),
+    };
+    if let Some(link) = dst.ancestors().skip(1).find(|x| symlinks.contains(*x)) {
+        return Ok(Err(format!("{} is a symlink", link.display())));
+    }
+    if let Some(parent) = dst.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context(IoFailed { path: parent })?;
+    }
+    let placed = make_place(dst.clone(), info, existing)
+        .await
+        .context(IoFailed { path: &dst })?;
+    Ok(placed.ok_or_else(|| "it already exists".to_owned()))
+}
+
+/// Paths of entries extracted so far.
+#[derive(Default)]
+struct Placed {
+    /// Archived path → where it was placed.
+    entries: HashMap<Vec<u8>, PathBuf>,
+    /// Symlinks created from the archive, they are never followed.
+    symlinks: HashSet<PathBuf>,
+}
+
+/// Writes a single entry to `dst`. Returns false when it was skipped.
+async fn write_entry<R: AsyncRead + Unpin>(
+    file: ReadFile<R>,
+    info: &mut Info<External>,
+    dst: &Path,
+    placed: &mut Placed,
+    report: &mut ExtractReport,
+) -> Result<(Reader<R>, bool), Error> {
+    match &info.data {
+        UnspecifiedInfo::Dir(_) => {
+            tokio::fs::create_dir_all(dst)
+                .await
+                .context(IoFailed { path: dst })?;
+        }
+        UnspecifiedInfo::File(_) => {
+            let earlier: Vec<PathBuf> = file
+                .earlier_links()
+                .iter()
+                .filter_map(|name| placed.entries.get(name).cloned())
+                .collect();
+            let output = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dst)
+                .await
+                .context(IoFailed { path: dst })?;
+            let mut hasher = stream_hash(output);
+            let archive = file
+                .drain_to(&mut hasher)
+                .await
+                .context(CantExtract { path: dst })?;
+            hasher.flush().await.context(IoFailed { path: dst })?;
+            info.hash = Some(Checksum::from(hasher.finalize()));
+            // Empty links were written before the data, now they become links to it.
+            for path in earlier {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .context(IoFailed { path: &path })?;
+                tokio::fs::hard_link(dst, &path)
+                    .await
+                    .context(IoFailed { path: &path })?;
+            }
+            return Ok((archive, true));
+        }
+        UnspecifiedInfo::Symlink(_) => {
+            let mut target = Vec::new();
+            let archive = file
+                .drain_to(&mut target)
+                .await
+                .context(CantExtract { path: dst })?;
+            let path = EncodedPath::from_vec(target.clone()).cast::<Local>();
+            let path = path.to_path().context(InvalidPath)?;
+            tokio::fs::symlink(path, dst)
+                .await
+                .context(IoFailed { path: dst })?;
+            placed.symlinks.insert(dst.to_path_buf());
+            info.data = UnspecifiedInfo::Symlink(SymlinkInfo { target });
+            return Ok((archive, true));
+        }
+        UnspecifiedInfo::HardLink(link) => {
+            // Only entries of this archive are linked, nothing outside of the output.
+            let target = if let Some(target) = placed.entries.get(&link.target) {
+                target
+            } else {
+                log!(warn: "Skipping {}: its target was not extracted", path = info.path.escaped());
+                report.skipped.push(info.path.clone());
+                let archive = file.to_void().await.context(CantExtract { path: dst })?;
+                return Ok((archive, false));
+            };
+            tokio::fs::hard_link(target, dst)
+                .await
+                .context(IoFailed { path: dst })?;
+            // Link to the symlink is a symlink too.
+            if placed.symlinks.contains(target) {
+                placed.symlinks.insert(dst.to_path_buf());
+            }
+        }
+        UnspecifiedInfo::Fifo(_) => {
+            fileext::make_fifo(dst, 0o600).context(IoFailed { path: dst })?;
+        }
+        UnspecifiedInfo::Device(device) => {
+            if let Err(e) = fileext::make_device(dst, device, 0o600) {
+                log!(warn: "Skipping device {}: {}", path = info.path.escaped(), error = e.to_string());
+                report.warnings += 1;
+                let archive = file.to_void().await.context(CantExtract { path: dst })?;
+                return Ok((archive, false));
+            }
+        }
+        UnspecifiedInfo::Unknown(_) => {
+            log!(warn: "Skipping {}: unknown file type", path = info.path.escaped());
+            report.warnings += 1;
+            let archive = file.to_void().await.context(CantExtract { path: dst })?;
+            return Ok((archive, false));
+        }
+    }
+    let archive = file.to_void().await.context(CantExtract { path: dst })?;
+    Ok((archive, true))
+}
+
+/// Extracts all entries of the archive into `output`, restoring their metadata.
+///
+/// [Script and manifest](script) are skipped. Devices which can't be created are skipped too.
+/// Missing parent directories are created, entries that already exist are handled
+/// according to [`existing`](ExtractOptions::existing).
+///
+/// Entries with `..` in their names, entries placed inside of symlinks extracted earlier,
+/// and hard links to entries that were not extracted are skipped with a warning.
+pub async fn extract<R: AsyncRead + Unpin>(
+    reader: R,
+    output: &Path,
+    options: &ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let mut report = ExtractReport::default();
+    let mut extracted: Vec<Extracted> = Vec::new();
+    let mut placed = Placed::default();
+    let mut archive = Reader::new(reader);
+    let trailer = loop {
+        let file = match archive.advance().await.context(CantRead)? {
+            NextItem::File(file) => file,
+            NextItem::End(end) => break end.files,
+        };
+        let mut info = file.info();
+        if script::is_embedded(info.path.as_bytes()) {
+            // Not listed in the trailer.
+            archive = file.to_void().await.context(CantExtract { path: output })?;
+            continue;
+        }
+        let dst = match place(output, &info, &placed.symlinks, options.existing).await? {
+            Ok(dst) => dst,
+            Err(reason) => {
+                log!(warn: "Skipping {}: {}", path = info.path.escaped(), reason);
+                archive = file.to_void().await.context(CantExtract { path: output })?;
+                report.skipped.push(info.path.clone());
+                extracted.push(Extracted { path: None, info });
+                continue;
+            }
+        };
+        // It's replaced by something else now, if it was there.
+        placed.symlinks.remove(&dst);
+        log!(cli: "Extracting {}", path = info.path.escaped());
+        let (next, created) = write_entry(file, &mut info, &dst, &mut placed, &mut report).await?;
+        archive = next;
+        let path = if created {
+            report.entries += 1;
+            placed
+                .entries
+                .insert(info.path.as_bytes().to_vec(), dst.clone());
+            Some(dst)
+        } else {
+            None
+        };
+        extracted.push(Extracted { path, info });
+    };
+    if let Some(trailer) = trailer {
+        merge(&mut extracted, trailer, &mut report);
+    }
+    let entries = extracted
+        .into_iter()
+        .filter_map(|x| Some((x.path?, x.info)))
+        .collect();
+    report.warnings += finish(entries, &options.owners);
+    Ok(report)
+}
@@ -1,10 +1,28 @@
+use crate::fileinfo::{DeviceInfo, DeviceKind};
+use crate::DateTime;
+#[cfg(unix)]
+use std::ffi::CString;
 use std::fs::Metadata;
+use std::io;
+use std::path::Path;
 
 pub(crate) trait FileExtensions {
     fn inode(&self) -> u64;
     fn mode(&self) -> u32;
     fn user_id(&self) -> u32;
     fn group_id(&self) -> u32;
+    fn links(&self) -> u64;
+    /// Device number of the special file.
+    fn rdev(&self) -> u64;
+    /// Device containing the file.
+    fn dev(&self) -> u64;
+    /// Number of 512-byte blocks allocated for the file.
+    fn blocks(&self) -> u64;
+    /// Time of the last status change, in nanoseconds since unix epoch.
+    fn status_changed(&self) -> Option<i128>;
+    fn is_fifo(&self) -> bool;
+    fn is_char_device(&self) -> bool;
+    fn is_block_device(&self) -> bool;
 }
 
 #[cfg(unix)]
@@ -24,6 +42,40 @@ impl FileExtensions for Metadata {
     fn group_id(&self) -> u32 {
         std::os::unix::fs::MetadataExt::gid(self)
     }
+
+    fn links(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::nlink(self)
+    }
+
+    fn rdev(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::rdev(self)
+    }
+
+    fn dev(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::dev(self)
+    }
+
+    fn blocks(&self) -> u64 {
+        std::os::unix::fs::MetadataExt::blocks(self)
+    }
+
+    fn status_changed(&self) -> Option<i128> {
+        let seconds = i128::from(std::os::unix::fs::MetadataExt::ctime(self));
+        let nanos = i128::from(std::os::unix::fs::MetadataExt::ctime_nsec(self));
+        Some(seconds * 1_000_000_000 + nanos)
+    }
+
+    fn is_fifo(&self) -> bool {
+        std::os::unix::fs::FileTypeExt::is_fifo(&self.file_type())
+    }
+
+    fn is_char_device(&self) -> bool {
+        std::os::unix::fs::FileTypeExt::is_char_device(&self.file_type())
+    }
+
+    fn is_block_device(&self) -> bool {
+        std::os::unix::fs::FileTypeExt::is_block_device(&self.file_type())
+    }
 }
 
 #[cfg(windows)]
@@ -43,4 +95,206 @@ impl FileExtensions for Metadata {
     fn group_id(&self) -> u32 {
         u32::MAX
     }
+
+    fn links(&self) -> u64 {
+        1
+    }
+
+    fn rdev(&self) -> u64 {
+        0
+    }
+
+    fn dev(&self) -> u64 {
+        0
+    }
+
+    fn blocks(&self) -> u64 {
+        0
+    }
+
+    fn status_changed(&self) -> Option<i128> {
+        None
+    }
+
+    fn is_fifo(&self) -> bool {
+        false
+    }
+
+    fn is_char_device(&self) -> bool {
+        false
+    }
+
+    fn is_block_device(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(unix)]
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> io::Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    c_string(path.as_os_str().as_bytes())
+}
+
+/// Converts result of the libc call into `io::Result`.
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sets access and modification times of the file, with nanoseconds. Symlinks are not followed.
+///
+/// Access time is left as is when it is unknown.
+#[cfg(unix)]
+pub fn set_times(path: &Path, accessed: Option<DateTime>, modified: DateTime) -> io::Result<()> {
+    const NANOS: i128 = 1_000_000_000;
+    // Seconds fit into time_t for any date supported by `time`, nanoseconds are below 10^9.
+    #[allow(clippy::cast_possible_truncation)]
+    let timespec = |time: Option<DateTime>| match time {
+        Some(time) => {
+            let nanos = time.unix_timestamp_nanos();
+            libc::timespec {
+                tv_sec: nanos.div_euclid(NANOS) as libc::time_t,
+                tv_nsec: nanos.rem_euclid(NANOS) as libc::c_long,
+            }
+        }
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+    let times = [timespec(accessed), timespec(Some(modified))];
+    let path = c_path(path)?;
+    check(unsafe {
+        // Safe: path is NUL-terminated and there are exactly two timestamps.
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })
+}
+
+/// Changes owner and group of the file, `None` keeps the current one. Symlinks are not followed.
+#[cfg(unix)]
+pub fn set_owner(path: &Path, user: Option<u32>, group: Option<u32>) -> io::Result<()> {
+    // -1 means "do not change".
+    let user = user.unwrap_or(u32::MAX);
+    let group = group.unwrap_or(u32::MAX);
+    let path = c_path(path)?;
+    // Safe: path is NUL-terminated.
+    check(unsafe { libc::lchown(path.as_ptr(), user, group) })
+}
+
+/// Sets permissions of the file, including setuid, setgid and sticky bits. Symlinks are followed.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+/// Returns true when running with root privileges, so files of any owner can be created.
+#[cfg(unix)]
+#[must_use]
+pub fn is_root() -> bool {
+    // Safe: this call always succeeds.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Creates a named pipe with given permissions.
+#[cfg(unix)]
+pub fn make_fifo(path: &Path, mode: u32) -> io::Result<()> {
+    let path = c_path(path)?;
+    // Safe: path is NUL-terminated.
+    check(unsafe { libc::mkfifo(path.as_ptr(), mode & 0o7777) })
+}
+
+/// Creates a device node, usually requires root privileges.
+#[cfg(unix)]
+pub fn make_device(path: &Path, device: &DeviceInfo, mode: u32) -> io::Result<()> {
+    let kind = match device.kind {
+        DeviceKind::Char => libc::S_IFCHR,
+        DeviceKind::Block => libc::S_IFBLK,
+    };
+    let path = c_path(path)?;
+    // Safe: path is NUL-terminated.
+    check(unsafe { libc::mknod(path.as_ptr(), kind | (mode & 0o7777), device.rdev()) })
+}
+
+/// Finds id of the local user by name.
+#[cfg(unix)]
+#[must_use]
+pub fn lookup_user(name: &str) -> Option<u32> {
+    let name = c_string(name.as_bytes()).ok()?;
+    // Safe: name is NUL-terminated, result is read before any other call.
+    unsafe { libc::getpwnam(name.as_ptr()).as_ref().map(|x| x.pw_uid) }
+}
+
+/// Finds id of the local group by name.
+#[cfg(unix)]
+#[must_use]
+pub fn lookup_group(name: &str) -> Option<u32> {
+    let name = c_string(name.as_bytes()).ok()?;
+    // Safe: same as in `lookup_user`.
+    unsafe { libc::getgrnam(name.as_ptr()).as_ref().map(|x| x.gr_gid) }
+}
+
+#[cfg(windows)]
+fn unsupported<T>() -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Not supported on this platform",
+    ))
+}
+
+#[cfg(windows)]
+pub fn set_times(_path: &Path, _accessed: Option<DateTime>, _modified: DateTime) -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(windows)]
+pub fn set_owner(_path: &Path, _user: Option<u32>, _group: Option<u32>) -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(windows)]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(windows)]
+#[must_use]
+pub fn is_root() -> bool {
+    false
+}
+
+#[cfg(windows)]
+pub fn make_fifo(_path: &Path, _mode: u32) -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(windows)]
+pub fn make_device(_path: &Path, _device: &DeviceInfo, _mode: u32) -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(windows)]
+#[must_use]
+pub fn lookup_user(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(windows)]
+#[must_use]
+pub fn lookup_group(_name: &str) -> Option<u32> {
+    None
 }
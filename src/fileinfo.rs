@@ -2,13 +2,14 @@
 
 use crate::fileext::FileExtensions;
 use crate::path::{EncodedPath, Local, PathKind};
+use crate::serde_b64;
 use crate::types::Checksum;
+use crate::xattr::{self, Xattr};
 use crate::DateTime;
 use serde::{Deserialize, Serialize};
 use std::fs::Metadata;
 use std::path::PathBuf;
 use std::time::SystemTime;
-use tokio::fs::File;
 
 /// Stores generic information about some object in filesystem: file or directory or whatever.
 /// More specific information is stored in `data` field.
@@ -17,6 +18,10 @@ use tokio::fs::File;
 /// - [`UnspecifiedInfo`](UnspecifiedInfo) (default)
 /// - [`FileInfo`](FileInfo)
 /// - [`DirInfo`](DirInfo)
+/// - [`SymlinkInfo`](SymlinkInfo)
+/// - [`HardLinkInfo`](HardLinkInfo)
+/// - [`FifoInfo`](FifoInfo)
+/// - [`DeviceInfo`](DeviceInfo)
 /// - [`UnknownInfo`](UnknownInfo)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(bound(serialize = "Kind: Serialize", deserialize = "Kind: Deserialize<'de>"))]
@@ -30,7 +35,22 @@ pub struct Info<P: PathKind, Kind = UnspecifiedInfo> {
     pub group_id: u32,
     pub created_at: DateTime,
     pub modified_at: DateTime,
+    /// Time of the last access. Reading the file changes it, so it is ignored when comparing snapshots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accessed_at: Option<DateTime>,
+    /// Time of the last status change (`ctime`), unknown on Windows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_at: Option<DateTime>,
+    /// Device containing the file. It may change after remount, so it is ignored when comparing snapshots.
+    #[serde(default)]
+    pub device: u64,
+    /// Number of 512-byte blocks allocated for the file, less than size for sparse files.
+    #[serde(default)]
+    pub blocks: u64,
     pub hash: Option<Checksum>,
+    /// Extended attributes and ACLs, sorted by name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<Xattr>,
     #[serde(flatten)]
     pub data: Kind,
 }
@@ -38,11 +58,104 @@ pub struct Info<P: PathKind, Kind = UnspecifiedInfo> {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileInfo {
     pub size: u64,
+    /// Number of hard links to the file. It is not stored when there is only one.
+    #[serde(default = "single_link", skip_serializing_if = "is_single_link")]
+    pub links: u64,
+}
+
+fn single_link() -> u64 {
+    1
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // Required by serde.
+fn is_single_link(links: &u64) -> bool {
+    *links <= 1
+}
+
+impl FileInfo {
+    /// Creates info of the file without other hard links.
+    #[must_use]
+    pub fn new(size: u64) -> Self {
+        FileInfo { size, links: 1 }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct DirInfo {}
 
+/// Symbolic link. In the archive its target is stored as the entry data, like cpio does.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct SymlinkInfo {
+    /// Path the link points to, as it was read from the filesystem. It is not resolved.
+    #[serde(with = "serde_b64")]
+    pub target: Vec<u8>,
+}
+
+/// Another name of the file that is already placed into the same archive.
+///
+/// Such entry has no data, it shares the inode with the target entry instead.
+/// Only [`Archive`](crate::cpio::Archive) creates these, snapshots store every link as a file.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct HardLinkInfo {
+    /// Name of the entry which holds the data.
+    #[serde(with = "serde_b64")]
+    pub target: Vec<u8>,
+    /// Number of links to the inode, copied from [`FileInfo`](FileInfo).
+    pub links: u64,
+}
+
+/// Named pipe.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct FifoInfo {}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DeviceKind {
+    Char,
+    Block,
+}
+
+/// Character or block device.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceInfo {
+    /// Splits device number, as it is encoded by Linux.
+    ///
+    /// ```
+    /// # use colbak_lib::fileinfo::{DeviceInfo, DeviceKind};
+    /// let tty = DeviceInfo::from_rdev(DeviceKind::Char, 0x0401);
+    /// assert_eq!((tty.major, tty.minor), (4, 1));
+    /// let large = DeviceInfo::from_rdev(DeviceKind::Block, 0x0001_2000_6783_459A);
+    /// assert_eq!((large.major, large.minor), (0x12345, 0x6789A));
+    /// assert_eq!(large.rdev(), 0x0001_2000_6783_459A);
+    /// ```
+    #[must_use]
+    pub fn from_rdev(kind: DeviceKind, rdev: u64) -> Self {
+        // Both halves fit into 32 bits after masking.
+        #[allow(clippy::cast_possible_truncation)]
+        DeviceInfo {
+            kind,
+            major: (((rdev >> 32) & 0xFFFF_F000) | ((rdev >> 8) & 0x0000_0FFF)) as u32,
+            minor: (((rdev >> 12) & 0xFFFF_FF00) | (rdev & 0x0000_00FF)) as u32,
+        }
+    }
+
+    /// Reverse of [`from_rdev`](Self::from_rdev).
+    #[must_use]
+    pub fn rdev(&self) -> u64 {
+        let major = u64::from(self.major);
+        let minor = u64::from(self.minor);
+        ((major & 0xFFFF_F000) << 32)
+            | ((major & 0x0000_0FFF) << 8)
+            | ((minor & 0xFFFF_FF00) << 12)
+            | (minor & 0x0000_00FF)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct UnknownInfo {}
 
@@ -51,6 +164,10 @@ pub struct UnknownInfo {}
 pub enum UnspecifiedInfo {
     File(FileInfo),
     Dir(DirInfo),
+    Symlink(SymlinkInfo),
+    HardLink(HardLinkInfo),
+    Fifo(FifoInfo),
+    Device(DeviceInfo),
     Unknown(UnknownInfo),
 }
 
@@ -59,6 +176,10 @@ pub enum UnspecifiedInfo {
 pub enum InfoKind<P: PathKind> {
     File(Info<P, FileInfo>) = 1,
     Dir(Info<P, DirInfo>) = 2,
+    Symlink(Info<P, SymlinkInfo>) = 3,
+    HardLink(Info<P, HardLinkInfo>) = 4,
+    Fifo(Info<P, FifoInfo>) = 5,
+    Device(Info<P, DeviceInfo>) = 6,
     Unknown(Info<P, UnknownInfo>) = u8::MAX,
 }
 
@@ -67,7 +188,9 @@ pub enum InfoKind<P: PathKind> {
 ///
 /// This identifier is used to find what files are really changed, it is good enough to do it reliably.
 /// (at least it's not worse than looking at `modified_at`, and many popular are doing just that)
-#[repr(C)]
+///
+/// Identifiers are compared by their bytes, so there must be no padding between fields.
+#[repr(C, packed)]
 pub struct FileIdentifier {
     inode: u64,
     ctime: i128,
@@ -75,10 +198,12 @@ pub struct FileIdentifier {
     mtime: i128,
 }
 
+static_assertions::assert_eq_size!(FileIdentifier, [u8; 48]);
+
 impl FileIdentifier {
     #[must_use]
     pub fn as_bytes(&self) -> &[u8] {
-        let ptr = (self as *const Self).cast::<u8>();
+        let ptr = std::ptr::from_ref::<Self>(self).cast::<u8>();
         unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of::<Self>()) }
     }
 }
@@ -93,6 +218,10 @@ impl<K: PathKind> Info<K, UnspecifiedInfo> {
         match self.clone().turn() {
             InfoKind::File(f) => Some(f.identifier()),
             InfoKind::Dir(_) => None,
+            InfoKind::Symlink(_) => None,
+            InfoKind::HardLink(_) => None,
+            InfoKind::Fifo(_) => None,
+            InfoKind::Device(_) => None,
             InfoKind::Unknown(_) => None,
         }
     }
@@ -111,6 +240,29 @@ impl<K: PathKind> Info<K, FileInfo> {
     }
 }
 
+impl<P: PathKind, Kind> Info<P, Kind> {
+    /// Changes kind of the path. Use with caution, see [`EncodedPath::cast`](EncodedPath::cast).
+    #[must_use]
+    pub fn cast<T: PathKind>(self) -> Info<T, Kind> {
+        Info {
+            path: self.path.cast(),
+            inode: self.inode,
+            mode: self.mode,
+            user_id: self.user_id,
+            group_id: self.group_id,
+            created_at: self.created_at,
+            modified_at: self.modified_at,
+            accessed_at: self.accessed_at,
+            changed_at: self.changed_at,
+            device: self.device,
+            blocks: self.blocks,
+            hash: self.hash,
+            xattrs: self.xattrs,
+            data: self.data,
+        }
+    }
+}
+
 impl<P: PathKind> Info<P, UnspecifiedInfo> {
     /// Returns size of file, or None when it is not a file.
     #[must_use]
@@ -118,10 +270,32 @@ impl<P: PathKind> Info<P, UnspecifiedInfo> {
         match &self.data {
             UnspecifiedInfo::File(file) => Some(file.size),
             UnspecifiedInfo::Dir(_) => None,
+            UnspecifiedInfo::Symlink(_) => None,
+            UnspecifiedInfo::HardLink(_) => None,
+            UnspecifiedInfo::Fifo(_) => None,
+            UnspecifiedInfo::Device(_) => None,
             UnspecifiedInfo::Unknown(_) => None,
         }
     }
 
+    /// Returns data that is stored in the archive instead of reading the file, if any.
+    #[must_use]
+    pub fn inline_data(&self) -> Option<&[u8]> {
+        match &self.data {
+            UnspecifiedInfo::Symlink(link) => Some(&link.target),
+            _ => None,
+        }
+    }
+
+    /// Size of the entry data in the archive.
+    #[must_use]
+    pub fn data_size(&self) -> u64 {
+        match self.inline_data() {
+            Some(data) => data.len() as u64,
+            None => self.size().unwrap_or(0),
+        }
+    }
+
     /// Turns from `Info<UnspecifiedInfo>` (enum is inside) to `InfoKind` (enum is outside).
     #[allow(clippy::unwrap_used)]
     #[must_use]
@@ -130,6 +304,10 @@ impl<P: PathKind> Info<P, UnspecifiedInfo> {
         match &self.data {
             UnspecifiedInfo::File(_) => InfoKind::File(self.into_file().unwrap()),
             UnspecifiedInfo::Dir(_) => InfoKind::Dir(self.into_dir().unwrap()),
+            UnspecifiedInfo::Symlink(_) => InfoKind::Symlink(self.into_symlink().unwrap()),
+            UnspecifiedInfo::HardLink(_) => InfoKind::HardLink(self.into_hard_link().unwrap()),
+            UnspecifiedInfo::Fifo(_) => InfoKind::Fifo(self.into_fifo().unwrap()),
+            UnspecifiedInfo::Device(_) => InfoKind::Device(self.into_device().unwrap()),
             UnspecifiedInfo::Unknown(_) => InfoKind::Unknown(self.into_unknown().unwrap()),
         }
     }
@@ -147,7 +325,12 @@ macro_rules! conversion {
                     group_id: x.group_id,
                     created_at: x.created_at,
                     modified_at: x.modified_at,
+                    accessed_at: x.accessed_at,
+                    changed_at: x.changed_at,
+                    device: x.device,
+                    blocks: x.blocks,
                     hash: x.hash,
+                    xattrs: x.xattrs,
                     data: UnspecifiedInfo::$i(x.data),
                 }
             }
@@ -165,7 +348,12 @@ macro_rules! conversion {
                         group_id: self.group_id,
                         created_at: self.created_at,
                         modified_at: self.modified_at,
+                        accessed_at: self.accessed_at,
+                        changed_at: self.changed_at,
+                        device: self.device,
+                        blocks: self.blocks,
                         hash: self.hash,
+                        xattrs: self.xattrs,
                     }),
                     _ => Err(self),
                 }
@@ -175,6 +363,10 @@ macro_rules! conversion {
 }
 conversion!(using Dir (into_dir) from DirInfo);
 conversion!(using File (into_file) from FileInfo);
+conversion!(using Symlink (into_symlink) from SymlinkInfo);
+conversion!(using HardLink (into_hard_link) from HardLinkInfo);
+conversion!(using Fifo (into_fifo) from FifoInfo);
+conversion!(using Device (into_device) from DeviceInfo);
 conversion!(using Unknown (into_unknown) from UnknownInfo);
 
 /// Converts `SystemTime` to normal `DateTime`, falling back to
@@ -208,22 +400,40 @@ pub fn systime_to_datetime<E>(x: Result<SystemTime, E>) -> DateTime {
 }
 
 /// Extracts specific information about the file from metadata given by OS.
-fn extract_kind(metadata: &Metadata) -> UnspecifiedInfo {
+///
+/// Metadata should be read without following symlinks, and `path` is used for reading their targets.
+fn extract_kind(path: &EncodedPath<Local>, metadata: &Metadata) -> UnspecifiedInfo {
     if metadata.is_file() {
         UnspecifiedInfo::File(FileInfo {
             size: metadata.len(),
+            links: metadata.links(),
         })
     } else if metadata.is_dir() {
         UnspecifiedInfo::Dir(DirInfo {})
+    } else if metadata.file_type().is_symlink() {
+        let target = path.to_path().ok().map(std::fs::read_link);
+        match target {
+            Some(Ok(target)) => UnspecifiedInfo::Symlink(SymlinkInfo {
+                target: EncodedPath::from_path(target).as_bytes().to_vec(),
+            }),
+            // Link was removed while we were looking at it.
+            _ => UnspecifiedInfo::Unknown(UnknownInfo {}),
+        }
+    } else if metadata.is_fifo() {
+        UnspecifiedInfo::Fifo(FifoInfo {})
+    } else if metadata.is_char_device() {
+        UnspecifiedInfo::Device(DeviceInfo::from_rdev(DeviceKind::Char, metadata.rdev()))
+    } else if metadata.is_block_device() {
+        UnspecifiedInfo::Device(DeviceInfo::from_rdev(DeviceKind::Block, metadata.rdev()))
     } else {
         UnspecifiedInfo::Unknown(UnknownInfo {})
     }
 }
 
 impl Info<Local> {
+    /// Reads information about the file. Symlinks are not followed.
     pub async fn new(local_path: PathBuf) -> Result<Self, tokio::io::Error> {
-        let file = File::open(&local_path).await?;
-        let metadata = file.metadata().await?;
+        let metadata = tokio::fs::symlink_metadata(&local_path).await?;
 
         let path = EncodedPath::from_path(local_path);
         Ok(Info::with_metadata(path, &metadata))
@@ -231,6 +441,13 @@ impl Info<Local> {
 
     #[must_use]
     pub fn with_metadata(path: EncodedPath<Local>, metadata: &Metadata) -> Self {
+        let data = extract_kind(&path, metadata);
+        // Failing to read attributes is not a reason to skip the file.
+        let xattrs = path
+            .to_path()
+            .ok()
+            .and_then(|path| xattr::read(&path).ok())
+            .unwrap_or_default();
         Self {
             path,
             inode: metadata.inode(),
@@ -239,8 +456,15 @@ impl Info<Local> {
             group_id: metadata.group_id(),
             created_at: systime_to_datetime(metadata.created()),
             modified_at: systime_to_datetime(metadata.modified()),
-            data: extract_kind(metadata),
+            accessed_at: metadata.accessed().ok().map(DateTime::from),
+            changed_at: metadata
+                .status_changed()
+                .map(DateTime::from_unix_timestamp_nanos),
+            device: metadata.dev(),
+            blocks: metadata.blocks(),
+            data,
             hash: None,
+            xattrs,
         }
     }
 }
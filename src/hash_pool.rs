@@ -0,0 +1,107 @@
+//! Computing checksums of many files at once, using a pool of threads.
+//!
+//! Reading files is mostly waiting for disk, so it's done by plain threads instead of tokio tasks.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use digest::Digest;
+
+use crate::types::Checksum;
+use crate::DefaultDigest;
+
+/// Computes checksum of the file content, the same way it is computed when archiving.
+pub fn hash_file(path: &Path) -> std::io::Result<Checksum> {
+    let mut file = std::fs::File::open(path)?;
+    let mut digest = DefaultDigest::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        digest.update(&buffer[..len]);
+    }
+    Ok(Checksum::from(digest.finalize()))
+}
+
+type Job<T> = (T, PathBuf);
+type Done<T> = (T, std::io::Result<Checksum>);
+
+/// Pool of threads hashing files.
+///
+/// Every file is tagged with some `T`, which is returned back together with the checksum.
+/// Results are returned in the order they are ready, not in the order files were pushed.
+pub struct HashPool<T> {
+    jobs: Option<SyncSender<Job<T>>>,
+    results: Receiver<Done<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> HashPool<T> {
+    /// Starts `workers` threads, at least one.
+    #[must_use]
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        // Queue is short, so memory is not wasted for files that are waiting.
+        let (jobs, queue) = mpsc::sync_channel(workers);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..workers)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let done = done.clone();
+                std::thread::spawn(move || work(&queue, &done))
+            })
+            .collect();
+        HashPool {
+            jobs: Some(jobs),
+            results,
+            workers,
+        }
+    }
+
+    /// Queues file for hashing, waiting if all workers are busy.
+    pub fn push(&self, tag: T, path: PathBuf) {
+        if let Some(jobs) = &self.jobs {
+            // Workers stop only when the pool is dropped.
+            let _unused_result = jobs.send((tag, path));
+        }
+    }
+
+    /// Returns files that are hashed already, without waiting.
+    pub fn ready(&self) -> impl Iterator<Item = Done<T>> + '_ {
+        self.results.try_iter()
+    }
+
+    /// Waits until all queued files are hashed and returns them.
+    pub fn finish(mut self) -> impl Iterator<Item = Done<T>> {
+        // Workers exit when the queue is closed and empty.
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _unused_result = worker.join();
+        }
+        let results: Vec<_> = self.results.try_iter().collect();
+        results.into_iter()
+    }
+}
+
+fn work<T>(queue: &Mutex<Receiver<Job<T>>>, done: &Sender<Done<T>>) {
+    loop {
+        let job = match queue.lock() {
+            Ok(queue) => queue.recv(),
+            Err(_) => break,
+        };
+        let (tag, path) = match job {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+        let result = hash_file(&path);
+        if done.send((tag, result)).is_err() {
+            break;
+        }
+    }
+}
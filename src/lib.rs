@@ -1,14 +1,4 @@
-#![feature(
-    min_type_alias_impl_trait,
-    trait_alias,
-    type_ascription,
-    never_type,
-    exhaustive_patterns,
-    try_blocks,
-    arbitrary_enum_discriminant,
-    format_args_capture,
-    backtrace
-)]
+#![feature(never_type, exhaustive_patterns)]
 #![cfg_attr(windows, feature(windows_by_handle))]
 #![warn(clippy::pedantic, clippy::cargo)]
 #![deny(
@@ -16,6 +6,10 @@
     // Unfortunately, much more simple `missing_panics_doc` works only on public items.
     clippy::unwrap_used, clippy::expect_used, clippy::panic
 )]
+#![allow(
+    // `log!` passes every logged value as a named argument, but messages refer to them by position.
+    named_arguments_used_positionally
+)]
 #![allow(
     // Waiting for https://github.com/rust-lang/rust-clippy/issues/7422 to be available in nightly.
     clippy::nonstandard_macro_braces
@@ -35,6 +29,10 @@
     clippy::module_name_repetitions,
     // This lint is useful, but too annoying
     clippy::wildcard_imports,
+    // `match` with early return reads better to me, than `let ... else`.
+    clippy::manual_let_else,
+    // Most errors carry a backtrace, so they are large anyway.
+    clippy::result_large_err,
 )]
 
 pub use sha2::Sha256 as DefaultDigest;
@@ -43,12 +41,21 @@ pub use time::OffsetDateTime as DateTime;
 #[macro_use]
 pub mod logging;
 
+pub mod backup;
+pub mod concurrent;
 pub mod cpio;
 pub mod database;
+pub mod estimate;
+pub mod extract;
 pub mod fileext;
 pub mod fileinfo;
+pub mod hash_pool;
 pub mod packer;
 pub mod path;
+pub mod restore;
+pub mod retention;
 pub mod serde_b64;
+pub mod storage;
 pub mod stream_hash;
 pub mod types;
+pub mod xattr;
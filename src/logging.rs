@@ -69,7 +69,7 @@ pub fn get_log(
 #[allow(clippy::unwrap_used)]
 pub fn write_log(this: &'static Mutex<Logging>, data: &[u8]) {
     let mut this = this.lock().unwrap();
-    this.json.write_all(&[b'\n']).unwrap();
+    this.json.write_all(b"\n").unwrap();
     this.json.write_all(data).unwrap();
     this.json.flush().unwrap();
 }
@@ -142,7 +142,7 @@ macro_rules! log {
             let ser = ::serde_json::to_vec(&s).unwrap();
             for group in [
                 $(
-                    $crate::logging::get_log(&crate::logging::groups::$group, stringify!($group))
+                    $crate::logging::get_log(&$crate::logging::groups::$group, stringify!($group))
                 ),*
             ].iter() {
                 $crate::logging::write_log(group, &ser);
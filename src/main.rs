@@ -1,17 +1,19 @@
-#![feature(backtrace)]
-
+use colbak_lib::backup::{backup, BackupOptions};
 use colbak_lib::cpio::reader::NextItem;
-use colbak_lib::cpio::Archive;
-use colbak_lib::database::{Database, SqlName};
-use colbak_lib::fileinfo::{Info, UnspecifiedInfo};
-use colbak_lib::path::Local;
-use colbak_lib::stream_hash::stream_hash;
-use colbak_lib::types::Checksum;
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::database::{Database, Hashing, SqlName};
+use colbak_lib::estimate::{estimate, Prices};
+use colbak_lib::extract::{extract, Existing, ExtractOptions, Owners};
+use colbak_lib::fileinfo::Info;
+use colbak_lib::packer::Strategy;
+use colbak_lib::path::EscapedString;
+use colbak_lib::restore::{restore, Patterns, RestoreOptions};
+use colbak_lib::retention;
+use colbak_lib::storage::StorageClass;
 use std::convert::Infallible;
 use std::error::Error as StdError;
 use std::io::Cursor;
 use std::path::PathBuf;
-use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 
 use structopt::StructOpt;
@@ -20,25 +22,195 @@ use structopt::StructOpt;
 #[structopt(name = "colbak")]
 enum Opt {
     /// Reads list of files from stdin and output archive into stdout.
-    CreateCpio,
+    CreateCpio {
+        /// Header format: `binary` (old binary format) or `newc` (SVR4 portable format).
+        #[structopt(long, default_value = "binary")]
+        format: Format,
+        /// Place restore script and manifest before files.
+        #[structopt(long)]
+        script: bool,
+    },
     /// Reads archive from stdin and extracts files
     UnpackCpio {
         /// Where extracted files will be located.
         output: PathBuf,
+        /// Gives files of the archived user to the local one, like `1000=alice`.
+        #[structopt(long)]
+        map_user: Vec<String>,
+        /// Same as `--map-user`, but for groups.
+        #[structopt(long)]
+        map_group: Vec<String>,
+        /// What to do with files that already exist:
+        /// `skip`, `overwrite`, `keep-both` (extract under another name) or `if-newer`.
+        #[structopt(long, default_value = "skip")]
+        existing: Existing,
     },
     /// Reads archive from stdin and lists files
     ListCpio,
     /// Creates a snapshot of specified directory
-    CreateSnapshot { database: PathBuf, root: PathBuf },
+    CreateSnapshot {
+        database: PathBuf,
+        root: PathBuf,
+        /// Hash content of files using this number of threads.
+        /// Checksums of unchanged files are taken from the last uploaded snapshot.
+        #[structopt(long)]
+        hash_workers: Option<usize>,
+    },
     /// Computes difference between snapshots
     DiffSnapshot { database: PathBuf, before: String, after: String },
+    /// Creates a snapshot and uploads everything changed since the last uploaded one
+    Backup {
+        database: PathBuf,
+        root: PathBuf,
+        /// Either `s3://bucket/prefix` or path to the local directory.
+        target: String,
+        /// Files smaller than this (in bytes) are grouped into single archive.
+        #[structopt(long, default_value = "1048576")]
+        min_size: u64,
+        /// Archives are never larger than this (in bytes), larger files are skipped.
+        #[structopt(long, default_value = "68719476736")]
+        max_size: u64,
+        /// How small files are grouped: `directory`, `extension` or `age`.
+        #[structopt(long, default_value = "directory")]
+        strategy: Strategy,
+        /// Approximate amount of memory (in bytes) used for packing files.
+        #[structopt(long, default_value = "67108864")]
+        memory_limit: u64,
+        /// Hash content of files using this number of threads while creating the snapshot.
+        /// Checksums of unchanged files are reused, so they are never read again.
+        #[structopt(long, default_value = "4")]
+        hash_workers: usize,
+        /// Do not hash files while creating the snapshot.
+        /// Changed files are not deduplicated then, since their content is unknown before uploading.
+        #[structopt(long)]
+        no_hash: bool,
+        /// Maximum number of requests made to the storage.
+        /// Files that don't fit are uploaded by the next backups.
+        #[structopt(long)]
+        max_requests: Option<u64>,
+        /// Number of archives uploaded at once.
+        #[structopt(long, default_value = "4")]
+        concurrency: usize,
+        /// S3 storage class, like `standard` or `deep-archive`.
+        #[structopt(long, default_value = "standard")]
+        storage_class: StorageClass,
+        /// Header format: `binary` (old binary format) or `newc` (SVR4 portable format).
+        #[structopt(long, default_value = "binary")]
+        format: Format,
+        /// Place restore script and manifest into every archive,
+        /// so files can be restored without colbak.
+        #[structopt(long)]
+        script: bool,
+    },
+    /// Estimates cost of uploading changes between snapshots and prints it as JSON.
+    /// Only checksums stored in the snapshot are used for deduplication
+    Estimate {
+        database: PathBuf,
+        /// Snapshot that would be uploaded.
+        after: String,
+        /// Storage archives would be uploaded to, like in `backup`.
+        /// Nothing is uploaded, it's only needed for the part size.
+        target: String,
+        /// Snapshot that is already uploaded. By default the last uploaded one is used.
+        #[structopt(long)]
+        before: Option<String>,
+        /// Files smaller than this (in bytes) are grouped into single archive.
+        #[structopt(long, default_value = "1048576")]
+        min_size: u64,
+        /// Archives are never larger than this (in bytes), larger files are skipped.
+        #[structopt(long, default_value = "68719476736")]
+        max_size: u64,
+        /// How small files are grouped: `directory`, `extension` or `age`.
+        #[structopt(long, default_value = "directory")]
+        strategy: Strategy,
+        /// Approximate amount of memory (in bytes) used for packing files.
+        #[structopt(long, default_value = "67108864")]
+        memory_limit: u64,
+        /// S3 storage class, like `standard` or `deep-archive`.
+        #[structopt(long, default_value = "standard")]
+        storage_class: StorageClass,
+        /// Header format: `binary` (old binary format) or `newc` (SVR4 portable format).
+        #[structopt(long, default_value = "binary")]
+        format: Format,
+        /// Place restore script and manifest into every archive.
+        #[structopt(long)]
+        script: bool,
+        /// JSON file with price table, replacing default prices of given classes.
+        #[structopt(long)]
+        prices: Option<PathBuf>,
+    },
+    /// Marks snapshots as expired, so their files may be deleted by `prune`.
+    /// The last uploaded snapshot is never expired
+    ExpireSnapshot {
+        database: PathBuf,
+        snapshots: Vec<String>,
+    },
+    /// Deletes archives that are not needed for any snapshot that is not expired,
+    /// once their minimum storage duration is over. Prints the plan as JSON
+    Prune {
+        database: PathBuf,
+        /// Either `s3://bucket/prefix` or path to the local directory.
+        target: String,
+        /// JSON file with price table, replacing default prices of given classes.
+        #[structopt(long)]
+        prices: Option<PathBuf>,
+        /// Only print the plan, do not delete anything.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Restores files from the snapshot, downloading only archives that contain them
+    Restore {
+        database: PathBuf,
+        snapshot: String,
+        /// Either `s3://bucket/prefix` or path to the local directory.
+        target: String,
+        /// Where restored files will be located.
+        output: PathBuf,
+        /// Globs matched against full paths of files, like `/home/*/docs/**`.
+        /// When none are given, everything is restored.
+        patterns: Vec<String>,
+        /// Number of archives downloaded at once.
+        #[structopt(long, default_value = "4")]
+        concurrency: usize,
+        /// Gives files of the archived user to the local one, like `1000=alice`.
+        #[structopt(long)]
+        map_user: Vec<String>,
+        /// Same as `--map-user`, but for groups.
+        #[structopt(long)]
+        map_group: Vec<String>,
+        /// What to do with files that already exist:
+        /// `skip`, `overwrite`, `keep-both` (restore under another name) or `if-newer`.
+        #[structopt(long, default_value = "skip")]
+        existing: Existing,
+    },
+}
+
+/// Reads price table from the JSON file, if it is given.
+async fn load_prices(path: Option<PathBuf>) -> Result<Prices, Box<dyn StdError>> {
+    match path {
+        Some(path) => Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?),
+        None => Ok(Prices::default()),
+    }
+}
+
+/// Builds owners mapping from `--map-user` and `--map-group` options.
+fn owners(users: &[String], groups: &[String]) -> Result<Owners, Box<dyn StdError>> {
+    let mut owners = Owners::default();
+    for mapping in users {
+        owners.map_user(mapping)?;
+    }
+    for mapping in groups {
+        owners.map_group(mapping)?;
+    }
+    Ok(owners)
 }
 
 async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
     match opt {
-        Opt::CreateCpio => {
+        Opt::CreateCpio { format, script } => {
             let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
-            let mut archive = Archive::new();
+            let mut archive = Archive::with_format(format);
+            archive.set_script(script);
             while let Some(line) = stdin.next_line().await? {
                 let path = PathBuf::from(line);
                 let info = Info::new(path).await?;
@@ -76,74 +248,45 @@ async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
                 }
             }
         }
-        Opt::UnpackCpio { output } => {
-            let stdin = tokio::io::stdin();
-            let mut archive = colbak_lib::cpio::Reader::new(stdin);
-            let mut hashes = Vec::new();
-            loop {
-                match archive.advance().await? {
-                    NextItem::File(file) => {
-                        let mut info = file.info();
-                        let path = info.path.clone().cast::<Local>().to_path()?;
-                        let dst = output.join(path);
-                        println!("Extracting {:?}...", dst);
-                        match info.data {
-                            UnspecifiedInfo::Dir(_) => {
-                                tokio::fs::create_dir(&dst).await?;
-                                archive = file.to_void().await?;
-                            }
-                            UnspecifiedInfo::File(_) => {
-                                let output = File::create(dst).await?;
-                                let mut hasher = stream_hash(output);
-                                archive = file.drain_to(&mut hasher).await?;
-                                let hash: Checksum = hasher.finalize().into();
-                                info.hash = Some(hash);
-                            }
-                            UnspecifiedInfo::Unknown(_) => {
-                                println!("\tSkipping unknown file.");
-                                archive = file.to_void().await?;
-                            }
-                        }
-                        hashes.push(info);
-                    }
-                    NextItem::End(end) => {
-                        let files = match end.files {
-                            Some(files) => files,
-                            None => break Ok(()),
-                        };
-                        for (expected, found) in files.into_iter().zip(hashes.into_iter()) {
-                            let total_match = expected == found;
-                            if total_match {
-                                continue;
-                            }
-                            if expected.path != found.path {
-                                eprintln!(
-                                    "Warning: path mismatch. Expected {:?}, found {:?}",
-                                    expected.path, found.path
-                                );
-                            }
-                            let hash_match = expected
-                                .hash
-                                .zip(found.hash)
-                                .map(|(x, y)| x == y)
-                                .unwrap_or(true);
-                            if !hash_match {
-                                eprintln!(
-                                    "Warning: hash mismatch at {:?}. Expected {:?}, found {:?}",
-                                    found.path, expected.hash, found.hash
-                                );
-                            }
-                        }
-                        break Ok(());
-                    }
-                }
+        Opt::UnpackCpio {
+            output,
+            map_user,
+            map_group,
+            existing,
+        } => {
+            let options = ExtractOptions {
+                owners: owners(&map_user, &map_group)?,
+                existing,
+            };
+            let report = extract(tokio::io::stdin(), &output, &options).await?;
+            for path in &report.mismatches {
+                eprintln!("Warning: checksum of {} does not match", path.escaped());
             }
+            println!(
+                "Extracted {} entries, skipped {}, {} warnings",
+                report.entries,
+                report.skipped.len(),
+                report.warnings
+            );
+            Ok(())
         }
-        Opt::CreateSnapshot { database, root } => {
+        Opt::CreateSnapshot {
+            database,
+            root,
+            hash_workers,
+        } => {
             let mut database = colbak_lib::database::Database::open(database)?;
+            let reuse_from = database.last_uploaded_snapshot()?;
             let name = SqlName::now();
             let mut snapshot = database.open_snapshot(name)?;
-            snapshot.filler()?.fill(&root)?.save()?;
+            let filler = match hash_workers {
+                Some(workers) => snapshot.hashing_filler(Hashing {
+                    workers,
+                    reuse_from,
+                })?,
+                None => snapshot.filler()?,
+            };
+            filler.fill(&root)?.save()?;
             println!("Created snapshot {}", snapshot.name());
             Ok(())
         },
@@ -158,12 +301,205 @@ async fn entry_point(opt: Opt) -> Result<(), Box<dyn StdError>> {
             })??;
             Ok(())
         }
+        Opt::Backup {
+            database,
+            root,
+            target,
+            min_size,
+            max_size,
+            strategy,
+            memory_limit,
+            hash_workers,
+            no_hash,
+            max_requests,
+            concurrency,
+            storage_class,
+            format,
+            script,
+        } => {
+            let mut database = Database::open(database)?;
+            let (storage, prefix) = colbak_lib::storage::open(&target)?;
+            let options = BackupOptions {
+                min_size,
+                max_size,
+                strategy,
+                memory_limit,
+                hash_workers: if no_hash { None } else { Some(hash_workers) },
+                max_requests,
+                concurrency,
+                storage_class,
+                format,
+                script,
+                prefix,
+            };
+            let report = backup(&mut database, &root, storage.as_ref(), &options).await?;
+            if report.oversized > 0 {
+                for (path, size) in database.oversized_files(&report.snapshot)? {
+                    eprintln!(
+                        "Warning: {} is skipped, it's too large ({} bytes)",
+                        path.escaped(),
+                        size
+                    );
+                }
+            }
+            println!(
+                "Created snapshot {} (relative to {}), uploaded {} archives, {} files were already stored",
+                report.snapshot,
+                report.base,
+                report.archives.len(),
+                report.deduplicated
+            );
+            if report.moved > 0 {
+                println!(
+                    "{} files were moved, only new paths are recorded",
+                    report.moved
+                );
+            }
+            if report.resumed > 0 {
+                println!(
+                    "{} files deferred by previous backups were included",
+                    report.resumed
+                );
+            }
+            if report.deferred > 0 {
+                println!(
+                    "{} files are deferred to the next backup, since request limit is reached",
+                    report.deferred
+                );
+            }
+            Ok(())
+        }
+        Opt::Estimate {
+            database,
+            after,
+            target,
+            before,
+            min_size,
+            max_size,
+            strategy,
+            memory_limit,
+            storage_class,
+            format,
+            script,
+            prices,
+        } => {
+            let mut database = Database::open(database)?;
+            let before = match before {
+                Some(before) => SqlName::new(before)?,
+                None => match database.last_uploaded_snapshot()? {
+                    Some(before) => before,
+                    None => database.empty_snapshot()?,
+                },
+            };
+            let prices = load_prices(prices).await?;
+            let options = BackupOptions {
+                min_size,
+                max_size,
+                strategy,
+                memory_limit,
+                storage_class,
+                format,
+                script,
+                ..BackupOptions::default()
+            };
+            let before = database.readonly_snapshot(before)?;
+            let after = database.readonly_snapshot(SqlName::new(after)?)?;
+            let diff = database.compare_snapshots(&before, &after)?;
+            let (storage, _) = colbak_lib::storage::open(&target)?;
+            let estimate = estimate(&database, &diff, &options, storage.part_size(), &prices)?;
+            println!("{}", serde_json::to_string_pretty(&estimate)?);
+            Ok(())
+        }
+        Opt::ExpireSnapshot {
+            database,
+            snapshots,
+        } => {
+            let database = Database::open(database)?;
+            for snapshot in snapshots {
+                database.expire_snapshot(&SqlName::new(snapshot)?)?;
+            }
+            Ok(())
+        }
+        Opt::Prune {
+            database,
+            target,
+            prices,
+            dry_run,
+        } => {
+            let database = Database::open(database)?;
+            let prices = load_prices(prices).await?;
+            let plan = retention::plan(&database, &prices, colbak_lib::DateTime::now_utc())?;
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+            if !dry_run {
+                let (storage, _) = colbak_lib::storage::open(&target)?;
+                retention::apply(&database, storage.as_ref(), &plan).await?;
+            }
+            Ok(())
+        }
+        Opt::Restore {
+            database,
+            snapshot,
+            target,
+            output,
+            patterns,
+            concurrency,
+            map_user,
+            map_group,
+            existing,
+        } => {
+            let database = Database::open(database)?;
+            let (storage, _) = colbak_lib::storage::open(&target)?;
+            let patterns = Patterns::new(&patterns)?;
+            let snapshot = SqlName::new(snapshot)?;
+            let options = RestoreOptions {
+                concurrency,
+                owners: owners(&map_user, &map_group)?,
+                existing,
+            };
+            let report = restore(
+                &database,
+                snapshot,
+                &patterns,
+                storage.as_ref(),
+                &output,
+                &options,
+            )
+            .await?;
+            for path in &report.missing {
+                eprintln!("Warning: {} is not found in any archive", path.escaped());
+            }
+            println!(
+                "Restored {} files from {} archives, skipped {}, {} warnings",
+                report.files,
+                report.archives.len(),
+                report.skipped.len(),
+                report.warnings
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Only errors that capture a backtrace are listed here.
+fn backtrace<'a>(err: &'a (dyn StdError + 'static)) -> Option<&'a snafu::Backtrace> {
+    use colbak_lib::cpio::reader::{ReadError, ReadingError};
+    use snafu::ErrorCompat;
+    if let Some(e) = err.downcast_ref::<colbak_lib::database::Error>() {
+        ErrorCompat::backtrace(e)
+    } else if let Some(e) = err.downcast_ref::<colbak_lib::storage::Error>() {
+        ErrorCompat::backtrace(e)
+    } else if let Some(e) = err.downcast_ref::<ReadingError>() {
+        ErrorCompat::backtrace(e)
+    } else if let Some(e) = err.downcast_ref::<ReadError>() {
+        ErrorCompat::backtrace(e)
+    } else {
+        None
     }
 }
 
-fn show_bt(err: &dyn StdError) {
+fn show_bt(err: &(dyn StdError + 'static)) {
     println!("# {}", err);
-    match err.backtrace() {
+    match backtrace(err) {
         Some(trace) => eprintln!("{}", trace),
         None => eprintln!("\nTrace missing :("),
     }
@@ -1,43 +1,96 @@
-use std::cell::RefCell;
-use std::collections::BinaryHeap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+use std::str::FromStr;
 
 use bumpalo::Bump;
 use radix_trie::Trie;
 use smallvec::SmallVec;
+use snafu::Snafu;
 
+use crate::cpio::{Archive, Format};
 use crate::database::{Diff, DiffRow, DiffType, RowId};
-pub struct Packed(pub Vec<SmallVec<[RowId; 4]>>);
+use crate::estimate::upload_requests;
+use crate::path::{EncodedPath, EscapedString, External, PathKind};
+use crate::DateTime;
+
+/// Result of [`pack`](pack).
+pub struct Packed {
+    /// Files that should be placed in the same archive.
+    pub packs: Vec<SmallVec<[RowId; 4]>>,
+    /// Number of files that are too large to be archived at all.
+    /// They are listed by [`for_each_oversized`](for_each_oversized).
+    pub oversized: usize,
+}
+
+/// File that is larger than maximum archive size.
+#[derive(Debug, Clone)]
+pub struct Oversized {
+    pub rowid: RowId,
+    pub path: EncodedPath<External>,
+    pub size: u64,
+}
+
+/// Logs that the file is skipped, since it's larger than `max_size`.
+fn skip_oversized(path: &EncodedPath<External>, size: u64, max_size: u64) {
+    log!(warn: "Skipping {}: it's size {} is larger than {}", path = path.escaped(), size, max_size);
+}
 
 struct File<'a> {
     rowid: RowId,
     size: u64,
     directory: &'a Directory<'a>,
+    /// Whether file is placed into some pack already.
+    packed: Cell<bool>,
 }
 
-impl PartialEq for File<'_> {
-    fn eq(&self, other: &Self) -> bool {
-        self.size.eq(&other.size)
-    }
+struct Directory<'a> {
+    parent: Option<&'a Directory<'a>>,
+    /// Sorted from the largest to the smallest.
+    files: RefCell<Vec<&'a File<'a>>>,
+    subdirs: RefCell<Vec<&'a Directory<'a>>>,
 }
 
-impl PartialOrd for File<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.size.partial_cmp(&other.size)
-    }
+/// Pack that is being filled.
+struct Pack<'a> {
+    files: SmallVec<[&'a File<'a>; 4]>,
+    total: u64,
 }
 
-impl Eq for File<'_> {}
+impl<'a> Pack<'a> {
+    fn new(first: &'a File<'a>) -> Self {
+        first.packed.set(true);
+        Pack {
+            files: smallvec::smallvec![first],
+            total: first.size,
+        }
+    }
 
-impl Ord for File<'_> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.size.cmp(&other.size)
+    /// Adds file to the pack, unless it is packed already or pack exceeds the `limits`.
+    fn try_add(&mut self, file: &'a File<'a>, limits: &PackLimits) {
+        if file.packed.get()
+            || self.total + file.size > limits.max_size
+            || self.files.len() >= limits.max_files()
+        {
+            return;
+        }
+        file.packed.set(true);
+        self.files.push(file);
+        self.total += file.size;
     }
-}
 
-struct Directory<'a> {
-    parent: Option<&'a Directory<'a>>,
-    files: RefCell<BinaryHeap<&'a File<'a>>>,
-    subdirs: RefCell<Vec<&'a Directory<'a>>>,
+    /// Adds files from `candidates` until pack is at least `min_size` bytes.
+    fn fill<I>(&mut self, candidates: I, limits: &PackLimits)
+    where
+        I: IntoIterator<Item = &'a File<'a>>,
+    {
+        for file in candidates {
+            if self.total >= limits.min_size {
+                break;
+            }
+            self.try_add(file, limits);
+        }
+    }
 }
 
 fn find_related_directories<'a>(file: &'a File<'a>) -> impl Iterator<Item = &'a Directory<'a>> {
@@ -69,106 +122,470 @@ fn find_related_directories<'a>(file: &'a File<'a>) -> impl Iterator<Item = &'a
     result.into_iter()
 }
 
-#[allow(clippy::missing_panics_doc)]
-pub fn pack(diff: &Diff, min_size: u64) -> Result<Packed, crate::database::Error> {
-    let arena = Bump::new();
+/// Rough estimate of memory used by a single file while it is being packed.
+const CANDIDATE_MEMORY: u64 = 512;
+
+/// Rough estimate of memory used by a single file in the [`Archive`](Archive) while it is uploaded,
+/// including it's entry in the trailer.
+const ARCHIVED_MEMORY: u64 = 1024;
+
+/// Constraints that every pack should satisfy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackLimits {
+    /// Files smaller than this are grouped together.
+    pub min_size: u64,
+    /// Total size of files in a single pack.
+    pub max_size: u64,
+    /// Approximate amount of memory that packing and uploading can use, in bytes.
+    ///
+    /// Half of it is used for files that are grouped at once,
+    /// and another half limits number of files in a single archive.
+    /// Only row ids of packed files are kept for the whole diff, 8 bytes per file.
+    pub memory: u64,
+}
+
+impl PackLimits {
+    /// Limits with the default memory usage of 64MB.
+    #[must_use]
+    pub fn new(min_size: u64, max_size: u64) -> Self {
+        PackLimits {
+            min_size,
+            max_size,
+            memory: 64 * 1024 * 1024,
+        }
+    }
+
+    /// Maximum number of files that are passed to the [strategy](PackStrategy) at once.
+    ///
+    /// ```
+    /// # use colbak_lib::packer::PackLimits;
+    /// let limits = PackLimits { memory: 1024 * 1024, ..PackLimits::new(1, 2) };
+    /// assert_eq!(limits.max_candidates(), 1024);
+    /// assert_eq!(limits.max_files(), 512);
+    /// ```
+    #[must_use]
+    pub fn max_candidates(&self) -> usize {
+        to_count(self.memory / 2 / CANDIDATE_MEMORY)
+    }
+
+    /// Maximum number of files in a single pack.
+    #[must_use]
+    pub fn max_files(&self) -> usize {
+        to_count(self.memory / 2 / ARCHIVED_MEMORY)
+    }
+}
+
+/// Converts number of files to `usize`, but never returns zero.
+fn to_count(count: u64) -> usize {
+    usize::try_from(count).unwrap_or(usize::MAX).max(1)
+}
+
+/// Small file that should be grouped with other ones.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub rowid: RowId,
+    pub size: u64,
+    pub path: EncodedPath<External>,
+    pub modified_at: DateTime,
+}
+
+/// Decides which of the small files are placed into the same archive.
+pub trait PackStrategy {
+    /// Groups `files` into packs, so total size of each one is between
+    /// [`min_size`](PackLimits::min_size) and [`max_size`](PackLimits::max_size) when possible.
+    /// Every file must be placed into some pack,
+    /// and no pack can have more than [`max_files`](PackLimits::max_files).
+    ///
+    /// Each of the `files` is smaller than `min_size` and not larger than `max_size`.
+    /// Note that large diffs are grouped in parts, see [`pack`](pack).
+    fn group(&self, files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>>;
+}
+
+/// Keeps files from the same or nearby directories together.
+///
+/// Each pack starts from the largest file that is not packed yet,
+/// and is filled with files from related directories first, larger ones preferred.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByDirectory;
+
+impl PackStrategy for ByDirectory {
+    fn group(&self, files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+        let arena = Bump::new();
+
+        // First, we want to fill files and directories.
+        let mut directories = Trie::new();
+        let mut packable = Vec::with_capacity(files.len());
+        for candidate in files {
+            let mut parent = None;
+            for key in candidate.path.prefixes() {
+                let dir = directories.get(key).copied().unwrap_or_else(|| {
+                    let dir = arena.alloc(Directory {
+                        parent,
+                        files: RefCell::new(Vec::new()),
+                        subdirs: RefCell::new(Vec::new()),
+                    });
+                    if let Some(parent) = parent {
+                        parent.subdirs.borrow_mut().push(dir);
+                    }
+                    let dir: &Directory = &*dir;
+                    directories.insert(key.to_vec(), dir);
+                    dir
+                });
+                parent = Some(dir);
+            }
+            #[allow(clippy::expect_used)]
+            let parent = parent.expect("prefixes() always returns empty string first.");
+
+            let file = arena.alloc(File {
+                rowid: candidate.rowid,
+                size: candidate.size,
+                directory: parent,
+                packed: Cell::new(false),
+            });
+            packable.push(&*file);
+        }
+        std::mem::drop(directories);
+        let mut files = packable;
+        files.sort_by_key(|f| Reverse(f.size));
+        for &file in &files {
+            file.directory.files.borrow_mut().push(file);
+        }
+
+        // Now we can really make packs
+        let mut result = Vec::new();
+        let mut next = 0;
+        loop {
+            // Largest file that is not packed yet starts a new pack.
+            while files.get(next).is_some_and(|f| f.packed.get()) {
+                next += 1;
+            }
+            let largest = match files.get(next) {
+                Some(&x) => x,
+                None => break,
+            };
+            let mut pack = Pack::new(largest);
+
+            // Then it is filled with files from related directories.
+            for dir in find_related_directories(largest) {
+                pack.fill(dir.files.borrow().iter().copied(), limits);
+            }
+            // If they are not enough, any other files are taken.
+            pack.fill(files[next..].iter().copied(), limits);
+
+            result.push(pack.files.iter().map(|f| f.rowid).collect());
+        }
+        result
+    }
+}
+
+/// Keeps files with the same extension together, since they are often changed together.
+///
+/// Extension is compared case-insensitively, files without one are grouped too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByExtension;
+
+impl PackStrategy for ByExtension {
+    fn group(&self, mut files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+        files.sort_by_cached_key(|f| (extension(&f.path), Reverse(f.size)));
+        pack_in_order(files, limits)
+    }
+}
+
+/// Keeps files modified at about the same time together,
+/// so archives with rarely changed files are rarely obsoleted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByAge;
+
+impl PackStrategy for ByAge {
+    fn group(&self, mut files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+        files.sort_by_key(|f| f.modified_at);
+        pack_in_order(files, limits)
+    }
+}
+
+/// Lowercase extension of the file name, empty if there is no extension.
+///
+/// ```
+/// # use colbak_lib::packer::extension;
+/// # use colbak_lib::path::EncodedPath;
+/// let path = |x: &str| EncodedPath::from_vec(x.as_bytes().to_vec());
+/// assert_eq!(extension(&path("/home/Photo.JPG")), b"jpg");
+/// assert_eq!(extension(&path("/home/.bashrc")), b"");
+/// assert_eq!(extension(&path("/home/some.dir/file")), b"");
+/// ```
+#[must_use]
+pub fn extension<P: PathKind>(path: &EncodedPath<P>) -> Vec<u8> {
+    let path = path.as_bytes();
+    let name = match path.iter().rposition(|&x| x == b'/') {
+        Some(idx) => &path[idx + 1..],
+        None => path,
+    };
+    match name.iter().rposition(|&x| x == b'.') {
+        // Leading dot marks hidden file, not the extension.
+        Some(idx) if idx > 0 => name[idx + 1..].to_ascii_lowercase(),
+        _ => Vec::new(),
+    }
+}
+
+/// Packs `files` in the given order, starting a new pack
+/// when the current one is at least `min_size` bytes or next file does not fit.
+fn pack_in_order(files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+    let mut result = Vec::new();
+    let mut pack = SmallVec::new();
+    let mut total = 0;
+    for file in files {
+        let is_full = total >= limits.min_size
+            || total + file.size > limits.max_size
+            || pack.len() >= limits.max_files();
+        if !pack.is_empty() && is_full {
+            result.push(std::mem::take(&mut pack));
+            total = 0;
+        }
+        pack.push(file.rowid);
+        total += file.size;
+    }
+    if !pack.is_empty() {
+        result.push(pack);
+    }
+    result
+}
+
+/// Strategy that can be chosen by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Default)]
+pub enum Strategy {
+    #[default]
+    Directory,
+    Extension,
+    Age,
+}
+
+
+impl PackStrategy for Strategy {
+    fn group(&self, files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+        match self {
+            Strategy::Directory => ByDirectory.group(files, limits),
+            Strategy::Extension => ByExtension.group(files, limits),
+            Strategy::Age => ByAge.group(files, limits),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Unknown packing strategy `{}`, expected `directory`, `extension` or `age`",
+    name
+))]
+pub struct UnknownStrategy {
+    name: String,
+}
+
+impl FromStr for Strategy {
+    type Err = UnknownStrategy;
 
-    // First, we want to fill files and directories.
-    let mut directories = Trie::new();
-    let mut files = BinaryHeap::new();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directory" | "dir" => Ok(Strategy::Directory),
+            "extension" | "ext" => Ok(Strategy::Extension),
+            "age" => Ok(Strategy::Age),
+            _ => Err(UnknownStrategy { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Groups files from the `diff` into packs, each of them becomes a single archive.
+///
+/// Created or changed files smaller than `min_size` are grouped together by the `strategy`,
+/// so total size of the pack is between `min_size` and `max_size`.
+/// Directories, symlinks, named pipes and devices are grouped the same way, with zero size,
+/// so archives can be extracted without the index.
+/// Only the last packs can be smaller, when there are not enough small files left.
+/// Files are read from the database ordered by path, and passed to the strategy
+/// in parts of [`max_candidates`](PackLimits::max_candidates), so memory usage is bounded.
+///
+/// Created or changed files which are at least `min_size` bytes become packs on their own.
+/// Files larger than `max_size` are not packed at all, see [`Packed::oversized`](Packed::oversized).
+///
+/// Changed files are archived with their contents even when only metadata changed,
+/// so the latest archive of the file has it's current metadata. But when their checksums
+/// are known, backup [deduplicates](crate::backup::BackupOptions::hash_workers) them,
+/// and new metadata is recorded only in the snapshot.
+pub fn pack(
+    diff: &Diff,
+    strategy: &dyn PackStrategy,
+    limits: &PackLimits,
+) -> Result<Packed, crate::database::Error> {
+    let PackLimits {
+        min_size, max_size, ..
+    } = *limits;
+    let mut result = Vec::new();
+    let mut small = Vec::new();
+    let mut oversized = 0;
     let Ok(()) = diff
         .query()
-        .only_kind(DiffType::Changed)
+        .deny_kind(DiffType::Deleted)
+        .deny_kind(DiffType::Moved)
         .less_than(min_size)
+        .ordered_by_path()
         .for_each::<_, !>(|row| {
-            if let DiffRow::Changed {
-                rowid, size, path, ..
-            } = row
-            {
-                let mut parent = None;
-                for key in path.prefixes() {
-                    let dir = directories.get(key).copied().unwrap_or_else(|| {
-                        let dir = arena.alloc(Directory {
-                            parent,
-                            files: RefCell::new(BinaryHeap::new()),
-                            subdirs: RefCell::new(Vec::new()),
-                        });
-                        if let Some(parent) = parent {
-                            parent.subdirs.borrow_mut().push(dir);
-                        }
-                        let dir: &Directory = &*dir;
-                        directories.insert(key.to_vec(), dir);
-                        dir
-                    });
-                    parent = Some(dir);
+            let (rowid, size, path, after) = match row {
+                DiffRow::Created {
+                    rowid,
+                    size,
+                    path,
+                    after,
                 }
-                #[allow(clippy::expect_used)]
-                let parent = parent.expect("prefixes() always returns empty string first.");
-
-                let file = arena.alloc(File {
+                | DiffRow::Changed {
                     rowid,
                     size,
-                    directory: parent,
-                });
-                let file = &*file;
-                parent.files.borrow_mut().push(file);
-                files.push(file);
+                    path,
+                    after,
+                    ..
+                } => (rowid, size, path, after),
+                DiffRow::Deleted { .. } | DiffRow::Moved { .. } => return Ok(()),
+            };
+            if size > max_size {
+                skip_oversized(&path, size, max_size);
+                oversized += 1;
+                return Ok(());
+            }
+            small.push(Candidate {
+                rowid,
+                size,
+                path,
+                modified_at: after.modified_at,
+            });
+            if small.len() >= limits.max_candidates() {
+                result.extend(strategy.group(std::mem::take(&mut small), limits));
             }
             Ok(())
         })?;
-    std::mem::drop(directories);
-
-    // Now we can really make packs
-    let mut result = Vec::new();
-    let mut top = Vec::new();
-    for size_of_pack in 2.. {
-        // Searching for top-N smallest files in related directories
-        let largest = match files.pop() {
-            Some(x) => x,
-            None => break,
-        };
-        let files_to_add = size_of_pack - 1;
-        let mut min_size = u64::MAX;
-        for dir in find_related_directories(largest) {
-            for &file in dir.files.borrow().iter() {
-                if top.len() < files_to_add {
-                    top.push(file);
-                    continue;
-                }
-                if file.size >= min_size {
-                    continue;
-                }
-                min_size = file.size;
-
-                let to_remove = top
-                    .iter()
-                    .enumerate()
-                    .rev()
-                    .find(|(_, f)| f.size > min_size);
-                debug_assert!(to_remove.is_some());
-                if let Some((idx, _)) = to_remove {
-                    top.remove(idx);
-                }
-                top.push(file);
-            }
-        }
-
-        // Then creating a pack from them.
-        let mut pack: SmallVec<_> = top.drain(..).map(|f| f.rowid).collect();
-        pack.push(largest.rowid);
-        result.push(pack);
-    }
+    result.extend(strategy.group(small, limits));
 
     // Finally, we should add bigger files that were skippped earlier.
     let Ok(()) = diff
         .query()
-        .only_kind(DiffType::Created)
+        .deny_kind(DiffType::Deleted)
+        .deny_kind(DiffType::Moved)
         .larger_or_eq(min_size)
         .for_each::<_, !>(|row| {
-            if let DiffRow::Created { rowid, .. } = row {
-                result.push(smallvec::smallvec![rowid]);
+            if let DiffRow::Created {
+                rowid, size, path, ..
+            }
+            | DiffRow::Changed {
+                rowid, size, path, ..
+            } = row
+            {
+                if size > max_size {
+                    skip_oversized(&path, size, max_size);
+                    oversized += 1;
+                } else {
+                    result.push(smallvec::smallvec![rowid]);
+                }
             }
             Ok(())
         })?;
-    Ok(Packed(result))
+    Ok(Packed {
+        packs: result,
+        oversized,
+    })
+}
+
+/// Calls `func` for every created or changed file which is larger than `max_size`,
+/// so it's never [packed](pack). Files are read from the database one by one.
+pub fn for_each_oversized<F, E>(
+    diff: &Diff,
+    max_size: u64,
+    mut func: F,
+) -> Result<Result<(), E>, crate::database::Error>
+where
+    F: FnMut(Oversized) -> Result<(), E>,
+{
+    let min_size = match max_size.checked_add(1) {
+        Some(size) => size,
+        None => return Ok(Ok(())),
+    };
+    diff.query()
+        .deny_kind(DiffType::Deleted)
+        .deny_kind(DiffType::Moved)
+        .larger_or_eq(min_size)
+        .for_each(|row| match row {
+            DiffRow::Created {
+                rowid, size, path, ..
+            }
+            | DiffRow::Changed {
+                rowid, size, path, ..
+            } => func(Oversized { rowid, path, size }),
+            DiffRow::Deleted { .. } | DiffRow::Moved { .. } => Ok(()),
+        })
+}
+
+/// Makes sure that uploading all packs takes at most `max_requests` requests.
+///
+/// Packs are given together with sizes of their archives, see [`Archive::size`](Archive::size).
+/// First, the smallest packs are merged, as long as merged ones fit into `limits`.
+/// If it is not enough, packs that do not fit are removed from `sized` and returned,
+/// so they can be uploaded later. Request count is the same as in [`estimate`](crate::estimate),
+/// depending on the [`part_size`](crate::storage::Storage::part_size) of the storage.
+/// Remaining packs are ordered from the largest.
+pub fn fit_budget(
+    sized: &mut Vec<(u64, SmallVec<[RowId; 4]>)>,
+    limits: &PackLimits,
+    part_size: Option<u64>,
+    max_requests: u64,
+) -> Vec<SmallVec<[RowId; 4]>> {
+    let requests = |size| upload_requests(size, part_size);
+    sized.sort_by_key(|(size, _)| Reverse(*size));
+    let mut total: u64 = sized.iter().map(|(size, _)| requests(*size)).sum();
+
+    // Two smallest packs are at the end.
+    while total > max_requests && sized.len() >= 2 {
+        let smallest = sized.split_off(sized.len() - 2);
+        // Merged archive has single trailer, so it's even a bit smaller.
+        let size: u64 = smallest.iter().map(|(size, _)| size).sum();
+        let files: usize = smallest.iter().map(|(_, pack)| pack.len()).sum();
+        if size > limits.max_size || files > limits.max_files() {
+            sized.extend(smallest);
+            break;
+        }
+        total -= smallest
+            .iter()
+            .map(|(size, _)| requests(*size))
+            .sum::<u64>();
+        total += requests(size);
+        let pack = smallest.into_iter().flat_map(|(_, pack)| pack).collect();
+        let idx = sized.partition_point(|(other, _)| *other >= size);
+        sized.insert(idx, (size, pack));
+    }
+
+    let mut used = 0;
+    let mut deferred = Vec::new();
+    for (size, pack) in std::mem::take(sized) {
+        if used + requests(size) <= max_requests {
+            used += requests(size);
+            sized.push((size, pack));
+        } else {
+            deferred.push(pack);
+        }
+    }
+    deferred
+}
+
+/// Creates archive with all created or changed files from the pack.
+pub fn to_archive(
+    diff: &Diff,
+    pack: &[RowId],
+    format: Format,
+) -> Result<Archive, crate::database::Error> {
+    let mut archive = Archive::with_format(format);
+    for &rowid in pack {
+        match diff.query().get(rowid)? {
+            DiffRow::Created { after, .. } | DiffRow::Changed { after, .. } => {
+                archive.add(after.cast());
+            }
+            // Nothing to upload.
+            DiffRow::Deleted { .. } | DiffRow::Moved { .. } => {}
+        }
+    }
+    Ok(archive)
 }
@@ -1,6 +1,7 @@
 use crate::serde_b64;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::fmt::Write;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::path::PathBuf;
@@ -13,7 +14,7 @@ fn u64_to_ascii(num: u64) -> [u8; 12] {
     let digits = b'0'..=b'9'; // 10
     let upper = b'A'..=b'Z'; // 25
                              // 6 more chars:
-    let additional = [b'-', b'+', b'!', b'=', b'_', b'#'];
+    let additional = *b"-+!=_#";
     // NOTE: Forbidden characters in Windows are: < > : " / \ | ? *
 
     let alphabet = additional
@@ -82,6 +83,7 @@ impl EncodedPath<Local> {
     }
 
     /// Converts `EncodedPath` into `PathBuf` with correct separators matching current platform.
+    #[must_use]
     pub fn to_path(&self) -> Result<PathBuf, os_str_bytes::EncodingError> {
         let mut vec = self.0.clone();
         for i in &mut vec {
@@ -103,7 +105,7 @@ impl EncodedPath<External> {
                 *i = b'/';
             }
         }
-        EncodedPath(vec, PhantomData::default())
+        EncodedPath(vec, PhantomData)
     }
 }
 
@@ -111,7 +113,7 @@ impl<K: PathKind> EncodedPath<K> {
     /// Changes kind of this path. Use with caution.
     #[must_use]
     pub fn cast<T: PathKind>(self) -> EncodedPath<T> {
-        EncodedPath(self.0, PhantomData::default())
+        EncodedPath(self.0, PhantomData)
     }
 
     /// # Example
@@ -188,7 +190,7 @@ impl<K: PathKind> EncodedPath<K> {
     /// assert_eq!(cropped.len(), 25);
     /// ```
     #[must_use]
-    pub fn crop_name_to<L: Into<usize>>(&self, max_length: L) -> Cow<[u8]> {
+    pub fn crop_name_to<L: Into<usize>>(&self, max_length: L) -> Cow<'_, [u8]> {
         const EXTENSION_LENGTH: usize = 10;
         const HASH_LENGTH: usize = 12;
 
@@ -228,7 +230,7 @@ impl<K: PathKind> EncodedPath<K> {
 pub trait EscapedString {
     /// Converts self to the string, replacing any invalid characters with `\x??` sequences.
     /// Should be used for displaying purposes only.
-    fn escaped(&self) -> Cow<str>;
+    fn escaped(&self) -> Cow<'_, str>;
 }
 
 impl<T: PathKind> EscapedString for EncodedPath<T> {
@@ -238,7 +240,7 @@ impl<T: PathKind> EscapedString for EncodedPath<T> {
     /// let path = EncodedPath::from_vec(b"Hello \xC3\x28 world".to_vec());
     /// assert_eq!(path.escaped(), "Hello \\xC3( world");
     /// ```
-    fn escaped(&self) -> Cow<str> {
+    fn escaped(&self) -> Cow<'_, str> {
         self.0.escaped()
     }
 }
@@ -264,7 +266,7 @@ impl EscapedString for [u8] {
     /// assert_eq!(b"Hello \xC3\x28 world!".escaped(), "Hello \\xC3( world!");
     /// assert_eq!(b"Hello \xF4\xBF\xBF\xBF world!".escaped(), "Hello \\xF4\\xBF\\xBF\\xBF world!");
     /// ```
-    fn escaped(&self) -> Cow<str> {
+    fn escaped(&self) -> Cow<'_, str> {
         let mut remaining = self;
         let mut result = String::new();
         loop {
@@ -283,8 +285,10 @@ impl EscapedString for [u8] {
                     let valid = unsafe { std::str::from_utf8_unchecked(valid) };
                     remaining = rest;
                     result.push_str(valid);
-                    let escaped: String = bad.iter().map(|x| format!("\\x{:02X}", x)).collect();
-                    result.push_str(&escaped);
+                    for x in bad {
+                        // Writing to a String never fails.
+                        let _ = write!(result, "\\x{x:02X}");
+                    }
                 }
             }
         }
@@ -294,6 +298,6 @@ impl EscapedString for [u8] {
 impl<P: PathKind> std::fmt::Debug for EncodedPath<P> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let escaped = self.escaped();
-        write!(f, "EncodedPath({})", escaped)
+        write!(f, "EncodedPath({escaped})")
     }
 }
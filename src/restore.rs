@@ -0,0 +1,442 @@
+//! Restoring chosen files from a snapshot: index → download → extract.
+
+use crate::concurrent::run_limited;
+use crate::cpio::reader::{NextItem, ReadError, ReadFile, ReadingError};
+use crate::cpio::Reader;
+use crate::database::{self, ArchiveId, Database, SqlName};
+use crate::extract::{self as extractor, destination, make_place, Existing, Owners};
+use crate::fileext;
+use crate::fileinfo::{Info, UnspecifiedInfo};
+use crate::path::{EncodedPath, EscapedString, External, Local};
+use crate::storage::{self, Storage};
+use crate::stream_hash::stream_hash;
+use crate::types::Checksum;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    DatabaseFailed {
+        source: database::Error,
+    },
+    StorageFailed {
+        source: storage::Error,
+    },
+    InvalidPattern {
+        source: glob::PatternError,
+    },
+    InvalidPath {
+        source: os_str_bytes::EncodingError,
+    },
+    CantReadArchive {
+        key: String,
+        source: ReadingError,
+    },
+    CantExtract {
+        key: String,
+        source: ReadError,
+    },
+    IoFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Path {} leads outside of the output directory", path))]
+    OutsideOfOutput {
+        path: String,
+    },
+    #[snafu(display(
+        "Checksum mismatch at {:?}: expected {}, found {}",
+        path,
+        expected,
+        found
+    ))]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: Checksum,
+        found: Checksum,
+    },
+    #[snafu(display("Archive {} does not contain {} of expected files", key, count))]
+    IncompleteArchive {
+        key: String,
+        count: usize,
+    },
+}
+
+/// Set of globs, which are matched against full paths of files in the snapshot.
+///
+/// `*` does not match `/`, while `**` does:
+/// ```
+/// # use colbak_lib::restore::Patterns;
+/// # use colbak_lib::path::EncodedPath;
+/// let patterns = Patterns::new(&["/home/*/docs/**", "/etc/fstab"]).unwrap();
+/// assert!(patterns.matches(&EncodedPath::from_vec(b"/home/me/docs/a/b.txt".to_vec())));
+/// assert!(patterns.matches(&EncodedPath::from_vec(b"/etc/fstab".to_vec())));
+/// assert!(!patterns.matches(&EncodedPath::from_vec(b"/home/me/other/docs/a.txt".to_vec())));
+/// ```
+///
+/// Empty set matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct Patterns(Vec<glob::Pattern>);
+
+impl Patterns {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, Error> {
+        let patterns = patterns
+            .iter()
+            .map(|x| glob::Pattern::new(x.as_ref()))
+            .collect::<Result<_, _>>()
+            .context(InvalidPattern)?;
+        Ok(Patterns(patterns))
+    }
+
+    #[must_use]
+    pub fn matches(&self, path: &EncodedPath<External>) -> bool {
+        const OPTIONS: glob::MatchOptions = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        if self.0.is_empty() {
+            return true;
+        }
+        let path = path.escaped();
+        self.0.iter().any(|x| x.matches_with(&path, OPTIONS))
+    }
+}
+
+/// Settings of the single restore run.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    /// Number of archives downloaded at once.
+    pub concurrency: usize,
+    /// Owners of restored files.
+    pub owners: Owners,
+    /// What to do with files that already exist.
+    pub existing: Existing,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            concurrency: 4,
+            owners: Owners::default(),
+            existing: Existing::default(),
+        }
+    }
+}
+
+/// What was done by [`restore`](restore).
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Keys of downloaded archives, sorted.
+    pub archives: Vec<String>,
+    /// Number of restored files, including symlinks, named pipes and devices.
+    pub files: usize,
+    /// Matching files that were not found in any archive.
+    pub missing: Vec<EncodedPath<External>>,
+    /// Matching files that were not restored, because they already exist. Sorted.
+    pub skipped: Vec<EncodedPath<External>>,
+    /// Number of entries and attributes that were not restored, details are logged.
+    pub warnings: usize,
+}
+
+/// Single file that should be extracted from archive.
+struct Extraction {
+    /// All paths where file should be placed, with their metadata. Usually there is only one.
+    destinations: Vec<(PathBuf, Info<External>)>,
+    checksum: Option<Checksum>,
+}
+
+/// Where extracted files were placed.
+#[derive(Default)]
+struct Placed {
+    /// Written files, with metadata that should be applied to them.
+    written: Vec<(PathBuf, Info<External>)>,
+    /// Files that already exist and were left as is.
+    skipped: Vec<EncodedPath<External>>,
+}
+
+async fn create_dir(path: &Path) -> Result<(), Error> {
+    tokio::fs::create_dir_all(path)
+        .await
+        .context(IoFailed { path })
+}
+
+/// Creates file that does not exist yet, so symlinks are never followed.
+async fn create_new(path: &Path) -> Result<File, Error> {
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+        .context(IoFailed { path })
+}
+
+/// Writes file to the first destination, and copies it to the rest.
+///
+/// Destinations that already exist are handled according to the `existing` policy.
+async fn extract<R: AsyncRead + Unpin>(
+    file: ReadFile<R>,
+    key: &str,
+    extraction: Extraction,
+    existing: Existing,
+    placed: &mut Placed,
+) -> Result<Reader<R>, Error> {
+    let start = placed.written.len();
+    for (path, info) in extraction.destinations {
+        if let Some(parent) = path.parent() {
+            create_dir(parent).await?;
+        }
+        let place = make_place(path.clone(), &info, existing)
+            .await
+            .context(IoFailed { path })?;
+        match place {
+            Some(path) => placed.written.push((path, info)),
+            None => placed.skipped.push(info.path),
+        }
+    }
+    let (first, rest) = match placed.written[start..].split_first() {
+        Some(((first, _), rest)) => (first, rest),
+        None => return file.to_void().await.context(CantExtract { key }),
+    };
+    let output = create_new(first).await?;
+    let mut hasher = stream_hash(output);
+    let reader = file
+        .drain_to(&mut hasher)
+        .await
+        .context(CantExtract { key })?;
+    hasher.flush().await.context(IoFailed { path: first })?;
+    let found = Checksum::from(hasher.finalize());
+    if let Some(expected) = extraction.checksum {
+        snafu::ensure!(
+            expected == found,
+            ChecksumMismatch {
+                path: first,
+                expected,
+                found
+            }
+        );
+    }
+    for (path, _) in rest {
+        let mut source = File::open(first).await.context(IoFailed { path: first })?;
+        let mut copy = create_new(path).await?;
+        tokio::io::copy(&mut source, &mut copy)
+            .await
+            .context(IoFailed { path })?;
+    }
+    Ok(reader)
+}
+
+/// Creates entry which has no data in archives: symlink, named pipe or device.
+///
+/// Returns false when it can't be created, the reason is logged.
+async fn create_special(path: &Path, info: &Info<External>) -> Result<bool, Error> {
+    match &info.data {
+        UnspecifiedInfo::Symlink(link) => {
+            let target = EncodedPath::from_vec(link.target.clone()).cast::<Local>();
+            let target = target.to_path().context(InvalidPath)?;
+            tokio::fs::symlink(target, path)
+                .await
+                .context(IoFailed { path })?;
+        }
+        UnspecifiedInfo::Fifo(_) => {
+            fileext::make_fifo(path, 0o600).context(IoFailed { path })?;
+        }
+        UnspecifiedInfo::Device(device) => {
+            if let Err(e) = fileext::make_device(path, device, 0o600) {
+                log!(warn: "Skipping device {}: {}", path = info.path.escaped(), error = e.to_string());
+                return Ok(false);
+            }
+        }
+        _ => {
+            log!(warn: "Skipping {}: unknown file type", path = info.path.escaped());
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Creates entry from the snapshot, unless it already exists.
+///
+/// Returns where the entry was created, `None` when it was skipped.
+async fn place_special(
+    path: PathBuf,
+    info: &Info<External>,
+    existing: Existing,
+    report: &mut RestoreReport,
+) -> Result<Option<PathBuf>, Error> {
+    if let Some(parent) = path.parent() {
+        create_dir(parent).await?;
+    }
+    let place = make_place(path.clone(), info, existing)
+        .await
+        .context(IoFailed { path })?;
+    match place {
+        Some(path) if create_special(&path, info).await? => {
+            report.files += 1;
+            Ok(Some(path))
+        }
+        Some(_) => {
+            report.warnings += 1;
+            Ok(None)
+        }
+        None => {
+            report.skipped.push(info.path.clone());
+            Ok(None)
+        }
+    }
+}
+
+/// Downloads archive and extracts `wanted` files from it.
+///
+/// Returns key of the archive and where extracted files were placed.
+async fn download(
+    storage: &dyn Storage,
+    key: String,
+    mut wanted: HashMap<Vec<u8>, Extraction>,
+    existing: Existing,
+) -> Result<(String, Placed), Error> {
+    log!(cli: "Downloading {} for {} files", key, count = wanted.len());
+    let mut placed = Placed::default();
+    let data = storage.get(&key).await.context(StorageFailed)?;
+    let mut reader = Reader::new(data);
+    while let NextItem::File(file) = reader
+        .advance()
+        .await
+        .context(CantReadArchive { key: &key })?
+    {
+        let path = file.info().path;
+        reader = match wanted.remove(path.as_bytes()) {
+            Some(extraction) => extract(file, &key, extraction, existing, &mut placed).await?,
+            None => file.to_void().await.context(CantExtract { key: &key })?,
+        };
+    }
+    snafu::ensure!(
+        wanted.is_empty(),
+        IncompleteArchive {
+            key,
+            count: wanted.len()
+        }
+    );
+    Ok((key, placed))
+}
+
+/// Restores files matching `patterns` from the `snapshot` into the `output` directory.
+///
+/// Archive index is used to find which archives hold matching files,
+/// so only these archives are downloaded. Other files in them are skipped.
+/// Directories, symlinks, named pipes and devices are created from the snapshot alone.
+/// Up to [`concurrency`](RestoreOptions::concurrency) archives are downloaded at once, largest first.
+pub async fn restore(
+    db: &Database,
+    snapshot: SqlName,
+    patterns: &Patterns,
+    storage: &dyn Storage,
+    output: &Path,
+    options: &RestoreOptions,
+) -> Result<RestoreReport, Error> {
+    let mut report = RestoreReport::default();
+    // Archive → path in that archive → what to do with it.
+    let mut plan: BTreeMap<ArchiveId, HashMap<Vec<u8>, Extraction>> = BTreeMap::new();
+    let mut dirs = Vec::new();
+    let mut specials = Vec::new();
+    // Metadata is set when everything is extracted,
+    // otherwise directories would get the time of extraction.
+    let mut finishing = Vec::new();
+    {
+        let snapshot = db.readonly_snapshot(snapshot).context(DatabaseFailed)?;
+        snapshot
+            .for_each(|info| {
+                if !patterns.matches(&info.path) {
+                    return Ok(());
+                }
+                if let UnspecifiedInfo::Dir(_) = info.data {
+                    let dir = destination(output, &info.path)
+                        .context(InvalidPath)?
+                        .context(OutsideOfOutput {
+                            path: info.path.escaped(),
+                        })?;
+                    dirs.push(dir.clone());
+                    finishing.push((dir, info));
+                    return Ok(());
+                }
+                let identifier = if let Some(identifier) = info.identifier() {
+                    identifier
+                } else {
+                    // Symlinks, pipes and devices have no data, so their archives are not needed.
+                    let path = destination(output, &info.path)
+                        .context(InvalidPath)?
+                        .context(OutsideOfOutput {
+                            path: info.path.escaped(),
+                        })?;
+                    specials.push((path, info));
+                    return Ok(());
+                };
+                let found = db.find_by_identifier(&identifier).context(DatabaseFailed)?;
+                match found {
+                    Some(found) => {
+                        let extraction = plan
+                            .entry(found.archive)
+                            .or_default()
+                            .entry(found.path.as_bytes().to_vec())
+                            .or_insert_with(|| Extraction {
+                                destinations: Vec::new(),
+                                checksum: found.checksum,
+                            });
+                        let path = destination(output, &info.path)
+                            .context(InvalidPath)?
+                            .context(OutsideOfOutput {
+                                path: info.path.escaped(),
+                            })?;
+                        extraction.destinations.push((path, info));
+                    }
+                    None => report.missing.push(info.path),
+                }
+                Ok(())
+            })
+            .context(DatabaseFailed)??;
+    }
+    for dir in &dirs {
+        create_dir(dir).await?;
+    }
+    for path in &report.missing {
+        log!(warn: "File {} is not found in any archive", path = path.escaped());
+    }
+
+    let mut archives = Vec::with_capacity(plan.len());
+    for (id, wanted) in plan {
+        let record = db.archive(id).context(DatabaseFailed)?;
+        archives.push((record.size, record.key, wanted));
+    }
+    // Like backup, the largest archives are started first, so they don't delay the end.
+    archives.sort_unstable_by_key(|(size, ..)| Reverse(*size));
+    let jobs = archives
+        .into_iter()
+        .map(|(_, key, wanted)| Ok(download(storage, key, wanted, options.existing)));
+    run_limited(jobs, options.concurrency, |(key, placed)| {
+        report.files += placed.written.len();
+        report.archives.push(key);
+        report.skipped.extend(placed.skipped);
+        finishing.extend(placed.written);
+        Ok(())
+    })
+    .await?;
+    report.archives.sort();
+    // Created last, so restored files are never written through restored symlinks.
+    for (path, info) in specials {
+        if let Some(path) = place_special(path, &info, options.existing, &mut report).await? {
+            finishing.push((path, info));
+        }
+    }
+    report
+        .skipped
+        .sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    for path in &report.skipped {
+        log!(warn: "File {} already exists, skipping", path = path.escaped());
+    }
+    report.warnings += extractor::finish(finishing, &options.owners);
+    Ok(report)
+}
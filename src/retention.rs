@@ -0,0 +1,109 @@
+//! Deleting archives that are not needed anymore, without paying for early deletion.
+//!
+//! Archive becomes obsolete when none of it's files is present in any retained snapshot.
+//! Every uploaded snapshot is retained until it is [expired](crate::database::Database::expire_snapshot)
+//! explicitly, and the last one is always retained.
+//! Note that files of expired snapshots can't be restored after obsolete archives are deleted.
+//!
+//! But most cold storage classes charge for the minimum storage duration even if object is deleted earlier,
+//! so obsolete archive is deleted only when [that duration](crate::estimate::ClassPrices::min_duration_days)
+//! is over. Until then it's cheaper to keep it.
+
+use crate::database::{self, ArchiveId, Database};
+use crate::estimate::Prices;
+use crate::storage::{self, Storage, StorageClass};
+use crate::DateTime;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use std::convert::TryFrom;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    DatabaseFailed { source: database::Error },
+    StorageFailed { source: storage::Error },
+}
+
+/// Obsolete archive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ObsoleteArchive {
+    #[serde(skip)]
+    pub id: ArchiveId,
+    pub key: String,
+    pub size: u64,
+    pub storage_class: StorageClass,
+    pub uploaded_at: DateTime,
+    /// When archive can be deleted without any penalty.
+    pub deletable_at: DateTime,
+    /// How much storing this archive costs per month.
+    pub monthly_cost: f64,
+    /// How much it costs to delete archive right now.
+    pub early_deletion_cost: f64,
+}
+
+/// What should be done with obsolete archives.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RetentionPlan {
+    /// Archives that can be deleted for free.
+    pub delete: Vec<ObsoleteArchive>,
+    /// Archives that are obsolete, but still in their minimum storage duration.
+    pub keep: Vec<ObsoleteArchive>,
+    /// How much is saved per month after deleting archives.
+    pub monthly_savings: f64,
+    /// How much more will be saved per month, when kept archives are deleted too.
+    pub pending_savings: f64,
+    /// How much would cost deleting kept archives right now.
+    pub early_deletion_cost: f64,
+}
+
+/// Decides which archives should be deleted at the moment `now`.
+///
+/// Nothing is obsolete until the first snapshot is uploaded.
+pub fn plan(db: &Database, prices: &Prices, now: DateTime) -> Result<RetentionPlan, Error> {
+    let mut plan = RetentionPlan::default();
+    let last = db.last_uploaded_snapshot().context(DatabaseFailed)?;
+    if last.is_none() {
+        return Ok(plan);
+    }
+    let obsolete = db.obsolete_archives().context(DatabaseFailed)?;
+    for (id, record) in obsolete {
+        let class = record.storage_class;
+        let min_days = prices.get(class).min_duration_days;
+        let deletable_at = record.uploaded_at + time::Duration::days(min_days.into());
+        let stored_days = (now - record.uploaded_at).whole_days();
+        // Negative duration means clock was changed, so nothing is known for sure.
+        let stored_days = u32::try_from(stored_days).unwrap_or(0);
+        let archive = ObsoleteArchive {
+            id,
+            key: record.key,
+            size: record.size,
+            storage_class: class,
+            uploaded_at: record.uploaded_at,
+            deletable_at,
+            monthly_cost: prices.monthly_cost(class, record.size),
+            early_deletion_cost: prices.early_deletion_cost(class, record.size, stored_days),
+        };
+        if deletable_at <= now {
+            plan.monthly_savings += archive.monthly_cost;
+            plan.delete.push(archive);
+        } else {
+            plan.pending_savings += archive.monthly_cost;
+            plan.early_deletion_cost += archive.early_deletion_cost;
+            plan.keep.push(archive);
+        }
+    }
+    Ok(plan)
+}
+
+/// Deletes archives [planned](plan) for deletion, both from storage and from the index.
+pub async fn apply(
+    db: &Database,
+    storage: &dyn Storage,
+    plan: &RetentionPlan,
+) -> Result<(), Error> {
+    for archive in &plan.delete {
+        storage.delete(&archive.key).await.context(StorageFailed)?;
+        db.remove_archive(archive.id).context(DatabaseFailed)?;
+        log!(cli: "Deleted {}", key = archive.key.as_str());
+    }
+    Ok(())
+}
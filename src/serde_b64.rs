@@ -21,7 +21,7 @@ where
         .and_then(|string| base64::decode(string).map_err(|err| Error::custom(err.to_string())))
         .and_then(|vec| {
             vec.try_into()
-                .map_err(|err| Error::custom(format!("{:?}", err)))
+                .map_err(|err| Error::custom(format!("{err:?}")))
         })
 }
 
@@ -0,0 +1,64 @@
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(super)")]
+pub enum Error {
+    RequestFailed {
+        source: hyper::Error,
+        backtrace: snafu::Backtrace,
+    },
+    CantBuildRequest {
+        source: hyper::http::Error,
+        backtrace: snafu::Backtrace,
+    },
+    IoFailed {
+        source: std::io::Error,
+        backtrace: snafu::Backtrace,
+    },
+    JsonFailed {
+        source: serde_json::Error,
+        backtrace: snafu::Backtrace,
+    },
+    CantWalkdir {
+        source: walkdir::Error,
+    },
+    #[snafu(display("Background task has failed"))]
+    WorkerFailed,
+    InvalidXml {
+        source: quick_xml::Error,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(display("Server responded with {} to {} {}: {}", status, method, key, body))]
+    UnexpectedStatus {
+        method: String,
+        key: String,
+        status: u16,
+        body: String,
+    },
+    #[snafu(display("Field `{}` is missing in the response", name))]
+    MissingField {
+        name: &'static str,
+    },
+    #[snafu(display("Field `{}` has invalid value `{}`", name, value))]
+    InvalidField {
+        name: &'static str,
+        value: String,
+    },
+    #[snafu(display("Invalid endpoint `{}`: {}", endpoint, source))]
+    InvalidEndpoint {
+        endpoint: String,
+        source: hyper::http::uri::InvalidUri,
+    },
+    #[snafu(display("Environment variable `{}` is not set", name))]
+    MissingEnv {
+        name: &'static str,
+    },
+    #[snafu(display("Key `{}` is not allowed", key))]
+    InvalidKey {
+        key: String,
+    },
+    #[snafu(display("Unknown storage class `{}`", name))]
+    UnknownStorageClass {
+        name: String,
+    },
+}
@@ -0,0 +1,213 @@
+//! Storage backend that keeps objects in a local directory.
+//!
+//! Useful for testing and for backing up to a mounted NAS.
+//! Object `foo/bar.cpio` is stored as `<root>/foo/bar.cpio`, so key layout is the same as in S3.
+//! Storage class and user metadata are stored beside, in the `<root>/foo/bar.cpio.colbak-meta` JSON file.
+//!
+//! Every file is written to the temporary file first and then renamed,
+//! so partially uploaded objects are never visible.
+
+use super::error::*;
+use super::{ObjectInfo, PutOptions, Storage, StorageClass};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::ffi::OsString;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncRead;
+
+/// Suffix of files with object metadata.
+pub const META_SUFFIX: &str = ".colbak-meta";
+
+/// Suffix of files that are being written now.
+pub const TEMP_SUFFIX: &str = ".colbak-tmp";
+
+/// Makes names of temporary files unique within the process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content of the metadata file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sidecar {
+    storage_class: StorageClass,
+    metadata: Vec<(String, String)>,
+}
+
+/// Storage that keeps objects in the local directory.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Writes everything from `data` to the temporary file, then atomically moves it to the `path`.
+async fn write_atomic(path: &Path, data: &mut (dyn AsyncRead + Unpin)) -> std::io::Result<u64> {
+    let unique = format!(
+        ".{}-{}{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_SUFFIX
+    );
+    let temporary = with_suffix(path, &unique);
+    let written = async {
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        let size = tokio::io::copy(data, &mut file).await?;
+        file.sync_all().await?;
+        Ok(size)
+    }
+    .await;
+    let result = match written {
+        Ok(size) => tokio::fs::rename(&temporary, path).await.map(|()| size),
+        Err(err) => Err(err),
+    };
+    if result.is_err() {
+        // Nothing to do if it fails too, original error is more important.
+        let _ = tokio::fs::remove_file(&temporary).await;
+    }
+    result
+}
+
+/// Removes file, ignoring the case when it does not exist.
+async fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+impl FsStorage {
+    #[must_use]
+    pub fn new(root: PathBuf) -> Self {
+        FsStorage { root }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns where the object should be stored.
+    /// Keys that may escape the root or clash with service files are rejected.
+    fn locate(&self, key: &str) -> Result<PathBuf, Error> {
+        let is_valid = !key.is_empty()
+            && !key.ends_with(META_SUFFIX)
+            && !key.ends_with(TEMP_SUFFIX)
+            && key
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != "..");
+        if is_valid {
+            Ok(self.root.join(key))
+        } else {
+            InvalidKey { key }.fail()
+        }
+    }
+
+    /// Walks over the root, collecting all objects. Runs synchronously.
+    fn walk(root: &Path, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let mut result = Vec::new();
+        if !root.exists() {
+            return Ok(result);
+        }
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry.context(CantWalkdir)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = match entry.path().strip_prefix(root) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            let key = relative
+                .components()
+                .map(|part| part.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>();
+            let key = match key {
+                Some(key) => key.join("/"),
+                // Such objects were not created by us.
+                None => continue,
+            };
+            if key.ends_with(META_SUFFIX) || key.ends_with(TEMP_SUFFIX) || !key.starts_with(prefix) {
+                continue;
+            }
+            let sidecar = match std::fs::read(with_suffix(entry.path(), META_SUFFIX)) {
+                Ok(json) => serde_json::from_slice(&json).context(JsonFailed)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Sidecar::default(),
+                Err(err) => return Err(err).context(IoFailed),
+            };
+            let size = entry.metadata().context(CantWalkdir)?.len();
+            result.push(ObjectInfo {
+                key,
+                size,
+                storage_class: sidecar.storage_class,
+            });
+        }
+        result.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(result)
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for FsStorage {
+    async fn put(
+        &self,
+        key: &str,
+        data: &mut (dyn AsyncRead + Unpin),
+        options: &PutOptions,
+    ) -> Result<ObjectInfo, Error> {
+        log!(aws: "Storing {} in {}", key, root = self.root.to_string_lossy());
+        let path = self.locate(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.context(IoFailed)?;
+        }
+        // Metadata is written first: metadata without object is simply ignored.
+        let sidecar = Sidecar {
+            storage_class: options.storage_class,
+            metadata: options.metadata.clone(),
+        };
+        let sidecar = serde_json::to_vec(&sidecar).context(JsonFailed)?;
+        write_atomic(&with_suffix(&path, META_SUFFIX), &mut Cursor::new(sidecar))
+            .await
+            .context(IoFailed)?;
+        let size = write_atomic(&path, data).await.context(IoFailed)?;
+        Ok(ObjectInfo {
+            key: key.to_owned(),
+            size,
+            storage_class: options.storage_class,
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin>, Error> {
+        let path = self.locate(key)?;
+        let file = tokio::fs::File::open(path).await.context(IoFailed)?;
+        Ok(Box::new(file))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let root = self.root.clone();
+        let prefix = prefix.to_owned();
+        tokio::task::spawn_blocking(move || Self::walk(&root, &prefix))
+            .await
+            .ok()
+            .context(WorkerFailed)?
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        log!(aws: "Removing {} from {}", key, root = self.root.to_string_lossy());
+        let path = self.locate(key)?;
+        remove_existing(&path).await.context(IoFailed)?;
+        remove_existing(&with_suffix(&path, META_SUFFIX))
+            .await
+            .context(IoFailed)?;
+        Ok(())
+    }
+
+    fn part_size(&self) -> Option<u64> {
+        None
+    }
+}
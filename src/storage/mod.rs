@@ -0,0 +1,153 @@
+//! Remote storages where archives are shipped to.
+//!
+//! Every storage is a flat key-value store of objects, as S3 is.
+//! Keys are slash-separated, but there is no real directories.
+
+mod error;
+pub mod fs;
+pub mod s3;
+pub mod sigv4;
+
+pub use error::Error;
+pub use fs::FsStorage;
+pub use s3::{S3Config, S3Storage};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+
+/// Storage class of the object.
+/// Names are the same as used by [S3](https://aws.amazon.com/s3/storage-classes/).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default)]
+pub enum StorageClass {
+    #[default]
+    Standard,
+    ReducedRedundancy,
+    StandardIa,
+    OnezoneIa,
+    IntelligentTiering,
+    Glacier,
+    DeepArchive,
+}
+
+impl StorageClass {
+    /// Returns name of the class, as it is expected by S3.
+    ///
+    /// ```
+    /// # use colbak_lib::storage::StorageClass;
+    /// assert_eq!(StorageClass::DeepArchive.as_str(), "DEEP_ARCHIVE");
+    /// assert_eq!("deep-archive".parse::<StorageClass>().unwrap(), StorageClass::DeepArchive);
+    /// ```
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::ReducedRedundancy => "REDUCED_REDUNDANCY",
+            StorageClass::StandardIa => "STANDARD_IA",
+            StorageClass::OnezoneIa => "ONEZONE_IA",
+            StorageClass::IntelligentTiering => "INTELLIGENT_TIERING",
+            StorageClass::Glacier => "GLACIER",
+            StorageClass::DeepArchive => "DEEP_ARCHIVE",
+        }
+    }
+}
+
+
+impl std::fmt::Display for StorageClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StorageClass {
+    type Err = Error;
+
+    /// Accepts both S3 names (`DEEP_ARCHIVE`) and more CLI-friendly ones (`deep-archive`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().replace('-', "_").as_str() {
+            "STANDARD" => Ok(StorageClass::Standard),
+            "REDUCED_REDUNDANCY" => Ok(StorageClass::ReducedRedundancy),
+            "STANDARD_IA" => Ok(StorageClass::StandardIa),
+            "ONEZONE_IA" => Ok(StorageClass::OnezoneIa),
+            "INTELLIGENT_TIERING" => Ok(StorageClass::IntelligentTiering),
+            "GLACIER" => Ok(StorageClass::Glacier),
+            "DEEP_ARCHIVE" => Ok(StorageClass::DeepArchive),
+            _ => Err(Error::UnknownStorageClass { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Opens storage described by the `target`:
+///
+/// - `s3://bucket/prefix` is an S3 bucket, other settings are read
+///   [from the environment](S3Config::from_env_for),
+/// - anything else is a path to the local directory.
+///
+/// Returns prefix that should be prepended to every key too. It is either empty or ends with `/`.
+pub fn open(target: &str) -> Result<(Box<dyn Storage>, String), Error> {
+    match target.strip_prefix("s3://") {
+        Some(location) => {
+            let (bucket, prefix) = match location.find('/') {
+                Some(idx) => (&location[..idx], location[idx + 1..].trim_end_matches('/')),
+                None => (location, ""),
+            };
+            let prefix = if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}/")
+            };
+            let config = S3Config::from_env_for(bucket.to_owned())?;
+            Ok((Box::new(S3Storage::new(config)?), prefix))
+        }
+        None => Ok((Box::new(FsStorage::new(target.into())), String::new())),
+    }
+}
+
+/// Options of the uploaded object.
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    pub storage_class: StorageClass,
+    /// User-defined metadata. For S3 it is sent as `x-amz-meta-*` headers,
+    /// so keys should be lowercase ASCII.
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Object stored in the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub storage_class: StorageClass,
+}
+
+/// Storage of objects.
+///
+/// Futures are not required to be `Send`, since [`cpio::Reader`](crate::cpio::Reader) is not.
+#[async_trait(?Send)]
+pub trait Storage {
+    /// Uploads everything from the `data` as an object with the given key.
+    /// Existing object is replaced.
+    async fn put(
+        &self,
+        key: &str,
+        data: &mut (dyn AsyncRead + Unpin),
+        options: &PutOptions,
+    ) -> Result<ObjectInfo, Error>;
+
+    /// Downloads object.
+    ///
+    /// Note that objects in the cold storage classes should be restored before.
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin>, Error>;
+
+    /// Lists all objects which keys start with the `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error>;
+
+    /// Removes object. Removing an object that does not exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Objects of this size or larger are uploaded in parts, each of them takes a separate request.
+    /// `None` when every object is uploaded by a single request.
+    fn part_size(&self) -> Option<u64>;
+}
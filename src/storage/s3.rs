@@ -0,0 +1,504 @@
+//! S3 (and compatible, like [MinIO](https://min.io/)) storage backend.
+//!
+//! Only a small subset of API is implemented, that is enough for storing archives:
+//! single and multipart uploads, downloads, listing with `ListObjectsV2` and deletion.
+
+use super::error::*;
+use super::sigv4::{self, Credentials, Signer};
+use super::{ObjectInfo, PutOptions, Storage};
+use crate::DateTime;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, Uri};
+use hyper_rustls::HttpsConnector;
+use quick_xml::events::Event;
+use snafu::{OptionExt, ResultExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+/// S3 does not accept parts smaller than 5 MiB, except the last one.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Part size used by default. Objects smaller than it are uploaded using single request.
+pub const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Settings of the S3 bucket.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Like `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub credentials: Credentials,
+    /// Whether bucket name should be put in the path (`endpoint/bucket/key`)
+    /// instead of the host name (`bucket.endpoint/key`).
+    /// Most of the S3-compatible servers expect path-style requests.
+    pub path_style: bool,
+    /// Size of every part of multipart upload. Whole part is kept in memory.
+    pub part_size: usize,
+}
+
+impl S3Config {
+    /// Reads configuration from the environment:
+    ///
+    /// - `COLBAK_S3_BUCKET`, `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` are required,
+    /// - `AWS_REGION` is `us-east-1` by default,
+    /// - `COLBAK_S3_ENDPOINT` is the AWS endpoint of the region by default.
+    ///   When it is set, path-style requests are used.
+    pub fn from_env() -> Result<Self, Error> {
+        let bucket = std::env::var("COLBAK_S3_BUCKET")
+            .ok()
+            .context(MissingEnv {
+                name: "COLBAK_S3_BUCKET",
+            })?;
+        Self::from_env_for(bucket)
+    }
+
+    /// Same as [`from_env`](Self::from_env), but bucket is specified explicitly.
+    pub fn from_env_for(bucket: String) -> Result<Self, Error> {
+        let var = |name: &'static str| std::env::var(name).ok().context(MissingEnv { name });
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+        let (endpoint, path_style) = match std::env::var("COLBAK_S3_ENDPOINT") {
+            Ok(endpoint) => (endpoint, true),
+            Err(_) => (format!("https://s3.{region}.amazonaws.com"), false),
+        };
+        Ok(S3Config {
+            endpoint,
+            region,
+            bucket,
+            credentials: Credentials {
+                access_key: var("AWS_ACCESS_KEY_ID")?,
+                secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            },
+            path_style,
+            part_size: DEFAULT_PART_SIZE,
+        })
+    }
+}
+
+/// Storage that keeps objects in the S3 bucket.
+pub struct S3Storage {
+    config: S3Config,
+    scheme: String,
+    authority: String,
+    signer: Signer,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Single page of the `ListObjectsV2` response.
+#[derive(Debug, Default)]
+struct ListPage {
+    objects: Vec<ObjectInfo>,
+    continuation: Option<String>,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Result<Self, Error> {
+        let endpoint: Uri = config.endpoint.parse().context(InvalidEndpoint {
+            endpoint: &config.endpoint,
+        })?;
+        let scheme = endpoint.scheme_str().unwrap_or("https").to_owned();
+        let authority = endpoint
+            .authority()
+            .context(MissingField { name: "endpoint host" })?
+            .to_string();
+        let signer = Signer {
+            credentials: config.credentials.clone(),
+            region: config.region.clone(),
+            service: "s3".to_owned(),
+        };
+        let client = Client::builder().build(HttpsConnector::with_webpki_roots());
+        Ok(S3Storage {
+            config,
+            scheme,
+            authority,
+            signer,
+            client,
+        })
+    }
+
+    /// Returns host and path where the object is located.
+    fn locate(&self, key: &str) -> (String, String) {
+        if self.config.path_style {
+            (
+                self.authority.clone(),
+                format!("/{}/{}", self.config.bucket, key),
+            )
+        } else {
+            (
+                format!("{}.{}", self.config.bucket, self.authority),
+                format!("/{key}"),
+            )
+        }
+    }
+
+    /// Sends signed request, failing when response status is not successful.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response<Body>, Error> {
+        log!(aws: "{} {}", method = method.as_str(), key; query, size = body.len());
+
+        let (host, path) = self.locate(key);
+        let payload_hash = sigv4::sha256_hex(&body);
+        let time = DateTime::now_utc();
+        let amz_date = sigv4::amz_date(time);
+
+        let mut all_headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        all_headers.extend_from_slice(headers);
+        let authorization = self.signer.authorization(
+            &sigv4::Request {
+                method: method.as_str(),
+                path: &path,
+                query,
+                headers: &all_headers,
+                payload_hash: &payload_hash,
+            },
+            time,
+        );
+
+        let mut uri = format!("{}://{}{}", self.scheme, host, sigv4::uri_encode(&path, true));
+        if !query.is_empty() {
+            uri.push('?');
+            uri.push_str(&sigv4::canonical_query(query));
+        }
+        let mut request = Request::builder().method(method.clone()).uri(uri);
+        for (name, value) in all_headers {
+            request = request.header(name, value);
+        }
+        let request = request
+            .header("authorization", authorization)
+            .body(Body::from(body))
+            .context(CantBuildRequest)?;
+
+        let response = self.client.request(request).await.context(RequestFailed)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .context(RequestFailed)?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        log!(aws, error: "{} {} failed with {}", method = method.as_str(), key, status = status.as_u16(); body);
+        UnexpectedStatus {
+            method: method.as_str(),
+            key,
+            status: status.as_u16(),
+            body,
+        }
+        .fail()
+    }
+
+    /// Sends request and reads whole response.
+    async fn send_read(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, Error> {
+        let response = self.send(method, key, query, headers, body).await?;
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .context(RequestFailed)?;
+        Ok(body.to_vec())
+    }
+
+    /// Uploads all parts of the multipart upload, returning total size.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut part: Vec<u8>,
+        data: &mut (dyn AsyncRead + Unpin),
+    ) -> Result<u64, Error> {
+        let mut size = 0;
+        let mut etags = Vec::new();
+        while !part.is_empty() {
+            size += part.len() as u64;
+            let number = (etags.len() + 1).to_string();
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+            let response = self.send(Method::PUT, key, &query, &[], part).await?;
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .context(MissingField { name: "ETag" })?;
+            etags.push(etag.to_owned());
+            part = read_part(data, self.config.part_size)
+                .await
+                .context(IoFailed)?;
+        }
+
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (idx, etag) in etags.iter().enumerate() {
+            complete.push_str("<Part><PartNumber>");
+            complete.push_str(&(idx + 1).to_string());
+            complete.push_str("</PartNumber><ETag>");
+            complete.push_str(etag);
+            complete.push_str("</ETag></Part>");
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+        let response = self
+            .send_read(
+                Method::POST,
+                key,
+                &[("uploadId", upload_id)],
+                &[],
+                complete.into_bytes(),
+            )
+            .await?;
+        // S3 may report an error after sending `200 OK`.
+        if let Some(code) = find_text(&response, b"Code")? {
+            return UnexpectedStatus {
+                method: "POST",
+                key,
+                status: 200_u16,
+                body: code,
+            }
+            .fail();
+        }
+        Ok(size)
+    }
+
+    async fn list_page(&self, prefix: &str, continuation: Option<&str>) -> Result<ListPage, Error> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if let Some(token) = continuation {
+            query.push(("continuation-token", token));
+        }
+        let response = self.send_read(Method::GET, "", &query, &[], Vec::new()).await?;
+        parse_list(&response)
+    }
+}
+
+/// Reads up to `size` bytes. Returned part is shorter only when EOF is reached.
+async fn read_part(data: &mut (dyn AsyncRead + Unpin), size: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(size);
+    (&mut *data).take(size as u64).read_to_end(&mut buffer).await?;
+    Ok(buffer)
+}
+
+/// Finds text of the first element with the given name.
+fn find_text(xml: &[u8], name: &[u8]) -> Result<Option<String>, Error> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    reader.trim_text(true);
+    let mut buffer = Vec::new();
+    let mut found = false;
+    loop {
+        match reader.read_event(&mut buffer).context(InvalidXml)? {
+            Event::Start(start) => found = start.name() == name,
+            Event::Text(text) if found => {
+                return Ok(Some(text.unescape_and_decode(&reader).context(InvalidXml)?));
+            }
+            Event::Eof => return Ok(None),
+            _ => found = false,
+        }
+        buffer.clear();
+    }
+}
+
+fn parse_list(xml: &[u8]) -> Result<ListPage, Error> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    reader.trim_text(true);
+    let mut buffer = Vec::new();
+    let mut page = ListPage::default();
+    let mut current: Option<ObjectInfo> = None;
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event(&mut buffer).context(InvalidXml)? {
+            Event::Start(start) => {
+                if start.name() == b"Contents" {
+                    current = Some(ObjectInfo {
+                        key: String::new(),
+                        size: 0,
+                        storage_class: super::StorageClass::default(),
+                    });
+                }
+                element = start.name().to_vec();
+            }
+            Event::Text(text) => {
+                let text = text.unescape_and_decode(&reader).context(InvalidXml)?;
+                match (element.as_slice(), &mut current) {
+                    (b"Key", Some(object)) => object.key = text,
+                    (b"Size", Some(object)) => {
+                        object.size = text.parse().ok().context(InvalidField {
+                            name: "Size",
+                            value: &text,
+                        })?;
+                    }
+                    // Classes that are unknown to us are treated as STANDARD.
+                    (b"StorageClass", Some(object)) => {
+                        object.storage_class = text.parse().unwrap_or_default();
+                    }
+                    (b"NextContinuationToken", None) => page.continuation = Some(text),
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                if end.name() == b"Contents" {
+                    page.objects.extend(current.take());
+                }
+                element.clear();
+            }
+            Event::Eof => break Ok(page),
+            _ => {}
+        }
+        buffer.clear();
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for S3Storage {
+    async fn put(
+        &self,
+        key: &str,
+        data: &mut (dyn AsyncRead + Unpin),
+        options: &PutOptions,
+    ) -> Result<ObjectInfo, Error> {
+        let metadata: Vec<_> = options
+            .metadata
+            .iter()
+            .map(|(name, value)| (format!("x-amz-meta-{name}"), value))
+            .collect();
+        let mut headers = vec![("x-amz-storage-class", options.storage_class.as_str())];
+        headers.extend(
+            metadata
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+        let part_size = self.config.part_size.max(MIN_PART_SIZE);
+        let first = read_part(data, part_size).await.context(IoFailed)?;
+        let size = if first.len() < part_size {
+            let size = first.len() as u64;
+            self.send(Method::PUT, key, &[], &headers, first).await?;
+            size
+        } else {
+            let response = self
+                .send_read(Method::POST, key, &[("uploads", "")], &headers, Vec::new())
+                .await?;
+            let upload_id =
+                find_text(&response, b"UploadId")?.context(MissingField { name: "UploadId" })?;
+            match self.upload_parts(key, &upload_id, first, data).await {
+                Ok(size) => size,
+                Err(err) => {
+                    // Otherwise uploaded parts will be stored (and billed) forever.
+                    let query = [("uploadId", upload_id.as_str())];
+                    if let Err(abort) = self.send(Method::DELETE, key, &query, &[], Vec::new()).await {
+                        log!(aws, error: "Can't abort upload {}", upload_id; abort = abort.to_string());
+                    }
+                    return Err(err);
+                }
+            }
+        };
+        Ok(ObjectInfo {
+            key: key.to_owned(),
+            size,
+            storage_class: options.storage_class,
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin>, Error> {
+        let response = self.send(Method::GET, key, &[], &[], Vec::new()).await?;
+        let body = response
+            .into_body()
+            .map_err(std::io::Error::other);
+        Ok(Box::new(StreamReader::new(body)))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let mut result = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = self.list_page(prefix, continuation.as_deref()).await?;
+            result.extend(page.objects);
+            match page.continuation {
+                Some(token) => continuation = Some(token),
+                None => break Ok(result),
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, key, &[], &[], Vec::new()).await?;
+        Ok(())
+    }
+
+    fn part_size(&self) -> Option<u64> {
+        Some(self.config.part_size.max(MIN_PART_SIZE) as u64)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageClass;
+
+    #[test]
+    fn list_response() {
+        let xml = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                <Name>bucket</Name>
+                <Prefix>backup/</Prefix>
+                <KeyCount>2</KeyCount>
+                <IsTruncated>true</IsTruncated>
+                <Contents>
+                    <Key>backup/first &amp; second.cpio</Key>
+                    <Size>434234</Size>
+                    <StorageClass>DEEP_ARCHIVE</StorageClass>
+                </Contents>
+                <Contents>
+                    <Key>backup/third.cpio</Key>
+                    <Size>0</Size>
+                    <StorageClass>STANDARD</StorageClass>
+                </Contents>
+                <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>
+            </ListBucketResult>"#;
+        let page = parse_list(xml).unwrap();
+        assert_eq!(
+            page.objects,
+            vec![
+                ObjectInfo {
+                    key: "backup/first & second.cpio".to_owned(),
+                    size: 434234,
+                    storage_class: StorageClass::DeepArchive,
+                },
+                ObjectInfo {
+                    key: "backup/third.cpio".to_owned(),
+                    size: 0,
+                    storage_class: StorageClass::Standard,
+                },
+            ]
+        );
+        assert_eq!(
+            page.continuation.as_deref(),
+            Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=")
+        );
+    }
+
+    #[test]
+    fn upload_id() {
+        let xml = br"<InitiateMultipartUploadResult>
+                <Bucket>bucket</Bucket>
+                <Key>key</Key>
+                <UploadId>VXBsb2FkIElEIGZvciA2aWWpbmcncyBteS1tb3ZpZS5tMnRzIHVwbG9hZA</UploadId>
+            </InitiateMultipartUploadResult>";
+        assert_eq!(
+            find_text(xml, b"UploadId").unwrap().as_deref(),
+            Some("VXBsb2FkIElEIGZvciA2aWWpbmcncyBteS1tb3ZpZS5tMnRzIHVwbG9hZA")
+        );
+        assert_eq!(find_text(xml, b"Code").unwrap(), None);
+    }
+}
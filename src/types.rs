@@ -12,7 +12,7 @@ impl<OutputSize: ArrayLength<u8>> From<GenericArray<u8, OutputSize>> for Checksu
     fn from(fin: GenericArray<u8, OutputSize>) -> Checksum {
         let mut arr = [0; LENGTH];
         let min_length = LENGTH.min(fin.len());
-        (&mut arr[..min_length]).copy_from_slice(&fin[..min_length]);
+        arr[..min_length].copy_from_slice(&fin[..min_length]);
         Checksum(arr)
     }
 }
@@ -21,7 +21,7 @@ impl std::fmt::Display for Checksum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("{")?;
         for i in &self.0 {
-            f.write_fmt(format_args!("{:x}", i))?;
+            f.write_fmt(format_args!("{i:x}"))?;
         }
         f.write_str("}")?;
         Ok(())
@@ -30,6 +30,6 @@ impl std::fmt::Display for Checksum {
 
 impl std::fmt::Debug for Checksum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{self}")
     }
 }
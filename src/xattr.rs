@@ -0,0 +1,163 @@
+//! Extended attributes of files.
+//!
+//! POSIX ACLs are stored by Linux as `system.posix_acl_access` and `system.posix_acl_default`
+//! attributes, so they are preserved too. Symlinks are never followed.
+//! On other platforms files have no attributes.
+
+use crate::serde_b64;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+/// Single extended attribute. Both name and value are arbitrary bytes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Xattr {
+    #[serde(with = "serde_b64")]
+    pub name: Vec<u8>,
+    #[serde(with = "serde_b64")]
+    pub value: Vec<u8>,
+}
+
+/// Reads all attributes of the file, sorted by name.
+///
+/// Filesystems without attributes support are treated as having none.
+pub fn read(path: &Path) -> io::Result<Vec<Xattr>> {
+    let names = match sys::list(path) {
+        Ok(names) => names,
+        Err(e) if sys::is_unsupported(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut result = Vec::new();
+    for name in names.split(|&x| x == 0).filter(|x| !x.is_empty()) {
+        let value = match sys::get(path, name) {
+            Ok(value) => value,
+            // Attribute was removed after we listed it.
+            Err(e) if e.raw_os_error() == sys::NO_ATTRIBUTE => continue,
+            Err(e) => return Err(e),
+        };
+        result.push(Xattr {
+            name: name.to_vec(),
+            value,
+        });
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Sets a single attribute of the file, replacing the existing value.
+///
+/// Setting `security.*` and `trusted.*` attributes usually requires root privileges,
+/// and ACLs require filesystem support. Such errors are returned as is.
+pub fn write(path: &Path, xattr: &Xattr) -> io::Result<()> {
+    sys::set(path, &xattr.name, &xattr.value)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub const NO_ATTRIBUTE: Option<i32> = Some(libc::ENODATA);
+
+    pub fn is_unsupported(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::ENOTSUP)
+    }
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Calls `f` with a buffer large enough for the result.
+    /// Size is asked first, but it may grow before the second call, so this is retried.
+    fn with_buffer(f: impl Fn(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+        loop {
+            let size = f(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Size is not negative here.
+            #[allow(clippy::cast_sign_loss)]
+            let mut buffer = vec![0_u8; size as usize];
+            if buffer.is_empty() {
+                return Ok(buffer);
+            }
+            let len = f(buffer.as_mut_ptr().cast(), buffer.len());
+            if len >= 0 {
+                #[allow(clippy::cast_sign_loss)]
+                buffer.truncate(len as usize);
+                return Ok(buffer);
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Returns NUL-separated names of all attributes.
+    pub fn list(path: &Path) -> io::Result<Vec<u8>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        with_buffer(|buf, size| unsafe {
+            // Safe: path is NUL-terminated, buffer is either null or `size` bytes long.
+            libc::llistxattr(path.as_ptr(), buf.cast(), size)
+        })
+    }
+
+    pub fn get(path: &Path, name: &[u8]) -> io::Result<Vec<u8>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name)?;
+        with_buffer(|buf, size| unsafe {
+            // Safe: same as in `list`.
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size)
+        })
+    }
+
+    pub fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name)?;
+        let result = unsafe {
+            // Safe: strings are NUL-terminated, value is not written.
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub const NO_ATTRIBUTE: Option<i32> = None;
+
+    pub fn is_unsupported(_: &io::Error) -> bool {
+        false
+    }
+
+    pub fn list(_: &Path) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    pub fn get(_: &Path, _: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    pub fn set(_: &Path, _: &[u8], _: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Extended attributes are not supported",
+        ))
+    }
+}
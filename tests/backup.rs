@@ -0,0 +1,493 @@
+mod common;
+
+use colbak_lib::backup::{backup, BackupOptions};
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::database::{Database, SqlName};
+use colbak_lib::extract::{extract, Existing, ExtractOptions};
+use colbak_lib::fileext::make_fifo;
+use colbak_lib::hash_pool::hash_file;
+use colbak_lib::path::{EncodedPath, External};
+use colbak_lib::storage::{FsStorage, Storage};
+use common::write;
+use std::convert::Infallible;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Component, Path};
+use tokio::io::AsyncReadExt;
+
+/// Downloads archive and returns contents of all regular files in it.
+async fn files_in(storage: &FsStorage, key: &str) -> Vec<Vec<u8>> {
+    let mut data = Vec::new();
+    storage
+        .get(key)
+        .await
+        .unwrap()
+        .read_to_end(&mut data)
+        .await
+        .unwrap();
+    let mut reader = colbak_lib::cpio::Reader::new(std::io::Cursor::new(data));
+    let mut result = Vec::new();
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let is_file = f.info().size().is_some();
+                let mut contents = Vec::new();
+                reader = f.drain_to(&mut contents).await.unwrap();
+                if is_file {
+                    result.push(contents);
+                }
+            }
+            NextItem::End(_) => break result,
+        }
+    }
+}
+
+/// Returns key of archive holding the file at given snapshot.
+fn archive_of(db: &Database, snapshot: &SqlName, path: &Path) -> Option<String> {
+    let snapshot = db.readonly_snapshot(snapshot.clone()).unwrap();
+    let path = EncodedPath::from_path(path.to_path_buf()).cast::<External>();
+    let file = db.find_archive(&snapshot, &path).unwrap()?;
+    Some(db.archive(file.archive).unwrap().key)
+}
+
+#[tokio::test]
+async fn incremental_backups() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "first", "Hello world\n");
+    write(&root, "dir/second", "odd_named_file\n");
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        // Every file will be placed into separate archive,
+        // and both directories will be placed together.
+        min_size: 1,
+        prefix: "backups/".to_owned(),
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.base.as_str(), "empty");
+    assert_eq!(report.archives.len(), 3);
+    assert_eq!(storage.list("backups/").await.unwrap(), report.archives);
+    let mut contents = Vec::new();
+    for archive in &report.archives {
+        assert!(archive
+            .key
+            .starts_with(&format!("backups/{}/", report.snapshot)));
+        contents.extend(files_in(&storage, &archive.key).await);
+    }
+    contents.sort();
+    assert_eq!(
+        contents,
+        vec![b"Hello world\n".to_vec(), b"odd_named_file\n".to_vec()]
+    );
+
+    let first_key = archive_of(&db, &report.snapshot, &root.join("first")).unwrap();
+    assert_eq!(
+        files_in(&storage, &first_key).await,
+        vec![b"Hello world\n".to_vec()]
+    );
+    assert_eq!(db.archives().unwrap().len(), 3);
+
+    // Nothing changed.
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.base, report.snapshot);
+    assert!(second.archives.is_empty());
+
+    // Only changed file is uploaded.
+    write(&root, "dir/second", "even_named_file\n");
+    let third = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(third.base, second.snapshot);
+    assert_eq!(third.archives.len(), 1);
+    assert_eq!(
+        files_in(&storage, &third.archives[0].key).await,
+        vec![b"even_named_file\n".to_vec()]
+    );
+
+    // Unchanged file is still found in the first archive.
+    assert_eq!(
+        archive_of(&db, &third.snapshot, &root.join("first")),
+        Some(first_key)
+    );
+    assert_eq!(
+        archive_of(&db, &third.snapshot, &root.join("dir/second")),
+        Some(third.archives[0].key.clone())
+    );
+    assert_eq!(
+        archive_of(&db, &third.snapshot, &root.join("missing")),
+        None
+    );
+}
+
+#[tokio::test]
+async fn same_content_is_uploaded_once() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "original", "Hello world\n");
+    write(&root, "copy", "Hello world\n");
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        // Checksums must be known before archiving.
+        hash_workers: Some(1),
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+
+    // Both files are new, but only one is uploaded. Directory is placed into another archive.
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(first.archives.len(), 2);
+    assert_eq!(first.deduplicated, 1);
+    let key = archive_of(&db, &first.snapshot, &root.join("original")).unwrap();
+    assert_eq!(
+        files_in(&storage, &key).await,
+        vec![b"Hello world\n".to_vec()]
+    );
+    for name in &["original", "copy"] {
+        let found = archive_of(&db, &first.snapshot, &root.join(name));
+        assert_eq!(found.as_ref(), Some(&key));
+    }
+
+    // New copy is not uploaded too.
+    // Renamed file keeps it's identifier, so it is not even read.
+    std::fs::rename(root.join("copy"), root.join("renamed")).unwrap();
+    write(&root, "another", "Hello world\n");
+    // Only the directory is uploaded, since it's modification time changed.
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    assert!(files_in(&storage, &second.archives[0].key).await.is_empty());
+    assert_eq!(second.deduplicated, 1);
+    assert_eq!(second.moved, 1);
+
+    // Touched file has the same checksum as it's previous version.
+    write(&root, "original", "Hello world\n");
+    let third = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert!(third.archives.is_empty());
+    assert_eq!(third.moved, 1);
+    for name in &["original", "renamed", "another"] {
+        let found = archive_of(&db, &third.snapshot, &root.join(name));
+        assert_eq!(found.as_ref(), Some(&key));
+    }
+}
+
+#[tokio::test]
+async fn copies_are_deduplicated_by_default() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "original", "Hello world\n");
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions::default();
+    let mut db = Database::open(&db_path).unwrap();
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(first.archives.len(), 1);
+    let key = first.archives[0].key.clone();
+
+    // Copy has another identifier, so it is neither unchanged nor moved.
+    std::fs::copy(root.join("original"), root.join("copy")).unwrap();
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    assert!(files_in(&storage, &second.archives[0].key).await.is_empty());
+    assert_eq!(second.deduplicated, 1);
+    assert_eq!(second.moved, 0);
+    assert_eq!(
+        archive_of(&db, &second.snapshot, &root.join("copy")),
+        Some(key)
+    );
+}
+
+#[tokio::test]
+async fn oversized_files_are_skipped() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "small", "Hello world\n");
+    std::fs::write(root.join("large"), vec![0; 1000]).unwrap();
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        max_size: 100,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    // The other one holds the directory.
+    assert_eq!(report.archives.len(), 2);
+    let key = archive_of(&db, &report.snapshot, &root.join("small")).unwrap();
+    assert_eq!(
+        files_in(&storage, &key).await,
+        vec![b"Hello world\n".to_vec()]
+    );
+    assert_eq!(report.oversized, 1);
+    let large = EncodedPath::from_path(root.join("large")).cast::<External>();
+    assert_eq!(
+        db.oversized_files(&report.snapshot).unwrap(),
+        vec![(large, 1000)]
+    );
+}
+
+#[tokio::test]
+async fn request_budget() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    // Contents are different, so nothing is deduplicated.
+    for name in &["a", "b", "c"] {
+        std::fs::write(root.join(name), name.repeat(300)).unwrap();
+    }
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let mut db = Database::open(&db_path).unwrap();
+    // Two archives can't be merged, so the third one is deferred,
+    // together with the archive of the directory.
+    let options = BackupOptions {
+        min_size: 1,
+        max_size: 500,
+        max_requests: Some(2),
+        ..BackupOptions::default()
+    };
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.archives.len(), 2);
+    assert_eq!(report.deferred, 2);
+    assert_eq!(db.deferred_files().unwrap().len(), 1);
+
+    // Deferred file is uploaded with new ones, all of them are merged into single archive.
+    for name in &["d", "e"] {
+        std::fs::write(root.join(name), name.repeat(300)).unwrap();
+    }
+    let options = BackupOptions {
+        min_size: 1,
+        max_requests: Some(1),
+        ..BackupOptions::default()
+    };
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.resumed, 1);
+    assert_eq!(report.deferred, 0);
+    assert_eq!(report.archives.len(), 1);
+    assert_eq!(files_in(&storage, &report.archives[0].key).await.len(), 3);
+    assert!(db.deferred_files().unwrap().is_empty());
+    for name in &["a", "b", "c", "d", "e"] {
+        assert!(archive_of(&db, &report.snapshot, &root.join(name)).is_some());
+    }
+}
+
+#[tokio::test]
+async fn checksums_are_recorded() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "first", "Hello world\n");
+    write(&root, "dir/second", "odd_named_file\n");
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        hash_workers: None,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+
+    // Files were not hashed while creating the snapshot, but they are hashed now.
+    let snapshot = db.readonly_snapshot(report.snapshot).unwrap();
+    let mut files = 0;
+    snapshot
+        .for_each::<_, Infallible>(|info| {
+            if info.size().is_some() {
+                let path = info.path.cast().to_path().unwrap();
+                assert_eq!(info.hash, Some(hash_file(&path).unwrap()));
+                files += 1;
+            }
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(files, 2);
+}
+
+#[tokio::test]
+async fn moved_by_checksum() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "original", "Hello world\n");
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        hash_workers: Some(1),
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    let key = archive_of(&db, &first.snapshot, &root.join("original"));
+
+    // Copy has another identifier, but the same checksum.
+    std::fs::copy(root.join("original"), root.join("copy")).unwrap();
+    std::fs::remove_file(root.join("original")).unwrap();
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    assert!(files_in(&storage, &second.archives[0].key).await.is_empty());
+    assert_eq!(second.deduplicated, 0);
+    assert_eq!(second.moved, 1);
+    assert_eq!(archive_of(&db, &second.snapshot, &root.join("copy")), key);
+}
+
+/// Number of files recorded in all archives, including references.
+fn archived_files(db: &Database) -> usize {
+    db.archives()
+        .unwrap()
+        .into_iter()
+        .map(|(id, _)| db.archive_files(id).unwrap().len())
+        .sum()
+}
+
+#[tokio::test]
+async fn unchanged_files_are_not_deduplicated_again() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "first", "Hello world\n");
+    write(&root, "second", "odd_named_file\n");
+
+    // Small files are packed together, and their checksums are recorded after archiving.
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        hash_workers: None,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(first.archives.len(), 1);
+    let recorded = archived_files(&db);
+    assert_eq!(recorded, 2);
+
+    for _ in 0..2 {
+        let next = backup(&mut db, &root, &storage, &options).await.unwrap();
+        assert!(next.archives.is_empty());
+        assert_eq!(next.deduplicated, 0);
+        assert_eq!(archived_files(&db), recorded);
+    }
+}
+
+#[tokio::test]
+async fn archives_are_extracted_without_index() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir/empty")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "dir/file", "Hello world\n");
+    std::os::unix::fs::symlink("dir/file", root.join("symlink")).unwrap();
+    make_fifo(&root.join("dir/fifo"), 0o600).unwrap();
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    backup(&mut db, &root, &storage, &options).await.unwrap();
+    // Only the new symlink and it's directory are uploaded.
+    std::os::unix::fs::symlink("file", root.join("dir/another")).unwrap();
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    std::mem::drop(db);
+
+    let output = temp.path().join("output");
+    let options = ExtractOptions {
+        existing: Existing::Overwrite,
+        ..ExtractOptions::default()
+    };
+    for archive in storage.list("").await.unwrap() {
+        let reader = storage.get(&archive.key).await.unwrap();
+        extract(reader, &output, &options).await.unwrap();
+    }
+    let restored = |name: &str| {
+        let mut result = output.clone();
+        result.extend(
+            root.join(name)
+                .components()
+                .filter(|x| matches!(x, Component::Normal(_))),
+        );
+        result
+    };
+    assert!(restored("dir/empty").is_dir());
+    assert_eq!(
+        std::fs::read_to_string(restored("dir/file")).unwrap(),
+        "Hello world\n"
+    );
+    assert_eq!(
+        std::fs::read_link(restored("symlink")).unwrap(),
+        Path::new("dir/file")
+    );
+    assert_eq!(
+        std::fs::read_link(restored("dir/another")).unwrap(),
+        Path::new("file")
+    );
+    let fifo = std::fs::symlink_metadata(restored("dir/fifo")).unwrap();
+    assert!(fifo.file_type().is_fifo());
+}
+
+#[tokio::test]
+async fn metadata_changes_are_archived() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    std::fs::write(root.join("large"), vec![1; 2000]).unwrap();
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1000,
+        hash_workers: None,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    backup(&mut db, &root, &storage, &options).await.unwrap();
+
+    // Large file is uploaded again, though only it's permissions are changed.
+    let mode = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(root.join("large"), mode).unwrap();
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    let key = &second.archives[0].key;
+    assert_eq!(files_in(&storage, key).await, vec![vec![1; 2000]]);
+    assert_eq!(
+        archive_of(&db, &second.snapshot, &root.join("large")).as_ref(),
+        Some(key)
+    );
+
+    // Checksum is known now, so the contents are deduplicated.
+    let mode = std::fs::Permissions::from_mode(0o640);
+    std::fs::set_permissions(root.join("large"), mode).unwrap();
+    let options = BackupOptions {
+        min_size: 1000,
+        ..BackupOptions::default()
+    };
+    let third = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert!(third.archives.is_empty());
+    assert_eq!(third.deduplicated, 1);
+}
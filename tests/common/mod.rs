@@ -0,0 +1,27 @@
+//! Fixtures shared by integration tests.
+// Every test crate uses only some of them.
+#![allow(dead_code)]
+
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::fileinfo::Info;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Creates an archive from all given files, with [restore script](colbak_lib::cpio::script)
+/// when `script` is set. Archive is checked to be as large as predicted.
+pub async fn archive(format: Format, script: bool, files: &[PathBuf]) -> Vec<u8> {
+    let mut archive = Archive::with_format(format);
+    archive.set_script(script);
+    for file in files {
+        archive.add(Info::new(file.clone()).await.unwrap());
+    }
+    let expected = archive.size();
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(buffer.len() as u64, expected);
+    buffer
+}
+
+pub fn write(root: &Path, name: &str, contents: &str) {
+    std::fs::write(root.join(name), contents).unwrap();
+}
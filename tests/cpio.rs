@@ -2,6 +2,7 @@ extern crate colbak_lib;
 
 use std::slice::SliceIndex;
 
+use colbak_lib::cpio::Format;
 use colbak_lib::fileinfo::Info;
 use tokio::io::AsyncReadExt;
 
@@ -30,6 +31,27 @@ const EMPTY: &[u8] = &[
     b'[', b']', // Empty json array
 ];
 
+const EMPTY_NEWC: &[u8] = concat!(
+    "070701",   // c_magic
+    "00000000", // c_ino
+    "00000000", // c_mode
+    "00000000", // c_uid
+    "00000000", // c_gid
+    "00000001", // c_nlink
+    "00000000", // c_mtime
+    "00000000", // c_filesize
+    "00000000", // c_devmajor
+    "00000000", // c_devminor
+    "00000000", // c_rdevmajor
+    "00000000", // c_rdevminor
+    "0000000B", // c_namesize: 11
+    "00000000", // c_check
+    "TRAILER!!!\0",
+    "\0\0\0", // Header and name are padded to multiple of four bytes
+    "[]",     // Empty json array
+)
+.as_bytes();
+
 fn normalize_header_at<P>(buffer: &mut [u8], expected: &[u8], position: P)
 where
     P: SliceIndex<[u8], Output = [u8]> + Clone,
@@ -41,7 +63,7 @@ where
     assert_eq!(&expected[0..=1], [0xC7, 0x71]);
 
     // Zero out c_dev. In our format that field stores higher bits of inode.
-    (&mut buffer[2..=3]).copy_from_slice(&[0, 0]);
+    buffer[2..=3].copy_from_slice(&[0, 0]);
 }
 
 #[tokio::test]
@@ -52,6 +74,14 @@ async fn empty() {
     assert_eq!(buffer, EMPTY);
 }
 
+#[tokio::test]
+async fn empty_newc() {
+    let mut archive = colbak_lib::cpio::Archive::with_format(Format::Newc);
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(buffer, EMPTY_NEWC);
+}
+
 #[tokio::test]
 async fn odd_named_file() {
     // SH: echo "tests/archive/odd" | cpio -o --io-size=1 --ignore-devno > tests/odd_named_file.cpio
@@ -1,4 +1,5 @@
 use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::{Archive, Format};
 use colbak_lib::fileinfo::Info;
 use std::io::Cursor;
 use tokio::io::AsyncReadExt;
@@ -86,3 +87,164 @@ async fn extract_json() {
     assert_eq!(files[2].size(), Some(15));
     assert_eq!(files[2].path.as_bytes(), b"tests/archive/odd");
 }
+
+#[tokio::test]
+async fn extract_newc() {
+    let mut archive = Archive::with_format(Format::Newc);
+    archive.add(Info::new("tests/archive/even".into()).await.unwrap());
+    archive.add(Info::new("tests/archive/foobar".into()).await.unwrap());
+    archive.add(Info::new("tests/archive/odd".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..6], b"070701");
+
+    let expected: [(&[u8], &[u8]); 3] = [
+        (b"tests/archive/even", b"even_named_file\n"),
+        (b"tests/archive/foobar", b"Hello world\n"),
+        (b"tests/archive/odd", b"odd_named_file\n"),
+    ];
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let mut contents = Vec::new();
+    for (path, content) in expected.iter() {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                contents.clear();
+                let info = f.info();
+                reader = f.drain_to(&mut contents).await.unwrap();
+                assert_eq!(info.path.as_bytes(), *path);
+                assert_eq!(info.size(), Some(content.len() as u64));
+                assert_eq!(contents, *content);
+            }
+            NextItem::End(_) => panic!(),
+        }
+    }
+
+    match reader.advance().await.unwrap() {
+        NextItem::End(end) => assert_eq!(end.files.unwrap().len(), 3),
+        NextItem::File(_) => panic!(),
+    }
+}
+
+/// Converts archive created by [`Archive`] to big-endian byte order.
+fn swap_byte_order(mut buffer: Vec<u8>) -> Vec<u8> {
+    let mut position = 0;
+    loop {
+        let header = &mut buffer[position..position + 26];
+        for word in header.chunks_exact_mut(2) {
+            word.swap(0, 1);
+        }
+        let namesize = u16::from_be_bytes([header[20], header[21]]) as usize;
+        let filesize = u32::from_be_bytes([header[22], header[23], header[24], header[25]]);
+        let name = &buffer[position + 26..position + 26 + namesize];
+        if name == b"TRAILER!!!\0" {
+            break buffer;
+        }
+        position += 26 + namesize + namesize % 2;
+        position += filesize as usize + filesize as usize % 2;
+    }
+}
+
+/// Reads all files from the archive, returning their names and contents.
+async fn read_all(buffer: Vec<u8>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let mut result = Vec::new();
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let mut contents = Vec::new();
+                let info = f.info();
+                reader = f.drain_to(&mut contents).await.unwrap();
+                assert_eq!(info.size().unwrap_or_default(), contents.len() as u64);
+                result.push((info.path.as_bytes().to_vec(), contents));
+            }
+            NextItem::End(_) => break result,
+        }
+    }
+}
+
+#[tokio::test]
+async fn extract_big_endian() {
+    let mut archive = Archive::new();
+    archive.add(Info::new("tests/archive/even".into()).await.unwrap());
+    archive.add(Info::new("tests/archive/odd".into()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    let buffer = swap_byte_order(buffer);
+    assert_eq!(&buffer[..2], [0x71, 0xC7]);
+    let files = read_all(buffer).await;
+    assert_eq!(
+        files,
+        vec![
+            (b"tests/archive/even".to_vec(), b"even_named_file\n".to_vec()),
+            (b"tests/archive/odd".to_vec(), b"odd_named_file\n".to_vec()),
+        ]
+    );
+}
+
+fn odc_entry(name: &[u8], mode: u32, data: &[u8]) -> Vec<u8> {
+    let mut entry = format!(
+        "070707{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+        0, 42, mode, 1000, 100, 1, 0, 1_600_000_000, name.len() + 1, data.len()
+    )
+    .into_bytes();
+    entry.extend_from_slice(name);
+    entry.push(0);
+    entry.extend_from_slice(data);
+    entry
+}
+
+#[tokio::test]
+async fn extract_odc() {
+    let mut buffer = odc_entry(b"dir", 0o040755, b"");
+    buffer.extend(odc_entry(b"dir/odd", 0o100644, b"odd_named_file\n"));
+    buffer.extend(odc_entry(b"TRAILER!!!", 0, b""));
+
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer.clone()));
+    match reader.advance().await.unwrap() {
+        NextItem::File(f) => {
+            let info = f.info();
+            assert_eq!(info.size(), None);
+            assert_eq!(info.inode, 42);
+            assert_eq!(info.user_id, 1000);
+            assert_eq!(info.modified_at.unix_timestamp(), 1_600_000_000);
+        }
+        NextItem::End(_) => panic!(),
+    }
+
+    let files = read_all(buffer).await;
+    assert_eq!(files[1], (b"dir/odd".to_vec(), b"odd_named_file\n".to_vec()));
+}
+
+fn crc_entry(name: &[u8], data: &[u8], check: u32) -> Vec<u8> {
+    let namesize = name.len() + 1;
+    let mut entry = format!(
+        "070702{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+        1, 0o100644, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, namesize, check
+    )
+    .into_bytes();
+    entry.extend_from_slice(name);
+    entry.push(0);
+    entry.resize(entry.len() + (4 - (110 + namesize) % 4) % 4, 0);
+    entry.extend_from_slice(data);
+    entry.resize(entry.len() + (4 - data.len() % 4) % 4, 0);
+    entry
+}
+
+#[tokio::test]
+async fn extract_crc() {
+    let data = b"Hello world\n";
+    let check = data.iter().map(|&x| u32::from(x)).sum();
+
+    let mut buffer = crc_entry(b"foobar", data, check);
+    buffer.extend(crc_entry(b"TRAILER!!!", b"", 0));
+    let files = read_all(buffer).await;
+    assert_eq!(files, vec![(b"foobar".to_vec(), data.to_vec())]);
+
+    let buffer = crc_entry(b"foobar", data, check + 1);
+    let reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    match reader.advance().await.unwrap() {
+        NextItem::File(f) => assert!(f.to_void().await.is_err()),
+        NextItem::End(_) => panic!(),
+    }
+}
@@ -0,0 +1,128 @@
+use colbak_lib::database::{Database, DiffRow, DiffType, SqlName};
+use colbak_lib::fileext::set_times;
+use colbak_lib::fileinfo::Info;
+use colbak_lib::path::{EncodedPath, External};
+use colbak_lib::xattr::{self, Xattr};
+use colbak_lib::DateTime;
+use std::path::Path;
+
+/// Creates snapshot with given files, changing their paths.
+async fn snapshot(db: &mut Database, name: &str, files: &[(&Path, &str)]) -> SqlName {
+    let name = SqlName::new(name.to_owned()).unwrap();
+    let mut snapshot = db.open_snapshot(name.clone()).unwrap();
+    let filler = snapshot.filler().unwrap();
+    for (file, path) in files {
+        let mut info = Info::new(file.to_path_buf()).await.unwrap();
+        info.path = EncodedPath::from_vec(path.as_bytes().to_vec()).cast();
+        filler.add_info(&info).unwrap();
+    }
+    filler.save().unwrap();
+    name
+}
+
+/// Returns all rows of the diff as `(kind, path)`.
+fn rows(db: &Database, before: SqlName, after: SqlName) -> Vec<(DiffType, Vec<u8>)> {
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+    let mut result = Vec::new();
+    diff.query()
+        .for_each::<_, ()>(|row| {
+            let path: &EncodedPath<External> = match &row {
+                DiffRow::Deleted { path, .. }
+                | DiffRow::Created { path, .. }
+                | DiffRow::Changed { path, .. }
+                | DiffRow::Moved { path, .. } => path,
+            };
+            result.push((row.kind(), path.as_bytes().to_vec()));
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+    result.sort_by(|a, b| a.1.cmp(&b.1));
+    result
+}
+
+#[tokio::test]
+async fn moved_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&db_path).unwrap();
+    let first = temp.path().join("first");
+    let second = temp.path().join("second");
+    std::fs::write(&first, "Hello world\n").unwrap();
+    std::fs::write(&second, "Something else\n").unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    // Same files are seen at other paths, while identifiers are the same.
+    let before = snapshot(&mut db, "before", &[(&first, "/a"), (&second, "/b")]).await;
+    let after = snapshot(&mut db, "after", &[(&first, "/c"), (&second, "/b")]).await;
+    assert_eq!(
+        rows(&db, before, after),
+        vec![(DiffType::Moved, b"/c".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn hard_links_are_not_moved() {
+    let temp = tempfile::tempdir().unwrap();
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&db_path).unwrap();
+    let file = temp.path().join("file");
+    std::fs::write(&file, "Hello world\n").unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    // Both paths share the identifier.
+    let before = snapshot(&mut db, "before", &[(&file, "/a"), (&file, "/b")]).await;
+    let after = snapshot(&mut db, "after", &[(&file, "/a"), (&file, "/b")]).await;
+    assert!(rows(&db, before, after).is_empty());
+
+    // Link is removed, so nothing is moved too.
+    let before = snapshot(&mut db, "linked", &[(&file, "/a"), (&file, "/b")]).await;
+    let after = snapshot(&mut db, "unlinked", &[(&file, "/a")]).await;
+    assert!(rows(&db, before, after).is_empty());
+}
+
+#[tokio::test]
+async fn xattr_changes() {
+    let temp = tempfile::tempdir().unwrap();
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&db_path).unwrap();
+    let file = temp.path().join("file");
+    std::fs::write(&file, "Hello world\n").unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    let before = snapshot(&mut db, "before", &[(&file, "/a")]).await;
+    let attribute = Xattr {
+        name: b"user.colbak.test".to_vec(),
+        value: b"value".to_vec(),
+    };
+    if let Err(e) = xattr::write(&file, &attribute) {
+        eprintln!("Extended attributes are not supported here: {}", e);
+        return;
+    }
+    let after = snapshot(&mut db, "after", &[(&file, "/a")]).await;
+    assert_eq!(
+        rows(&db, before, after),
+        vec![(DiffType::Changed, b"/a".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn access_time_is_ignored() {
+    let temp = tempfile::tempdir().unwrap();
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&db_path).unwrap();
+    let file = temp.path().join("file");
+    std::fs::write(&file, "Hello world\n").unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    // Access time older than modification time is updated by reading, unless it's disabled.
+    let modified = Info::new(file.clone()).await.unwrap().modified_at;
+    let accessed = DateTime::from_unix_timestamp(1_000_000_000);
+    set_times(&file, Some(accessed), modified).unwrap();
+    let before = snapshot(&mut db, "before", &[(&file, "/a")]).await;
+    std::fs::read(&file).unwrap();
+    let after = snapshot(&mut db, "after", &[(&file, "/a")]).await;
+    assert_eq!(rows(&db, before, after), vec![]);
+}
@@ -0,0 +1,195 @@
+use colbak_lib::backup::{backup, BackupOptions};
+use colbak_lib::cpio::Format;
+use colbak_lib::database::{Database, Hashing, SqlName};
+use colbak_lib::estimate::{estimate, Estimate, Prices};
+use colbak_lib::storage::s3::DEFAULT_PART_SIZE;
+use colbak_lib::storage::{FsStorage, StorageClass};
+use std::path::Path;
+
+/// Reads every directory and file once. With `relatime` only the first read after modification
+/// updates access time, so the following snapshots have the same metadata and archives the same size.
+fn read_everything(root: &Path) {
+    for entry in walkdir::WalkDir::new(root) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            std::fs::read(entry.path()).unwrap();
+        }
+    }
+}
+
+/// Creates hashed snapshot of the `root` and estimates uploading it relative to the last uploaded one.
+fn estimate_next(db: &mut Database, root: &Path, options: &BackupOptions) -> Estimate {
+    read_everything(root);
+    let base = match db.last_uploaded_snapshot().unwrap() {
+        Some(base) => base,
+        None => db.empty_snapshot().unwrap(),
+    };
+    let name = SqlName::now();
+    db.open_snapshot(name.clone())
+        .unwrap()
+        .hashing_filler(Hashing {
+            workers: 1,
+            reuse_from: Some(base.clone()),
+        })
+        .unwrap()
+        .fill(root)
+        .unwrap()
+        .save()
+        .unwrap();
+    let before = db.readonly_snapshot(base).unwrap();
+    let after = db.readonly_snapshot(name).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+    estimate(db, &diff, options, None, &Prices::default()).unwrap()
+}
+
+#[tokio::test]
+async fn matches_backup() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    std::fs::write(root.join("first"), "Hello world\n").unwrap();
+    std::fs::write(root.join("dir/second"), vec![b'x'; 100_000]).unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    read_everything(&root);
+    let name = SqlName::now();
+    db.open_snapshot(name.clone())
+        .unwrap()
+        .filler()
+        .unwrap()
+        .fill(&root)
+        .unwrap()
+        .save()
+        .unwrap();
+    let base = db.empty_snapshot().unwrap();
+    let prices = Prices::default();
+    let (standard, glacier) = {
+        let before = db.readonly_snapshot(base).unwrap();
+        let after = db.readonly_snapshot(name).unwrap();
+        let diff = db.compare_snapshots(&before, &after).unwrap();
+        let options = BackupOptions {
+            min_size: 1,
+            ..BackupOptions::default()
+        };
+        let part_size = Some(DEFAULT_PART_SIZE as u64);
+        let standard = estimate(&db, &diff, &options, part_size, &prices).unwrap();
+        let options = BackupOptions {
+            storage_class: StorageClass::Glacier,
+            ..options
+        };
+        // Larger archive is uploaded in parts.
+        let glacier = estimate(&db, &diff, &options, Some(50_000), &prices).unwrap();
+        (standard, glacier)
+    };
+
+    // Both directories are placed into the third archive.
+    assert_eq!(standard.archives, 3);
+    assert_eq!(standard.files, 4);
+    assert_eq!(standard.requests, 3);
+    assert_eq!(standard.billed_bytes, standard.bytes);
+    assert_eq!(standard.standard_billed_bytes, 0);
+    assert_eq!(standard.min_duration_cost, 0.0);
+    assert!(standard.bytes > 100_012);
+
+    assert_eq!(glacier.bytes, standard.bytes);
+    assert_eq!(glacier.requests, 2 + (2 + 3));
+    assert_eq!(glacier.billed_bytes, glacier.bytes + 3 * 32 * 1024);
+    assert_eq!(glacier.standard_billed_bytes, 3 * 8 * 1024);
+    assert!(glacier.min_duration_cost > glacier.monthly_storage_cost);
+    assert!(glacier.retrieval_cost > standard.retrieval_cost);
+
+    // Archives are exactly as large as estimated.
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        ..BackupOptions::default()
+    };
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    let uploaded: u64 = report.archives.iter().map(|x| x.size).sum();
+    assert_eq!(uploaded, standard.bytes);
+}
+
+#[tokio::test]
+async fn duplicates_and_moved_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    // Names have the same length, so archive size does not depend on which one is uploaded.
+    std::fs::write(root.join("first"), "Hello world\n").unwrap();
+    std::fs::write(root.join("third"), "Hello world\n").unwrap();
+    std::fs::write(root.join("dir/second"), vec![b'x'; 100_000]).unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        format: Format::Newc,
+        script: true,
+        ..BackupOptions::default()
+    };
+
+    // Estimated archives include the restore script and manifest.
+    let estimated = estimate_next(&mut db, &root, &options);
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(estimated.deduplicated, 1);
+    assert_eq!(report.deduplicated, 1);
+    assert_eq!(estimated.archives, report.archives.len() as u64);
+    let uploaded: u64 = report.archives.iter().map(|x| x.size).sum();
+    assert_eq!(uploaded, estimated.bytes);
+
+    // Moved file is not uploaded again, only it's directory is.
+    std::fs::rename(root.join("dir/second"), root.join("dir/renamed")).unwrap();
+    let estimated = estimate_next(&mut db, &root, &options);
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(estimated.moved, 1);
+    assert_eq!(report.moved, 1);
+    assert_eq!(estimated.deduplicated, 0);
+    assert!(estimated.bytes < 100_000);
+    let uploaded: u64 = report.archives.iter().map(|x| x.size).sum();
+    assert_eq!(uploaded, estimated.bytes);
+}
+
+#[tokio::test]
+async fn deferred_files_are_not_touched() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    for name in &["a", "b", "c"] {
+        std::fs::write(root.join(name), name.repeat(300)).unwrap();
+    }
+    let mut db = Database::open(&db_path).unwrap();
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        max_size: 500,
+        max_requests: Some(2),
+        ..BackupOptions::default()
+    };
+    // Directory is deferred together with one of the files.
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.deferred, 2);
+    let deferred = db.deferred_files().unwrap();
+
+    // Deferred file and directory are estimated, but they are still deferred.
+    let options = BackupOptions {
+        min_size: 1,
+        ..BackupOptions::default()
+    };
+    let estimated = estimate_next(&mut db, &root, &options);
+    assert_eq!(estimated.files, 2);
+    assert_eq!(db.deferred_files().unwrap(), deferred);
+
+    // Backup would forget deleted files, estimate does not.
+    for name in &["a", "b", "c"] {
+        std::fs::remove_file(root.join(name)).unwrap();
+    }
+    // Only the directory is left.
+    let estimated = estimate_next(&mut db, &root, &options);
+    assert_eq!(estimated.files, 1);
+    assert_eq!(db.deferred_files().unwrap(), deferred);
+}
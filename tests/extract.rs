@@ -0,0 +1,289 @@
+mod common;
+
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::extract::{extract, Existing, ExtractOptions, Owners};
+use colbak_lib::fileext::set_times;
+use colbak_lib::fileinfo::{Info, SymlinkInfo, UnspecifiedInfo};
+use colbak_lib::path::{EncodedPath, Local};
+use colbak_lib::DateTime;
+use common::archive;
+use std::io::Cursor;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Where file from `path` is placed when extracting to `output`.
+fn extracted(output: &Path, path: &Path) -> PathBuf {
+    let mut result = output.to_path_buf();
+    result.extend(
+        path.components()
+            .filter(|x| matches!(x, Component::Normal(_))),
+    );
+    result
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// Lists `root` with everything inside, parents go first.
+fn walk(root: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .collect()
+}
+
+async fn check(format: Format) {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    std::fs::create_dir_all(root.join("private/nested")).unwrap();
+    std::fs::write(root.join("private/nested/file"), "Hello world\n").unwrap();
+    std::fs::write(root.join("shared"), "odd_named_file\n").unwrap();
+    set_mode(&root.join("shared"), 0o640);
+    set_mode(&root.join("private/nested/file"), 0o600);
+    // Listing directories changes their access time, so it's done before setting times.
+    let files = walk(&root);
+    let modified = DateTime::from_unix_timestamp_nanos(1_000_000_000_123_456_789);
+    let accessed = DateTime::from_unix_timestamp_nanos(1_100_000_000_000_000_000);
+    for path in &["shared", "private/nested/file", "private/nested", "private"] {
+        set_times(&root.join(path), Some(accessed), modified).unwrap();
+    }
+    // Directory is read-only, so its permissions must be set after children are written.
+    set_mode(&root.join("private"), 0o500);
+
+    let buffer = archive(format, false, &files).await;
+    let output = temp.path().join("output");
+    let mut options = ExtractOptions::default();
+    // Files are given to the current user, whoever owned them.
+    let uid = std::fs::metadata(&root).unwrap().uid();
+    let gid = std::fs::metadata(&root).unwrap().gid();
+    options.owners = Owners {
+        keep: false,
+        ..Owners::default()
+    };
+    options
+        .owners
+        .map_user(&format!("{}={}", uid, uid))
+        .unwrap();
+    options
+        .owners
+        .map_group(&format!("{}={}", gid, gid))
+        .unwrap();
+    let report = extract(Cursor::new(buffer), &output, &options)
+        .await
+        .unwrap();
+    set_mode(&root.join("private"), 0o700);
+
+    assert_eq!(report.entries, 5);
+    assert!(report.mismatches.is_empty());
+    let restored = extracted(&output, &root);
+    let mode = |path: &str| {
+        let meta = std::fs::symlink_metadata(restored.join(path)).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+        meta.permissions().mode() & 0o7777
+    };
+    assert_eq!(mode("shared"), 0o640);
+    assert_eq!(mode("private/nested/file"), 0o600);
+    assert_eq!(mode("private"), 0o500);
+    for path in &["shared", "private/nested/file", "private/nested", "private"] {
+        let meta = std::fs::metadata(restored.join(path)).unwrap();
+        // Nanoseconds are taken from the trailer.
+        assert_eq!(meta.mtime(), 1_000_000_000, "{}", path);
+        assert_eq!(meta.mtime_nsec(), 123_456_789, "{}", path);
+        assert_eq!(meta.atime(), 1_100_000_000, "{}", path);
+    }
+    assert_eq!(
+        std::fs::read(restored.join("private/nested/file")).unwrap(),
+        b"Hello world\n"
+    );
+    set_mode(&restored.join("private"), 0o700);
+}
+
+#[tokio::test]
+async fn metadata_is_restored() {
+    check(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn metadata_is_restored_newc() {
+    check(Format::Newc).await;
+}
+
+/// Entry with the given name, which has no data stored in the archive.
+async fn fake(name: &str, data: UnspecifiedInfo) -> Info<Local> {
+    let mut info = Info::new(PathBuf::from("/")).await.unwrap();
+    info.path = EncodedPath::from_vec(name.as_bytes().to_vec()).cast();
+    info.data = data;
+    info
+}
+
+fn symlink(target: &Path) -> UnspecifiedInfo {
+    UnspecifiedInfo::Symlink(SymlinkInfo {
+        target: target.to_str().unwrap().as_bytes().to_vec(),
+    })
+}
+
+#[tokio::test]
+async fn stays_inside_output() {
+    let temp = tempfile::tempdir().unwrap();
+    let outside = temp.path().join("outside");
+    let output = temp.path().join("output");
+    std::fs::create_dir_all(&outside).unwrap();
+
+    let mut archive = Archive::with_format(Format::Newc);
+    let dir = UnspecifiedInfo::Dir(Default::default());
+    archive.add(fake("../escaped", dir.clone()).await);
+    archive.add(fake("/absolute/dir", dir.clone()).await);
+    archive.add(fake("link", symlink(&outside)).await);
+    archive.add(fake("link/planted", dir.clone()).await);
+    archive.add(fake("link/../../escaped", dir).await);
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+
+    let report = extract(Cursor::new(buffer), &output, &ExtractOptions::default())
+        .await
+        .unwrap();
+    let skipped: Vec<_> = report.skipped.iter().map(|x| x.as_bytes()).collect();
+    let expected: [&[u8]; 3] = [b"../escaped", b"link/planted", b"link/../../escaped"];
+    assert_eq!(skipped, expected);
+    assert!(output.join("absolute/dir").is_dir());
+    assert_eq!(std::fs::read_link(output.join("link")).unwrap(), outside);
+    assert!(!temp.path().join("escaped").exists());
+    assert!(!outside.join("planted").exists());
+}
+
+/// Extracts `file` over `existing` contents, returns contents of the output directory.
+async fn extract_over(existing: Existing, older: bool) -> Vec<(String, String)> {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("root/file");
+    std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+    std::fs::write(&file, "new").unwrap();
+    let archived = DateTime::from_unix_timestamp_nanos(1_000_000_000_000_000_000);
+    set_times(&file, None, archived).unwrap();
+    let buffer = archive(Format::Binary, false, std::slice::from_ref(&file)).await;
+
+    let output = temp.path().join("output");
+    let placed = extracted(&output, &file);
+    std::fs::create_dir_all(placed.parent().unwrap()).unwrap();
+    std::fs::write(&placed, "old").unwrap();
+    let offset = if older { -1 } else { 1 };
+    let modified = DateTime::from_unix_timestamp(1_000_000_000 + offset);
+    set_times(&placed, None, modified).unwrap();
+
+    let options = ExtractOptions {
+        existing,
+        ..ExtractOptions::default()
+    };
+    extract(Cursor::new(buffer), &output, &options)
+        .await
+        .unwrap();
+    let mut result: Vec<_> = std::fs::read_dir(placed.parent().unwrap())
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            (name, std::fs::read_to_string(path).unwrap())
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+#[tokio::test]
+async fn existing_files() {
+    let entry = |name: &str, contents: &str| (name.to_owned(), contents.to_owned());
+    let old = vec![entry("file", "old")];
+    let new = vec![entry("file", "new")];
+    assert_eq!(extract_over(Existing::Skip, true).await, old);
+    assert_eq!(extract_over(Existing::Overwrite, false).await, new);
+    assert_eq!(extract_over(Existing::IfNewer, true).await, new);
+    assert_eq!(extract_over(Existing::IfNewer, false).await, old);
+    assert_eq!(
+        extract_over(Existing::KeepBoth, true).await,
+        vec![entry("file", "old"), entry("file.~1~", "new")]
+    );
+}
+
+#[tokio::test]
+async fn existing_symlink_is_not_followed() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("root/file");
+    std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+    std::fs::write(&file, "new").unwrap();
+    let buffer = archive(Format::Binary, false, std::slice::from_ref(&file)).await;
+
+    let output = temp.path().join("output");
+    let placed = extracted(&output, &file);
+    std::fs::create_dir_all(placed.parent().unwrap()).unwrap();
+    let target = temp.path().join("target");
+    std::fs::write(&target, "old").unwrap();
+    std::os::unix::fs::symlink(&target, &placed).unwrap();
+
+    let options = ExtractOptions {
+        existing: Existing::Overwrite,
+        ..ExtractOptions::default()
+    };
+    extract(Cursor::new(buffer), &output, &options)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "old");
+    assert_eq!(std::fs::read_to_string(&placed).unwrap(), "new");
+    assert!(!std::fs::symlink_metadata(&placed)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+/// Entry of newc archive, like GNU cpio writes it.
+fn newc_entry(name: &[u8], inode: u32, links: u32, data: &[u8]) -> Vec<u8> {
+    let fields = [
+        inode,
+        0o100644,
+        1000,
+        100,
+        links,
+        1_600_000_000,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    let mut entry = b"070701".to_vec();
+    for field in &fields {
+        entry.extend(format!("{:08X}", field).into_bytes());
+    }
+    entry.extend_from_slice(name);
+    entry.push(0);
+    entry.resize(entry.len().next_multiple_of(4), 0);
+    entry.extend_from_slice(data);
+    entry.resize(entry.len().next_multiple_of(4), 0);
+    entry
+}
+
+#[tokio::test]
+async fn gnu_hard_links() {
+    let temp = tempfile::tempdir().unwrap();
+    let output = temp.path().join("output");
+    // Data is stored with the last link, while others are empty.
+    let mut buffer = newc_entry(b"first", 7, 3, b"");
+    buffer.extend(newc_entry(b"second", 7, 3, b""));
+    buffer.extend(newc_entry(b"third", 7, 3, b"Hello world\n"));
+    buffer.extend(newc_entry(b"TRAILER!!!", 0, 1, b""));
+    buffer.resize(512, 0);
+
+    let report = extract(Cursor::new(buffer), &output, &ExtractOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(report.entries, 3);
+    let inode = std::fs::metadata(output.join("third")).unwrap().ino();
+    for name in &["first", "second", "third"] {
+        let path = output.join(name);
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), inode, "{}", name);
+        assert_eq!(std::fs::read(&path).unwrap(), b"Hello world\n");
+    }
+}
@@ -0,0 +1,82 @@
+use colbak_lib::storage::{FsStorage, PutOptions, Storage, StorageClass};
+use std::io::Cursor;
+use tokio::io::AsyncReadExt;
+
+#[tokio::test]
+async fn put_get_list_delete() {
+    let root = tempfile::tempdir().unwrap();
+    let storage = FsStorage::new(root.path().to_path_buf());
+    assert!(storage.list("").await.unwrap().is_empty());
+
+    let options = PutOptions {
+        storage_class: StorageClass::DeepArchive,
+        metadata: vec![("snapshot".to_owned(), "foo".to_owned())],
+    };
+    let info = storage
+        .put("archives/first.cpio", &mut Cursor::new(b"Hello world\n"), &options)
+        .await
+        .unwrap();
+    assert_eq!(info.size, 12);
+    storage
+        .put("archives/second.cpio", &mut Cursor::new(b"odd"), &PutOptions::default())
+        .await
+        .unwrap();
+    storage
+        .put("other.cpio", &mut Cursor::new(b""), &PutOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read(root.path().join("archives/first.cpio")).unwrap(),
+        b"Hello world\n"
+    );
+
+    let listed = storage.list("archives/").await.unwrap();
+    let keys: Vec<_> = listed.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(keys, ["archives/first.cpio", "archives/second.cpio"]);
+    assert_eq!(listed[0], info);
+    assert_eq!(listed[1].storage_class, StorageClass::Standard);
+
+    let mut contents = Vec::new();
+    let mut reader = storage.get("archives/first.cpio").await.unwrap();
+    reader.read_to_end(&mut contents).await.unwrap();
+    assert_eq!(contents, b"Hello world\n");
+
+    storage.delete("archives/first.cpio").await.unwrap();
+    storage.delete("archives/first.cpio").await.unwrap();
+    let keys: Vec<_> = storage
+        .list("")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|x| x.key)
+        .collect();
+    assert_eq!(keys, ["archives/second.cpio", "other.cpio"]);
+}
+
+#[tokio::test]
+async fn overwrite() {
+    let root = tempfile::tempdir().unwrap();
+    let storage = FsStorage::new(root.path().to_path_buf());
+    for data in [&b"first version"[..], &b"second"[..]].iter() {
+        storage
+            .put("file", &mut Cursor::new(data), &PutOptions::default())
+            .await
+            .unwrap();
+    }
+    let listed = storage.list("").await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].size, 6);
+}
+
+#[tokio::test]
+async fn invalid_keys() {
+    let root = tempfile::tempdir().unwrap();
+    let storage = FsStorage::new(root.path().join("storage"));
+    for key in ["", "/etc/passwd", "../escape", "foo/./bar", "foo//bar", "foo.colbak-meta"].iter() {
+        let result = storage
+            .put(key, &mut Cursor::new(b""), &PutOptions::default())
+            .await;
+        assert!(result.is_err(), "{}", key);
+    }
+    assert!(!root.path().join("escape").exists());
+}
@@ -0,0 +1,311 @@
+use colbak_lib::database::{Database, DiffRow, RowId, SqlName};
+use colbak_lib::fileinfo::{FileInfo, Info, UnspecifiedInfo};
+use colbak_lib::packer::{self, Candidate, PackLimits, PackStrategy, Strategy};
+use colbak_lib::path::{EncodedPath, External, Local};
+use colbak_lib::DateTime;
+use smallvec::SmallVec;
+use std::cell::Cell;
+use std::path::Path;
+
+fn snapshot(db: &mut Database, name: &str, root: &Path) -> SqlName {
+    let name = SqlName::new(name.to_owned()).unwrap();
+    db.open_snapshot(name.clone())
+        .unwrap()
+        .filler()
+        .unwrap()
+        .fill(root)
+        .unwrap()
+        .save()
+        .unwrap();
+    name
+}
+
+/// Fills snapshot with `count` files without touching the filesystem.
+/// File `i` is `i % 1000 + 1` bytes, and every directory holds 100 files.
+fn synthetic(db: &mut Database, name: &str, count: u64) -> SqlName {
+    let name = SqlName::new(name.to_owned()).unwrap();
+    let mut snapshot = db.open_snapshot(name.clone()).unwrap();
+    let filler = snapshot.filler().unwrap();
+    for i in 0..count {
+        let path = format!("/data/{}/{}/{}", i / 10000, i / 100, i);
+        let info = Info {
+            path: EncodedPath::from_vec(path.into_bytes()).cast::<Local>(),
+            inode: i,
+            mode: 0o100_644,
+            user_id: 0,
+            group_id: 0,
+            created_at: DateTime::unix_epoch(),
+            modified_at: DateTime::unix_epoch(),
+            accessed_at: None,
+            changed_at: None,
+            device: 0,
+            blocks: 0,
+            hash: None,
+            xattrs: Vec::new(),
+            data: UnspecifiedInfo::File(FileInfo::new(i % 1000 + 1)),
+        };
+        filler.add_info(&info).unwrap();
+    }
+    filler.save().unwrap();
+    name
+}
+
+#[test]
+fn max_size() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+    let before = snapshot(&mut db, "before", &root);
+
+    // Small files are grouped together.
+    for i in 0..5 {
+        std::fs::write(root.join(format!("small{}", i)), "Hello world\n").unwrap();
+    }
+    std::fs::write(root.join("large"), vec![0; 2000]).unwrap();
+    let after = snapshot(&mut db, "after", &root);
+
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+    let packed = packer::pack(&diff, &packer::ByDirectory, &PackLimits::new(1000, 30)).unwrap();
+
+    assert_eq!(packed.oversized, 1);
+    let mut oversized = Vec::new();
+    packer::for_each_oversized(&diff, 1000, |file| {
+        oversized.push(file);
+        Ok::<_, ()>(())
+    })
+    .unwrap()
+    .unwrap();
+    assert_eq!(oversized.len(), 1);
+    assert_eq!(oversized[0].size, 2000);
+    assert!(oversized[0].path.as_bytes().ends_with(b"/large"));
+    assert!(!packed.packs.is_empty());
+    for pack in &packed.packs {
+        let total: u64 = pack
+            .iter()
+            .map(|&rowid| match diff.query().get(rowid).unwrap() {
+                // Root directory is changed, since files are created in it.
+                DiffRow::Created { size, .. } | DiffRow::Changed { size, .. } => size,
+                row => panic!("Unexpected row: {:?}", row),
+            })
+            .sum();
+        assert!(total <= 30, "{}", total);
+    }
+}
+
+#[test]
+fn size_window() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    for dir in &["first", "second"] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        for i in 0..4 {
+            std::fs::write(root.join(dir).join(i.to_string()), vec![0; 300]).unwrap();
+        }
+    }
+    std::fs::write(root.join("lonely"), vec![0; 100]).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+    let before = db.empty_snapshot().unwrap();
+    let after = snapshot(&mut db, "after", &root);
+
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+    let packed = packer::pack(&diff, &packer::ByDirectory, &PackLimits::new(1000, 1500)).unwrap();
+    assert_eq!(packed.oversized, 0);
+
+    let mut sizes = Vec::new();
+    for pack in &packed.packs {
+        let mut total = 0;
+        let mut parents = Vec::new();
+        for &rowid in pack {
+            match diff.query().get(rowid).unwrap() {
+                DiffRow::Created { size, path, .. } => {
+                    total += size;
+                    let path = path.as_bytes();
+                    let slash = path.iter().rposition(|&x| x == b'/').unwrap();
+                    parents.push(path[..slash].to_vec());
+                }
+                row => panic!("Unexpected row: {:?}", row),
+            }
+        }
+        // Files from the same directory are kept together.
+        if total >= 1000 {
+            parents.dedup();
+            assert_eq!(parents.len(), 1);
+        }
+        sizes.push(total);
+    }
+    // Every small file is packed, and only the last pack is smaller than needed.
+    assert_eq!(sizes, vec![1200, 1200, 100]);
+}
+
+#[test]
+fn strategies() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    for dir in &["first", "second"] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        for name in &["0.txt", "1.log", "2.TXT", "3.log"] {
+            std::fs::write(root.join(dir).join(name), vec![0; 300]).unwrap();
+        }
+    }
+    std::fs::create_dir_all(&db_path).unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+    let before = db.empty_snapshot().unwrap();
+    let after = snapshot(&mut db, "after", &root);
+
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+    // Directories are packed too, but only files are checked.
+    let paths = |packed: &packer::Packed| -> Vec<Vec<EncodedPath<External>>> {
+        packed
+            .packs
+            .iter()
+            .map(|pack| -> Vec<_> {
+                pack.iter()
+                    .filter_map(|&rowid| match diff.query().get(rowid).unwrap() {
+                        DiffRow::Created { path, after, .. } => after.size().map(|_| path),
+                        row => panic!("Unexpected row: {:?}", row),
+                    })
+                    .collect()
+            })
+            .filter(|pack| !pack.is_empty())
+            .collect()
+    };
+
+    let strategy: Strategy = "extension".parse().unwrap();
+    let packed = packer::pack(&diff, &strategy, &PackLimits::new(1000, 1500)).unwrap();
+    let packs = paths(&packed);
+    assert_eq!(packs.len(), 2);
+    for pack in packs {
+        assert_eq!(pack.len(), 4);
+        let mut extensions: Vec<_> = pack.iter().map(packer::extension).collect();
+        extensions.dedup();
+        assert_eq!(extensions.len(), 1);
+    }
+
+    // Every file is packed by any strategy.
+    for strategy in &[Strategy::Directory, Strategy::Extension, Strategy::Age] {
+        let packed = packer::pack(&diff, strategy, &PackLimits::new(1000, 1500)).unwrap();
+        let count: usize = paths(&packed).iter().map(Vec::len).sum();
+        assert_eq!(count, 8);
+    }
+    assert!("unknown".parse::<Strategy>().is_err());
+}
+
+/// Packs synthetic diff and checks that every file is packed exactly once.
+fn check_bounded(count: u64, limits: &PackLimits) {
+    let temp = tempfile::tempdir().unwrap();
+    let mut db = Database::open(temp.path()).unwrap();
+    let before = db.empty_snapshot().unwrap();
+    let after = synthetic(&mut db, "after", count);
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+
+    let packed = packer::pack(&diff, &packer::ByDirectory, limits).unwrap();
+    let mut rowids: Vec<u64> = Vec::new();
+    for pack in &packed.packs {
+        assert!(pack.len() <= limits.max_files());
+        rowids.extend(pack.iter().map(|x| x.0));
+    }
+    let total = rowids.len();
+    rowids.sort_unstable();
+    rowids.dedup();
+    assert_eq!(rowids.len(), total);
+    assert_eq!(total as u64, count);
+}
+
+#[test]
+fn bounded_memory() {
+    let limits = PackLimits {
+        // Strategy sees 100 files at once, and pack has at most 50 files.
+        memory: 100 * 1024,
+        ..PackLimits::new(u64::MAX, u64::MAX)
+    };
+    assert_eq!(limits.max_candidates(), 100);
+    assert_eq!(limits.max_files(), 50);
+    check_bounded(5000, &limits);
+}
+
+/// Fills snapshot with `count` files by a single SQL statement, like [`synthetic`](synthetic) does.
+/// Only sizes and paths are meaningful, every row has the same info otherwise.
+fn bulk_synthetic(db: &mut Database, root: &Path, name: &str, count: u64) -> SqlName {
+    let name = SqlName::new(name.to_owned()).unwrap();
+    db.open_snapshot(name.clone()).unwrap();
+    let template = Info {
+        path: EncodedPath::from_vec(b"/data".to_vec()).cast::<Local>(),
+        inode: 0,
+        mode: 0o100_644,
+        user_id: 0,
+        group_id: 0,
+        created_at: DateTime::unix_epoch(),
+        modified_at: DateTime::unix_epoch(),
+        accessed_at: None,
+        changed_at: None,
+        device: 0,
+        blocks: 0,
+        hash: None,
+        xattrs: Vec::new(),
+        data: UnspecifiedInfo::File(FileInfo::new(1)),
+    };
+    let template = serde_json::to_string(&template).unwrap();
+    let conn = rusqlite::Connection::open(root.join(format!("{}.db", name))).unwrap();
+    conn.execute(
+        "WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i + 1 < ?1)
+        INSERT INTO snap(path, identifier, info, size)
+        SELECT
+            CAST('/data/' || (i / 10000) || '/' || (i / 100) || '/' || i AS BLOB),
+            CAST(printf('%020d', i) AS BLOB),
+            json_set(?2, '$.inode', i, '$.File.size', i % 1000 + 1),
+            i % 1000 + 1
+        FROM n",
+        rusqlite::params![count as i64, template],
+    )
+    .unwrap();
+    name
+}
+
+/// Groups files by directory, remembering the largest number of files grouped at once.
+#[derive(Default)]
+struct PeakCandidates(Cell<usize>);
+
+impl PackStrategy for PeakCandidates {
+    fn group(&self, files: Vec<Candidate>, limits: &PackLimits) -> Vec<SmallVec<[RowId; 4]>> {
+        self.0.set(self.0.get().max(files.len()));
+        packer::ByDirectory.group(files, limits)
+    }
+}
+
+#[test]
+fn millions_of_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let mut db = Database::open(temp.path()).unwrap();
+    let count = 2_000_000;
+    let before = db.empty_snapshot().unwrap();
+    let after = bulk_synthetic(&mut db, temp.path(), "after", count);
+    let before = db.readonly_snapshot(before).unwrap();
+    let after = db.readonly_snapshot(after).unwrap();
+    let diff = db.compare_snapshots(&before, &after).unwrap();
+
+    let limits = PackLimits::new(1024 * 1024, 64 * 1024 * 1024);
+    let strategy = PeakCandidates::default();
+    let packed = packer::pack(&diff, &strategy, &limits).unwrap();
+    // Files are grouped in parts, and every part is full except the last one.
+    assert_eq!(strategy.0.get(), limits.max_candidates());
+    let packed_files: usize = packed.packs.iter().map(|pack| pack.len()).sum();
+    assert_eq!(packed_files as u64, count);
+    assert!(packed
+        .packs
+        .iter()
+        .all(|pack| pack.len() <= limits.max_files()));
+}
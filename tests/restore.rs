@@ -0,0 +1,465 @@
+mod common;
+
+use async_trait::async_trait;
+use colbak_lib::backup::{backup, BackupOptions};
+use colbak_lib::cpio::Archive;
+use colbak_lib::database::{ArchiveRecord, Database, SqlName};
+use colbak_lib::extract::Existing;
+use colbak_lib::fileext::{make_fifo, set_times};
+use colbak_lib::fileinfo::Info;
+use colbak_lib::restore::{restore, Patterns, RestoreOptions, RestoreReport};
+use colbak_lib::storage::{self, FsStorage, ObjectInfo, PutOptions, Storage};
+use colbak_lib::DateTime;
+use common::write;
+use std::cell::RefCell;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncRead;
+
+/// Where file from `path` is placed when restoring to `output`.
+fn restored(output: &Path, path: &Path) -> PathBuf {
+    let mut result = output.to_path_buf();
+    result.extend(
+        path.components()
+            .filter(|x| matches!(x, Component::Normal(_))),
+    );
+    result
+}
+
+struct Setup {
+    temp: tempfile::TempDir,
+    root: PathBuf,
+    db: Database,
+    storage: FsStorage,
+}
+
+fn setup() -> Setup {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir/nested")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    write(&root, "first", "Hello world\n");
+    write(&root, "dir/second", "odd_named_file\n");
+    write(&root, "dir/nested/third", "even_named_file\n");
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let db = Database::open(&db_path).unwrap();
+    Setup {
+        temp,
+        root,
+        db,
+        storage,
+    }
+}
+
+#[tokio::test]
+async fn only_needed_archives() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    let options = BackupOptions {
+        // Every file will be placed into separate archive,
+        // and directories will be placed together into another one.
+        min_size: 1,
+        ..BackupOptions::default()
+    };
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(first.archives.len(), 4);
+    write(&root, "first", "Changed\n");
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+
+    let output = temp.path().join("output");
+    let patterns = Patterns::new(&[format!("{}/dir/**", root.display())]).unwrap();
+    let report = restore(
+        &db,
+        second.snapshot.clone(),
+        &patterns,
+        &storage,
+        &output,
+        &RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.archives.len(), 2);
+    assert_eq!(report.files, 2);
+    assert!(report.missing.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(restored(&output, &root.join("dir/second"))).unwrap(),
+        "odd_named_file\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(restored(&output, &root.join("dir/nested/third"))).unwrap(),
+        "even_named_file\n"
+    );
+    assert!(!restored(&output, &root.join("first")).exists());
+
+    // Older version is restored from older snapshot.
+    let output = temp.path().join("older");
+    let patterns = Patterns::new(&[format!("{}/first", root.display())]).unwrap();
+    let report = restore(
+        &db,
+        first.snapshot,
+        &patterns,
+        &storage,
+        &output,
+        &RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.archives.len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(restored(&output, &root.join("first"))).unwrap(),
+        "Hello world\n"
+    );
+}
+
+#[tokio::test]
+async fn skips_other_files() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    let name = SqlName::now();
+    db.open_snapshot(name.clone())
+        .unwrap()
+        .filler()
+        .unwrap()
+        .fill(&root)
+        .unwrap()
+        .save()
+        .unwrap();
+
+    // All files are placed into single archive.
+    let mut archive = Archive::new();
+    for file in &["first", "dir/second", "dir/nested/third"] {
+        archive.add(Info::new(root.join(file)).await.unwrap());
+    }
+    let key = "everything.cpio".to_owned();
+    let uploaded = {
+        let reader = archive.read();
+        tokio::pin!(reader);
+        storage
+            .put(&key, &mut reader, &PutOptions::default())
+            .await
+            .unwrap()
+    };
+    let record = ArchiveRecord {
+        key: key.clone(),
+        snapshot: name.as_str().to_owned(),
+        size: uploaded.size,
+        checksum: None,
+        storage_class: uploaded.storage_class,
+        uploaded_at: DateTime::now_utc(),
+    };
+    let files = archive.files().iter().map(|x| (&x.info, x.calculated));
+    db.add_archive(&record, files).unwrap();
+
+    let output = temp.path().join("output");
+    let patterns = Patterns::new(&[format!("{}/dir/*", root.display())]).unwrap();
+    let report = restore(
+        &db,
+        name,
+        &patterns,
+        &storage,
+        &output,
+        &RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.archives, vec![key]);
+    assert_eq!(report.files, 1);
+    assert_eq!(
+        std::fs::read_to_string(restored(&output, &root.join("dir/second"))).unwrap(),
+        "odd_named_file\n"
+    );
+    // `*` does not match nested directories, but directory itself is restored.
+    assert!(restored(&output, &root.join("dir/nested")).is_dir());
+    assert!(!restored(&output, &root.join("dir/nested/third")).exists());
+    assert!(!restored(&output, &root.join("first")).exists());
+}
+
+#[tokio::test]
+async fn many_archives_at_once() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    // Contents are different, so nothing is deduplicated.
+    let names: Vec<_> = (0..10).map(|x| format!("file{}", x)).collect();
+    for (idx, name) in names.iter().enumerate() {
+        write(&root, name, &name.repeat(idx + 1));
+    }
+    let options = BackupOptions {
+        min_size: 1,
+        concurrency: 3,
+        ..BackupOptions::default()
+    };
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.archives.len(), 14);
+    let mut keys: Vec<_> = report.archives.iter().map(|x| x.key.clone()).collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        report
+            .archives
+            .iter()
+            .map(|x| x.key.clone())
+            .collect::<Vec<_>>()
+    );
+
+    let output = temp.path().join("output");
+    let restored_report = restore(
+        &db,
+        report.snapshot,
+        &Patterns::default(),
+        &storage,
+        &output,
+        &RestoreOptions {
+            concurrency: 3,
+            ..RestoreOptions::default()
+        },
+    )
+    .await
+    .unwrap();
+    // Directories are created from the snapshot, so their archive is not downloaded.
+    assert_eq!(restored_report.archives.len(), 13);
+    assert!(restored_report.archives.iter().all(|key| keys.contains(key)));
+    assert_eq!(restored_report.files, 13);
+    for (idx, name) in names.iter().enumerate() {
+        assert_eq!(
+            std::fs::read_to_string(restored(&output, &root.join(name))).unwrap(),
+            name.repeat(idx + 1)
+        );
+    }
+}
+
+/// Remembers keys of downloaded objects, in the order of downloading.
+struct Recording<'a> {
+    inner: &'a FsStorage,
+    downloaded: RefCell<Vec<String>>,
+}
+
+#[async_trait(?Send)]
+impl Storage for Recording<'_> {
+    async fn put(
+        &self,
+        key: &str,
+        data: &mut (dyn AsyncRead + Unpin),
+        options: &PutOptions,
+    ) -> Result<ObjectInfo, storage::Error> {
+        self.inner.put(key, data, options).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin>, storage::Error> {
+        self.downloaded.borrow_mut().push(key.to_owned());
+        self.inner.get(key).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, storage::Error> {
+        self.inner.list(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), storage::Error> {
+        self.inner.delete(key).await
+    }
+
+    fn part_size(&self) -> Option<u64> {
+        self.inner.part_size()
+    }
+}
+
+#[tokio::test]
+async fn largest_archives_first() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    for idx in 0..5 {
+        let name = format!("file{}", idx);
+        write(&root, &name, &name.repeat((idx * 7) % 5 + 1));
+    }
+    let options = BackupOptions {
+        min_size: 1,
+        ..BackupOptions::default()
+    };
+    let report = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(report.archives.len(), 9);
+
+    let recording = Recording {
+        inner: &storage,
+        downloaded: RefCell::new(Vec::new()),
+    };
+    let output = temp.path().join("output");
+    restore(
+        &db,
+        report.snapshot.clone(),
+        &Patterns::default(),
+        &recording,
+        &output,
+        &RestoreOptions {
+            concurrency: 1,
+            ..RestoreOptions::default()
+        },
+    )
+    .await
+    .unwrap();
+    let sizes: Vec<u64> = recording
+        .downloaded
+        .into_inner()
+        .iter()
+        .map(|key| report.archives.iter().find(|x| &x.key == key).unwrap().size)
+        .collect();
+    assert_eq!(sizes.len(), 8);
+    assert!(
+        sizes.windows(2).all(|pair| pair[0] >= pair[1]),
+        "{:?}",
+        sizes
+    );
+}
+
+#[tokio::test]
+async fn times_are_restored() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    let modified = DateTime::from_unix_timestamp_nanos(1_600_000_000_123_456_789);
+    let accessed = DateTime::from_unix_timestamp_nanos(1_600_000_100_987_654_321);
+    for path in &["dir/second", "dir"] {
+        set_times(&root.join(path), Some(accessed), modified).unwrap();
+    }
+    let backup_report = backup(&mut db, &root, &storage, &BackupOptions::default())
+        .await
+        .unwrap();
+
+    let output = temp.path().join("output");
+    let patterns = Patterns::new(&[
+        format!("{}/dir", root.display()),
+        format!("{}/dir/**", root.display()),
+    ])
+    .unwrap();
+    restore(
+        &db,
+        backup_report.snapshot,
+        &patterns,
+        &storage,
+        &output,
+        &RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+    // Directory keeps its time, even though files were written into it.
+    for path in &["dir/second", "dir"] {
+        let metadata = std::fs::metadata(restored(&output, &root.join(path))).unwrap();
+        assert_eq!(DateTime::from(metadata.modified().unwrap()), modified);
+        assert_eq!(DateTime::from(metadata.accessed().unwrap()), accessed);
+    }
+}
+
+/// Restores only `first` file, with the given policy for existing files.
+async fn restore_first(
+    setup: &Setup,
+    snapshot: SqlName,
+    output: &Path,
+    existing: Existing,
+) -> RestoreReport {
+    let patterns = Patterns::new(&[format!("{}/first", setup.root.display())]).unwrap();
+    let options = RestoreOptions {
+        existing,
+        ..RestoreOptions::default()
+    };
+    restore(
+        &setup.db,
+        snapshot,
+        &patterns,
+        &setup.storage,
+        output,
+        &options,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn existing_files_are_not_followed() {
+    let mut setup = setup();
+    let backup_report = backup(
+        &mut setup.db,
+        &setup.root,
+        &setup.storage,
+        &BackupOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let temp = setup.temp.path();
+    let output = temp.join("output");
+    let placed = restored(&output, &setup.root.join("first"));
+    std::fs::create_dir_all(placed.parent().unwrap()).unwrap();
+    let target = temp.join("target");
+    write(temp, "target", "old");
+    std::os::unix::fs::symlink(&target, &placed).unwrap();
+
+    let snapshot = backup_report.snapshot;
+    let report = restore_first(&setup, snapshot.clone(), &output, Existing::Skip).await;
+    assert_eq!(report.files, 0);
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(std::fs::read_link(&placed).unwrap(), target);
+
+    let report = restore_first(&setup, snapshot, &output, Existing::Overwrite).await;
+    assert_eq!(report.files, 1);
+    assert!(report.skipped.is_empty());
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "old");
+    assert_eq!(std::fs::read_to_string(&placed).unwrap(), "Hello world\n");
+    assert!(!std::fs::symlink_metadata(&placed)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[tokio::test]
+async fn special_files_are_restored() {
+    let Setup {
+        temp,
+        root,
+        mut db,
+        storage,
+    } = setup();
+    std::os::unix::fs::symlink("dir/second", root.join("link")).unwrap();
+    make_fifo(&root.join("fifo"), 0o640).unwrap();
+    let backup_report = backup(&mut db, &root, &storage, &BackupOptions::default())
+        .await
+        .unwrap();
+
+    let output = temp.path().join("output");
+    let report = restore(
+        &db,
+        backup_report.snapshot,
+        &Patterns::default(),
+        &storage,
+        &output,
+        &RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(report.missing.is_empty());
+    assert_eq!(report.files, 5);
+    let link = restored(&output, &root.join("link"));
+    assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("dir/second"));
+    assert_eq!(std::fs::read_to_string(&link).unwrap(), "odd_named_file\n");
+    let fifo = std::fs::symlink_metadata(restored(&output, &root.join("fifo"))).unwrap();
+    assert!(fifo.file_type().is_fifo());
+    assert_eq!(fifo.permissions().mode() & 0o777, 0o640);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::script::{MANIFEST_NAME, SCRIPT_NAME};
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::fileinfo::Info;
+use common::archive;
+use std::io::Cursor;
+use std::path::Path;
+use std::process::Command;
+use tokio::io::AsyncReadExt;
+
+/// Extracts archive into `output` using only the embedded script.
+fn run_script(temp: &Path, buffer: &[u8], script: &[u8], args: &[&str]) -> Vec<u8> {
+    let archive = temp.join("archive.cpio");
+    let script_path = temp.join(SCRIPT_NAME);
+    std::fs::write(&archive, buffer).unwrap();
+    std::fs::write(&script_path, script).unwrap();
+    let result = Command::new("bash")
+        .arg(&script_path)
+        .args(args)
+        .arg(&archive)
+        .arg(temp.join("output"))
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    result.stdout
+}
+
+async fn check(format: Format) {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    let files = vec![root.join("odd"), root.join("dir/even name")];
+    std::fs::write(&files[0], "odd_named_file\n").unwrap();
+    std::fs::write(&files[1], "even_named_file\n").unwrap();
+    let buffer = archive(format, true, &files).await;
+
+    // Script and manifest come first, but they are not listed in the trailer.
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer.clone()));
+    let mut entries = Vec::new();
+    let end = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let mut contents = Vec::new();
+                let info = f.info();
+                reader = f.drain_to(&mut contents).await.unwrap();
+                entries.push((info.path.as_bytes().to_vec(), contents));
+            }
+            NextItem::End(end) => break end,
+        }
+    };
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].0, SCRIPT_NAME.as_bytes());
+    assert_eq!(entries[1].0, MANIFEST_NAME.as_bytes());
+    let manifest = String::from_utf8(entries[1].1.clone()).unwrap();
+    assert!(manifest.contains("dir/even name"), "{}", manifest);
+    assert_eq!(end.files.unwrap().len(), 2);
+
+    let script = entries[0].1.clone();
+    run_script(temp.path(), &buffer, &script, &[]);
+    let output = temp.path().join("output");
+    for file in &files {
+        let restored = output.join(file.strip_prefix("/").unwrap());
+        assert_eq!(
+            std::fs::read(restored).unwrap(),
+            std::fs::read(file).unwrap()
+        );
+    }
+    assert!(!output.join(SCRIPT_NAME).exists());
+
+    let json = run_script(temp.path(), &buffer, &script, &["--json"]);
+    let json: Vec<Info<colbak_lib::path::External>> = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json.len(), 2);
+}
+
+#[tokio::test]
+async fn binary() {
+    check(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn newc() {
+    check(Format::Newc).await;
+}
+
+#[tokio::test]
+async fn symlinks_are_not_followed() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let outside = temp.path().join("outside");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    std::fs::write(outside.join("file"), "archived").unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+    // The second entry is read through the symlink, like it was placed inside it.
+    let files = vec![root.join("link"), root.join("link/file")];
+    let mut archive = Archive::with_format(Format::Newc);
+    for file in &files {
+        archive.add(Info::new(file.clone()).await.unwrap());
+    }
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    std::fs::write(outside.join("file"), "untouched").unwrap();
+
+    let script = include_bytes!("../src/cpio/restore.sh");
+    run_script(temp.path(), &buffer, script, &[]);
+    let restored = temp
+        .path()
+        .join("output")
+        .join(root.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read_link(restored.join("link")).unwrap(), outside);
+    assert_eq!(
+        std::fs::read_to_string(outside.join("file")).unwrap(),
+        "untouched"
+    );
+}
@@ -0,0 +1,103 @@
+use colbak_lib::backup::{backup, BackupOptions};
+use colbak_lib::database::Database;
+use colbak_lib::estimate::Prices;
+use colbak_lib::retention;
+use colbak_lib::storage::{FsStorage, Storage, StorageClass};
+use colbak_lib::DateTime;
+use time::Duration;
+
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+#[tokio::test]
+async fn obsolete_archives() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    std::fs::write(root.join("kept"), "Hello world\n").unwrap();
+    std::fs::write(root.join("deleted"), "odd_named_file\n").unwrap();
+
+    let storage = FsStorage::new(temp.path().join("storage"));
+    let options = BackupOptions {
+        min_size: 1,
+        storage_class: StorageClass::Glacier,
+        ..BackupOptions::default()
+    };
+    let mut db = Database::open(&db_path).unwrap();
+    let prices = Prices::default();
+    assert!(retention::plan(&db, &prices, DateTime::now_utc())
+        .unwrap()
+        .delete
+        .is_empty());
+
+    // Directory is placed into the third archive.
+    let first = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(first.archives.len(), 3);
+    let plan = retention::plan(&db, &prices, DateTime::now_utc()).unwrap();
+    assert!(plan.delete.is_empty());
+    assert!(plan.keep.is_empty());
+
+    // Deleted file is still needed for the first snapshot.
+    // Directory is changed, so it's placed into a new archive.
+    std::fs::remove_file(root.join("deleted")).unwrap();
+    let second = backup(&mut db, &root, &storage, &options).await.unwrap();
+    assert_eq!(second.archives.len(), 1);
+    let plan = retention::plan(&db, &prices, DateTime::now_utc()).unwrap();
+    assert!(plan.delete.is_empty());
+    assert!(plan.keep.is_empty());
+
+    // Archives of the deleted file and of the old directory are obsolete,
+    // but Glacier charges for 90 days anyway.
+    db.expire_snapshot(&first.snapshot).unwrap();
+    let plan = retention::plan(&db, &prices, DateTime::now_utc()).unwrap();
+    assert!(plan.delete.is_empty());
+    assert_eq!(plan.keep.len(), 2);
+    // Glacier bills 32KB of index at it's own price and 8KB of metadata at the standard one.
+    let monthly_cost = |size: u64| {
+        (size + 32 * 1024) as f64 / GB * 0.004 + (8 * 1024) as f64 / GB * 0.023 + 0.0 / 1000.0
+    };
+    let mut monthly = 0.0;
+    for obsolete in &plan.keep {
+        let uploaded = first
+            .archives
+            .iter()
+            .find(|x| x.key == obsolete.key)
+            .unwrap();
+        assert_eq!(obsolete.size, uploaded.size);
+        assert_eq!(obsolete.monthly_cost, monthly_cost(uploaded.size));
+        // Nothing is stored yet, so all 90 days are charged.
+        assert_eq!(obsolete.early_deletion_cost, obsolete.monthly_cost * 90.0 / 30.0);
+        monthly += obsolete.monthly_cost;
+    }
+    assert!((plan.pending_savings - monthly).abs() < 1e-15);
+    assert!((plan.early_deletion_cost - monthly * 90.0 / 30.0).abs() < 1e-15);
+    assert_eq!(plan.monthly_savings, 0.0);
+    retention::apply(&db, &storage, &plan).await.unwrap();
+    assert_eq!(storage.list("").await.unwrap().len(), 4);
+
+    // After 60 days only the last month is charged.
+    let plan = retention::plan(&db, &prices, DateTime::now_utc() + Duration::days(60)).unwrap();
+    assert_eq!(plan.keep.len(), 2);
+    assert!((plan.early_deletion_cost - monthly * 30.0 / 30.0).abs() < 1e-15);
+
+    // After retention period they can be deleted for free.
+    let later = DateTime::now_utc() + Duration::days(91);
+    let plan = retention::plan(&db, &prices, later).unwrap();
+    assert!(plan.keep.is_empty());
+    assert_eq!(plan.delete.len(), 2);
+    assert!(plan.delete.iter().all(|x| x.early_deletion_cost == 0.0));
+    assert!((plan.monthly_savings - monthly).abs() < 1e-15);
+    retention::apply(&db, &storage, &plan).await.unwrap();
+    let remaining = storage.list("").await.unwrap();
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining.contains(&second.archives[0]));
+    for deleted in &plan.delete {
+        assert!(remaining.iter().all(|x| x.key != deleted.key));
+    }
+    assert_eq!(db.archives().unwrap().len(), 2);
+    assert!(retention::plan(&db, &prices, later)
+        .unwrap()
+        .delete
+        .is_empty());
+}
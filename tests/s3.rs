@@ -0,0 +1,66 @@
+//! These tests need an S3-compatible server, for example MinIO:
+//!
+//! ```sh
+//! docker run -p 9000:9000 -e MINIO_ROOT_USER=minio -e MINIO_ROOT_PASSWORD=minio123 minio/minio server /data
+//! export COLBAK_S3_ENDPOINT=http://localhost:9000 COLBAK_S3_BUCKET=colbak-test
+//! export AWS_ACCESS_KEY_ID=minio AWS_SECRET_ACCESS_KEY=minio123
+//! ```
+//!
+//! Bucket should exist already. When variables are not set, tests are skipped.
+
+use colbak_lib::storage::s3::MIN_PART_SIZE;
+use colbak_lib::storage::{PutOptions, S3Config, S3Storage, Storage};
+use std::io::Cursor;
+use tokio::io::AsyncReadExt;
+
+fn storage() -> Option<S3Storage> {
+    let mut config = match S3Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Skipping S3 test: {}", err);
+            return None;
+        }
+    };
+    config.part_size = MIN_PART_SIZE;
+    Some(S3Storage::new(config).unwrap())
+}
+
+async fn roundtrip(storage: &S3Storage, key: &str, data: Vec<u8>) {
+    let options = PutOptions {
+        metadata: vec![("colbak-test".to_owned(), "yes".to_owned())],
+        ..PutOptions::default()
+    };
+    let info = storage
+        .put(key, &mut Cursor::new(&data), &options)
+        .await
+        .unwrap();
+    assert_eq!(info.size, data.len() as u64);
+
+    let listed = storage.list(key).await.unwrap();
+    assert_eq!(listed, vec![info]);
+
+    let mut downloaded = Vec::new();
+    let mut reader = storage.get(key).await.unwrap();
+    reader.read_to_end(&mut downloaded).await.unwrap();
+    assert!(downloaded == data);
+
+    storage.delete(key).await.unwrap();
+    assert!(storage.list(key).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn small_object() {
+    if let Some(storage) = storage() {
+        roundtrip(&storage, "tests/small file.cpio", b"Hello world\n".to_vec()).await;
+    }
+}
+
+#[tokio::test]
+async fn multipart_object() {
+    if let Some(storage) = storage() {
+        let data = (0..MIN_PART_SIZE * 2 + 12345)
+            .map(|x| (x % 251) as u8)
+            .collect();
+        roundtrip(&storage, "tests/multipart.cpio", data).await;
+    }
+}
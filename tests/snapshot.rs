@@ -0,0 +1,75 @@
+use colbak_lib::database::{Database, Hashing, SqlName};
+use colbak_lib::fileinfo::Info;
+use colbak_lib::hash_pool::hash_file;
+use colbak_lib::types::Checksum;
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+/// Returns checksums of all files from the snapshot, by their names.
+fn hashes(db: &Database, name: &SqlName) -> HashMap<String, Option<Checksum>> {
+    let snapshot = db.readonly_snapshot(name.clone()).unwrap();
+    let mut result = HashMap::new();
+    snapshot
+        .for_each::<_, Infallible>(|info| {
+            if info.size().is_some() {
+                let path = info.path.cast().to_path().unwrap();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                result.insert(name, info.hash);
+            }
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+    result
+}
+
+#[tokio::test]
+async fn hashing() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let db_path = temp.path().join("db");
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::create_dir_all(&db_path).unwrap();
+    std::fs::write(root.join("first"), "Hello world\n").unwrap();
+    std::fs::write(root.join("dir/second"), "Hello world\n").unwrap();
+    std::fs::write(root.join("dir/third"), "Something else\n").unwrap();
+    let mut db = Database::open(&db_path).unwrap();
+
+    // Checksum of the `first` file is known already.
+    let previous = SqlName::new("previous".to_owned()).unwrap();
+    let fake = Checksum([1; 64]);
+    {
+        let mut snapshot = db.open_snapshot(previous.clone()).unwrap();
+        let filler = snapshot.filler().unwrap();
+        let mut info = Info::new(root.join("first")).await.unwrap();
+        info.hash = Some(fake);
+        filler.add_info(&info).unwrap();
+        filler.save().unwrap();
+    }
+
+    let name = SqlName::new("hashed".to_owned()).unwrap();
+    db.open_snapshot(name.clone())
+        .unwrap()
+        .hashing_filler(Hashing {
+            workers: 2,
+            reuse_from: Some(previous),
+        })
+        .unwrap()
+        .fill(&root)
+        .unwrap()
+        .save()
+        .unwrap();
+    let hashes = hashes(&db, &name);
+    assert_eq!(hashes.len(), 3);
+    // File was not read again.
+    assert_eq!(hashes["first"], Some(fake));
+    assert_eq!(
+        hashes["second"],
+        Some(hash_file(&root.join("dir/second")).unwrap())
+    );
+    assert_eq!(
+        hashes["third"],
+        Some(hash_file(&root.join("dir/third")).unwrap())
+    );
+    assert_ne!(hashes["second"], hashes["third"]);
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::script::SCRIPT_NAME;
+use colbak_lib::cpio::{Archive, Format};
+use colbak_lib::fileinfo::{DeviceInfo, DeviceKind, Info, UnspecifiedInfo};
+use common::archive;
+use std::io::Cursor;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::io::AsyncReadExt;
+
+/// Creates a file with two hard links, symlink to it and a named pipe.
+fn prepare(root: &Path) -> Vec<PathBuf> {
+    std::fs::create_dir_all(root).unwrap();
+    let files: Vec<_> = ["data", "hard", "symlink", "fifo"]
+        .iter()
+        .map(|name| root.join(name))
+        .collect();
+    std::fs::write(&files[0], "Hello world\n").unwrap();
+    std::fs::hard_link(&files[0], &files[1]).unwrap();
+    std::os::unix::fs::symlink("data", &files[2]).unwrap();
+    let status = Command::new("mkfifo").arg(&files[3]).status().unwrap();
+    assert!(status.success());
+    files
+}
+
+/// Reads all entries of the archive together with their data.
+async fn read_all(buffer: Vec<u8>) -> Vec<(Info<colbak_lib::path::External>, Vec<u8>)> {
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let mut result = Vec::new();
+    loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => {
+                let info = f.info();
+                let mut contents = Vec::new();
+                reader = f.drain_to(&mut contents).await.unwrap();
+                result.push((info, contents));
+            }
+            NextItem::End(_) => break result,
+        }
+    }
+}
+
+async fn check(format: Format) {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("root");
+    let files = prepare(&root);
+
+    let symlink = Info::new(files[2].clone()).await.unwrap();
+    match &symlink.data {
+        UnspecifiedInfo::Symlink(link) => assert_eq!(link.target, b"data"),
+        other => panic!("Not a symlink: {:?}", other),
+    }
+
+    let buffer = archive(format, true, &files).await;
+    let entries = read_all(buffer.clone()).await;
+    // Script and manifest come first.
+    assert_eq!(entries.len(), 6);
+    let data = &entries[2];
+    assert_eq!(data.1, b"Hello world\n");
+    match &entries[3].0.data {
+        UnspecifiedInfo::HardLink(link) => {
+            assert_eq!(link.target, data.0.path.as_bytes());
+            assert_eq!(link.links, 2);
+        }
+        other => panic!("Not a hard link: {:?}", other),
+    }
+    assert!(entries[3].1.is_empty());
+    assert!(matches!(entries[4].0.data, UnspecifiedInfo::Symlink(_)));
+    assert_eq!(entries[4].1, b"data");
+    assert!(matches!(entries[5].0.data, UnspecifiedInfo::Fifo(_)));
+
+    // Embedded script restores everything too.
+    let script = &entries[0].1;
+    let archive_path = temp.path().join("archive.cpio");
+    let script_path = temp.path().join(SCRIPT_NAME);
+    std::fs::write(&archive_path, &buffer).unwrap();
+    std::fs::write(&script_path, script).unwrap();
+    let output = temp.path().join("output");
+    let result = Command::new("bash")
+        .arg(&script_path)
+        .arg(&archive_path)
+        .arg(&output)
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let restored = output.join(root.strip_prefix("/").unwrap());
+    let data = std::fs::metadata(restored.join("data")).unwrap();
+    let hard = std::fs::metadata(restored.join("hard")).unwrap();
+    assert_eq!(data.ino(), hard.ino());
+    assert_eq!(
+        std::fs::read(restored.join("hard")).unwrap(),
+        b"Hello world\n"
+    );
+    let target = std::fs::read_link(restored.join("symlink")).unwrap();
+    assert_eq!(target, Path::new("data"));
+    let fifo = std::fs::symlink_metadata(restored.join("fifo")).unwrap();
+    assert!(fifo.file_type().is_fifo());
+}
+
+#[tokio::test]
+async fn special_files() {
+    check(Format::Binary).await;
+}
+
+#[tokio::test]
+async fn special_files_newc() {
+    check(Format::Newc).await;
+}
+
+#[tokio::test]
+async fn devices() {
+    for &format in &[Format::Binary, Format::Newc] {
+        let mut archive = Archive::with_format(format);
+        let mut info = Info::new(PathBuf::from("/")).await.unwrap();
+        info.path = colbak_lib::path::EncodedPath::from_vec(b"dev/tty1".to_vec()).cast();
+        info.data = UnspecifiedInfo::Device(DeviceInfo {
+            kind: DeviceKind::Char,
+            major: 4,
+            minor: 1,
+        });
+        archive.add(info);
+        let mut buffer = Vec::new();
+        archive.read().read_to_end(&mut buffer).await.unwrap();
+
+        let entries = read_all(buffer).await;
+        assert_eq!(entries.len(), 1);
+        match &entries[0].0.data {
+            UnspecifiedInfo::Device(device) => {
+                assert_eq!(device.kind, DeviceKind::Char);
+                assert_eq!((device.major, device.minor), (4, 1));
+            }
+            other => panic!("Not a device: {:?}", other),
+        }
+        assert!(entries[0].1.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use colbak_lib::cpio::reader::NextItem;
+use colbak_lib::cpio::Archive;
+use colbak_lib::extract::{extract, ExtractOptions};
+use colbak_lib::fileinfo::Info;
+use colbak_lib::xattr::{self, Xattr};
+use std::io::Cursor;
+use std::os::unix::fs::PermissionsExt;
+use tokio::io::AsyncReadExt;
+
+#[tokio::test]
+async fn stored_in_trailer() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("file");
+    std::fs::write(&file, "Hello world\n").unwrap();
+    let attributes = vec![
+        Xattr {
+            name: b"user.first".to_vec(),
+            value: b"1".to_vec(),
+        },
+        Xattr {
+            name: b"user.second".to_vec(),
+            value: vec![0, 255, 0],
+        },
+    ];
+    // Attributes are written in reverse order, but read sorted.
+    for attribute in attributes.iter().rev() {
+        if let Err(e) = xattr::write(&file, attribute) {
+            eprintln!("Extended attributes are not supported here: {}", e);
+            return;
+        }
+    }
+    let info = Info::new(file.clone()).await.unwrap();
+    assert_eq!(info.xattrs, attributes);
+    assert_eq!(xattr::read(&file).unwrap(), attributes);
+
+    let mut archive = Archive::new();
+    archive.add(info);
+    let expected = archive.size();
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    assert_eq!(buffer.len() as u64, expected);
+
+    let mut reader = colbak_lib::cpio::Reader::new(Cursor::new(buffer));
+    let end = loop {
+        match reader.advance().await.unwrap() {
+            NextItem::File(f) => reader = f.to_void().await.unwrap(),
+            NextItem::End(end) => break end,
+        }
+    };
+    let files = end.files.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].xattrs, attributes);
+
+    // And they can be applied to other file.
+    let copy = temp.path().join("copy");
+    std::fs::write(&copy, "Hello world\n").unwrap();
+    for attribute in &files[0].xattrs {
+        xattr::write(&copy, attribute).unwrap();
+    }
+    assert_eq!(xattr::read(&copy).unwrap(), attributes);
+}
+
+#[tokio::test]
+async fn restored_on_read_only_file() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("file");
+    std::fs::write(&file, "Hello world\n").unwrap();
+    let attribute = Xattr {
+        name: b"user.first".to_vec(),
+        value: b"1".to_vec(),
+    };
+    if let Err(e) = xattr::write(&file, &attribute) {
+        eprintln!("Extended attributes are not supported here: {}", e);
+        return;
+    }
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o400)).unwrap();
+
+    let mut archive = Archive::new();
+    archive.add(Info::new(file.clone()).await.unwrap());
+    let mut buffer = Vec::new();
+    archive.read().read_to_end(&mut buffer).await.unwrap();
+    let output = temp.path().join("output");
+    let report = extract(Cursor::new(buffer), &output, &ExtractOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(report.warnings, 0);
+    let mut restored = output;
+    restored.extend(file.components().skip(1));
+    let meta = std::fs::metadata(&restored).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o400);
+    assert_eq!(xattr::read(&restored).unwrap(), vec![attribute]);
+}